@@ -0,0 +1,96 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Measures how long recording a beat takes as the number of stored beats
+//! grows. It should stay about the same.
+//!
+//! Runs against the database in `HEARTBEAT_BENCH_DSN`, which is migrated and
+//! left with the beats that were added, so point it at a scratch database.
+//! Without it, a temporary `SQLite` database is used.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use heartbeat::{
+    devices::Credential,
+    storage::{self, NewBeat, NewDevice, Storage},
+};
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+use tokio::runtime::Runtime;
+
+/// The numbers of stored beats to measure at.
+const SIZES: [i64; 3] = [1_000, 10_000, 100_000];
+
+/// Adds a device with `count` beats, one a minute until `until`.
+async fn fill(storage: &dyn Storage, id: i64, count: i64, until: DateTime<Utc>) {
+    let token = format!("bench-{id}");
+    storage
+        .add_device(NewDevice {
+            id,
+            name: "bench",
+            token: &token,
+            signing_secret: None,
+            public_key: None,
+            probe: None,
+        })
+        .await
+        .expect("device to be added");
+    let time_stamps = (1..=count)
+        .rev()
+        .map(|minutes| until - TimeDelta::minutes(minutes))
+        .collect::<Vec<_>>();
+    for chunk in time_stamps.chunks(10_000) {
+        storage
+            .insert_beats(id, Credential::Current, chunk)
+            .await
+            .expect("beats to be inserted");
+    }
+}
+
+fn insert_beat(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("runtime to start");
+    let _guard = runtime.enter();
+    let scratch = std::env::temp_dir().join(format!("heartbeat-bench-{}.db", std::process::id()));
+    let dsn = std::env::var("HEARTBEAT_BENCH_DSN").unwrap_or_else(|_| format!("sqlite://{}", scratch.display()));
+    let storage: Arc<dyn Storage> =
+        storage::connect(&dsn, &storage::ConnectOptions::default()).expect("DSN to be valid");
+    runtime.block_on(storage.migrate()).expect("migrations to apply");
+    runtime.block_on(storage.server_start_time());
+    let start = Utc::now();
+    let mut group = c.benchmark_group("insert_beat");
+    let mut stored = 0;
+    for size in SIZES {
+        // every device's beats are older than the ones being measured
+        let id = start.timestamp_micros() + size;
+        runtime.block_on(fill(&*storage, id, size - stored, start));
+        stored = size;
+        let offset = AtomicI64::new(0);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.to_async(&runtime).iter(|| {
+                let time = start + TimeDelta::microseconds(offset.fetch_add(1, Ordering::Relaxed));
+                let storage = storage.clone();
+                async move {
+                    storage
+                        .insert_live_beats(&[NewBeat {
+                            device: id,
+                            credential: Credential::Current,
+                            time,
+                        }])
+                        .await
+                        .expect("beat to be inserted")
+                }
+            });
+        });
+    }
+    group.finish();
+    drop(storage);
+    let _ = std::fs::remove_file(&scratch);
+}
+
+criterion_group!(benches, insert_beat);
+criterion_main!(benches);
@@ -13,9 +13,23 @@
     clippy::unwrap_used
 )]
 
+/// The version of the latest migration in `dir`, which prefixes its file name.
+fn latest_migration(dir: &str) -> Option<i64> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| !name.ends_with(".down.sql"))
+        .filter_map(|name| name.split_once('_')?.0.parse().ok())
+        .max()
+}
+
 fn main() {
     let version = heartbeat_sys::build::long_version(env!("CARGO_PKG_VERSION"));
     let rev = heartbeat_sys::build::git_revision().unwrap_or_else(|| "main".into());
     println!("cargo:rustc-env=HB_VERSION={version}");
     println!("cargo:rustc-env=HB_GIT_REVISION={rev}");
+    for (backend, dir) in [("POSTGRES", "migrations/postgres"), ("SQLITE", "migrations/sqlite")] {
+        let latest = latest_migration(dir).unwrap_or_default();
+        println!("cargo:rustc-env=HB_{backend}_SCHEMA={latest}");
+    }
 }
@@ -7,11 +7,17 @@
 
 CREATE SCHEMA heartbeat;
 
+CREATE TYPE heartbeat.credential AS ENUM ('current', 'superseded', 'key', 'udp', 'probe');
+
 CREATE TABLE heartbeat.devices (
   id BIGINT PRIMARY KEY,
   name TEXT,
   token TEXT NOT NULL,
-  num_beats BIGINT NOT NULL DEFAULT 0
+  num_beats BIGINT NOT NULL DEFAULT 0,
+  signing_secret TEXT,
+  public_key BYTEA,
+  udp_counter BIGINT NOT NULL DEFAULT 0,
+  probe JSONB
 );
 
 CREATE INDEX devices_token_idx ON heartbeat.devices (token);
@@ -19,9 +25,34 @@ CREATE INDEX devices_token_idx ON heartbeat.devices (token);
 CREATE TABLE heartbeat.beats (
   device BIGINT NOT NULL REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
   time_stamp TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc'),
+  credential heartbeat.credential NOT NULL DEFAULT 'current',
   PRIMARY KEY (device, time_stamp)
 );
 
+CREATE TABLE heartbeat.superseded_tokens (
+  token TEXT PRIMARY KEY,
+  device BIGINT NOT NULL REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
+  superseded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc'),
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX superseded_tokens_device_idx ON heartbeat.superseded_tokens (device);
+
+CREATE TABLE heartbeat.beat_nonces (
+  device BIGINT NOT NULL REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
+  nonce TEXT NOT NULL,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY (device, nonce)
+);
+
+CREATE INDEX beat_nonces_expires_at_idx ON heartbeat.beat_nonces (expires_at);
+
+CREATE TABLE heartbeat.device_state (
+  device BIGINT PRIMARY KEY REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
+  metadata JSONB NOT NULL,
+  updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc')
+);
+
 CREATE TABLE heartbeat.stats (
   _id SMALLINT PRIMARY KEY,
   total_visits BIGINT NOT NULL DEFAULT 0,
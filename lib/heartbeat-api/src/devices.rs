@@ -0,0 +1,283 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Devices and the types they are exchanged as over the API.
+
+use crate::{problem::Problem, ts};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// A device, along with its most recent beat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct Device {
+    /// The device's Snowflake ID.
+    pub id: i64,
+    /// The device's human-readable name.
+    pub name: Option<String>,
+    /// When the device last sent a beat, serialized as a Unix timestamp.
+    #[serde(with = "ts")]
+    #[cfg_attr(feature = "utoipa", schema(value_type = Option<i64>))]
+    pub last_beat: Option<chrono::DateTime<chrono::Utc>>,
+    /// The total number of beats received from the device.
+    pub num_beats: i64,
+    /// How the device authenticated its last beat.
+    pub last_credential: Option<Credential>,
+    /// The metadata sent with the device's last beat that included any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    /// How the server checks on the device. This is never shown publicly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<Probe>,
+}
+
+impl Device {
+    /// Returns a copy of this device as it is shown publicly, with only the
+    /// given metadata fields and without its probe.
+    #[must_use]
+    pub fn public(&self, visible: &[MetadataField]) -> Self {
+        Self {
+            metadata: self
+                .metadata
+                .as_ref()
+                .map(|m| m.filtered(visible))
+                .filter(|m| !m.is_empty()),
+            probe: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// The maximum size of a beat body, in bytes.
+pub const MAX_METADATA_SIZE: usize = 4096;
+const MAX_FIELD_LEN: usize = 255;
+const MAX_STATUS_LEN: usize = 1024;
+/// The longest uptime a device may report, which is 100 years in seconds.
+const MAX_UPTIME: u64 = 100 * 365 * 86400;
+
+/// Information a device may send along with a beat.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Metadata {
+    /// Battery level, in percent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,
+    /// The device's hostname.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// The name and version of the operating system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// Time since the device booted, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime: Option<u64>,
+    /// The device's address on its local network.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "utoipa", schema(value_type = Option<String>))]
+    pub ip: Option<IpAddr>,
+    /// A free-form status message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+/// A field of [`Metadata`], used to configure which fields are public.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(missing_docs)] // each variant is the field of the same name
+pub enum MetadataField {
+    Battery,
+    Hostname,
+    Os,
+    Uptime,
+    Ip,
+    Status,
+}
+
+impl std::str::FromStr for MetadataField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "battery" => Ok(Self::Battery),
+            "hostname" => Ok(Self::Hostname),
+            "os" => Ok(Self::Os),
+            "uptime" => Ok(Self::Uptime),
+            "ip" => Ok(Self::Ip),
+            "status" => Ok(Self::Status),
+            _ => Err(format!("Invalid metadata field: {s}")),
+        }
+    }
+}
+
+impl Metadata {
+    /// Checks that the metadata is within the size limits.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the [`Problem`] with the metadata, if it is invalid.
+    pub fn validate(&self) -> Result<(), Problem> {
+        if self.battery.is_some_and(|b| b > 100) {
+            return Err(Problem::InvalidBattery);
+        }
+        let too_long = |field: &Option<String>, max| field.as_ref().is_some_and(|f| f.len() > max);
+        if too_long(&self.hostname, MAX_FIELD_LEN) || too_long(&self.os, MAX_FIELD_LEN) {
+            return Err(Problem::MetadataTooLong);
+        }
+        if too_long(&self.status, MAX_STATUS_LEN) {
+            return Err(Problem::StatusTooLong);
+        }
+        if self.uptime.is_some_and(|uptime| uptime > MAX_UPTIME) {
+            return Err(Problem::InvalidUptime);
+        }
+        Ok(())
+    }
+
+    /// Returns whether none of the fields are set.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.battery.is_none()
+            && self.hostname.is_none()
+            && self.os.is_none()
+            && self.uptime.is_none()
+            && self.ip.is_none()
+            && self.status.is_none()
+    }
+
+    /// Returns a copy with only the given fields.
+    #[must_use]
+    pub fn filtered(&self, visible: &[MetadataField]) -> Self {
+        let show = |field| visible.contains(&field);
+        Self {
+            battery: self.battery.filter(|_| show(MetadataField::Battery)),
+            hostname: self.hostname.clone().filter(|_| show(MetadataField::Hostname)),
+            os: self.os.clone().filter(|_| show(MetadataField::Os)),
+            uptime: self.uptime.filter(|_| show(MetadataField::Uptime)),
+            ip: self.ip.filter(|_| show(MetadataField::Ip)),
+            status: self.status.clone().filter(|_| show(MetadataField::Status)),
+        }
+    }
+}
+
+/// The credential a device authenticated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[cfg_attr(
+    feature = "sqlx",
+    derive(sqlx::Type),
+    sqlx(type_name = "credential", rename_all = "snake_case")
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Credential {
+    /// The device's current token.
+    Current,
+    /// A token that has since been rotated, but is still within its grace
+    /// period.
+    Superseded,
+    /// A signature made with the device's Ed25519 key.
+    Key,
+    /// A UDP datagram authenticated with the device's signing secret.
+    Udp,
+    /// A successful probe by the server.
+    Probe,
+}
+
+const MIN_PROBE_INTERVAL: u64 = 10;
+const MAX_PROBE_INTERVAL: u64 = 86400;
+const MAX_URL_LEN: usize = 2048;
+
+/// How the server checks on a device that can't send beats itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Probe {
+    /// The number of seconds between probes.
+    pub interval: u64,
+    /// What is checked on each probe.
+    pub check: ProbeCheck,
+}
+
+/// A check that succeeds if the device is up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+#[allow(missing_docs)] // fields are described on each variant
+pub enum ProbeCheck {
+    /// Succeeds if a TCP connection to `address` (`host:port`) can be opened.
+    Tcp { address: String },
+    /// Succeeds if a GET request to `url` returns `status` (or any 2xx status
+    /// if unset), with `body` somewhere in the response body if set.
+    Http {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body: Option<String>,
+    },
+    /// Succeeds if `name` resolves to at least one address.
+    Dns { name: String },
+}
+
+impl Probe {
+    /// Checks that the probe is within the allowed limits.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the [`Problem`] with the probe, if it is invalid.
+    pub fn validate(&self) -> Result<(), Problem> {
+        if !(MIN_PROBE_INTERVAL..=MAX_PROBE_INTERVAL).contains(&self.interval) {
+            return Err(Problem::InvalidProbeInterval);
+        }
+        match &self.check {
+            ProbeCheck::Tcp { address: target } | ProbeCheck::Dns { name: target } => {
+                if target.is_empty() || target.len() > MAX_FIELD_LEN {
+                    return Err(Problem::InvalidProbeTarget);
+                }
+            }
+            ProbeCheck::Http { url, status, body } => {
+                if url.len() > MAX_URL_LEN || !(url.starts_with("http://") || url.starts_with("https://")) {
+                    return Err(Problem::InvalidProbeUrl);
+                }
+                if status.is_some_and(|s| !(100..=599).contains(&s)) {
+                    return Err(Problem::InvalidProbeStatus);
+                }
+                if body.as_ref().is_some_and(|b| b.len() > MAX_STATUS_LEN) {
+                    return Err(Problem::ProbeBodyTooLong);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The body of `POST /api/v1/devices`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct PostDevice {
+    /// A human-readable name for the device.
+    pub name: String,
+    /// Whether beats from this device must be signed.
+    #[serde(default)]
+    pub signed_beats: bool,
+    /// A hex-encoded Ed25519 public key the device may authenticate with
+    /// instead of its token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// How the server should poll this device, if at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<Probe>,
+}
+
+/// The maximum number of beats that can be submitted in a single batch.
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/// The body of `POST /api/v1/beats/batch`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct PostBeats {
+    /// Unix timestamps of the beats, in seconds.
+    pub beats: Vec<i64>,
+}
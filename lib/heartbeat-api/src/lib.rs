@@ -0,0 +1,35 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#![forbid(unsafe_code)]
+#![deny(
+    missing_docs,
+    clippy::all,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::unwrap_used,
+    clippy::unwrap_in_result
+)]
+
+//! The types and signatures shared by the [Heartbeat](https://github.com/lmaotrigine/heartbeat)
+//! server and its clients.
+//!
+//! The server and the clients both use these, so the two can't drift apart.
+
+pub mod devices;
+pub mod problem;
+pub mod routes;
+pub mod signature;
+mod ts;
+
+/// The ID of a device authenticating with its Ed25519 key.
+pub const DEVICE_HEADER: &str = "X-Heartbeat-Device";
+/// The Unix timestamp (in seconds) at which a signed beat was sent.
+pub const TIMESTAMP_HEADER: &str = "X-Heartbeat-Timestamp";
+/// A unique value for each signed beat.
+pub const NONCE_HEADER: &str = "X-Heartbeat-Nonce";
+/// The hex-encoded HMAC-SHA256 or Ed25519 signature of a signed beat.
+pub const SIGNATURE_HEADER: &str = "X-Heartbeat-Signature";
@@ -0,0 +1,154 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The reasons the API rejects requests, and how they are reported.
+
+use serde::{Deserialize, Serialize};
+
+/// Where the problem types are documented. Each type URI is this followed by
+/// the problem's code as the fragment.
+pub const PROBLEM_TYPE_BASE: &str = "https://lmaotrigine.github.io/heartbeat/docs/clients/api.html#";
+
+/// The media type of error responses from the API.
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// A specific reason a request to the API was rejected.
+///
+/// Each problem has a stable, machine-readable [`code`](Self::code), which is
+/// also used in its type URI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// The `Authorization` header was missing.
+    NoToken,
+    /// The token or secret key was invalid.
+    InvalidToken,
+    /// The device header was missing on a beat authenticated with a key.
+    NoDevice,
+    /// The device doesn't exist, or has no public key.
+    InvalidDevice,
+    /// The device requires its beats to be signed.
+    SignatureRequired,
+    /// The nonce was empty or too long.
+    InvalidNonce,
+    /// The timestamp of a signed beat was too far from the server's time.
+    StaleTimestamp,
+    /// The signature didn't match the request.
+    InvalidSignature,
+    /// The nonce was already used by a previous beat.
+    NonceReused,
+    /// The metadata wasn't valid JSON, or had unknown fields.
+    InvalidMetadata,
+    /// The battery level was over 100.
+    InvalidBattery,
+    /// The hostname or OS was too long.
+    MetadataTooLong,
+    /// The status was too long.
+    StatusTooLong,
+    /// The uptime was over 100 years.
+    InvalidUptime,
+    /// The batch had too many beats.
+    BatchTooLarge,
+    /// A timestamp was out of range.
+    InvalidTimestamp,
+    /// The public key wasn't a hex-encoded Ed25519 public key.
+    InvalidPublicKey,
+    /// The probe interval was outside of the allowed range.
+    InvalidProbeInterval,
+    /// The probe's address or name was empty or too long.
+    InvalidProbeTarget,
+    /// The probe's URL wasn't an HTTP URL.
+    InvalidProbeUrl,
+    /// The probe's expected status code was invalid.
+    InvalidProbeStatus,
+    /// The probe's expected body was too long.
+    ProbeBodyTooLong,
+}
+
+impl Problem {
+    /// The machine-readable code of the problem.
+    #[must_use]
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::NoToken => "no_token",
+            Self::InvalidToken => "invalid_token",
+            Self::NoDevice => "no_device",
+            Self::InvalidDevice => "invalid_device",
+            Self::SignatureRequired => "signature_required",
+            Self::InvalidNonce => "invalid_nonce",
+            Self::StaleTimestamp => "stale_timestamp",
+            Self::InvalidSignature => "invalid_signature",
+            Self::NonceReused => "nonce_reused",
+            Self::InvalidMetadata => "invalid_metadata",
+            Self::InvalidBattery => "invalid_battery",
+            Self::MetadataTooLong => "metadata_too_long",
+            Self::StatusTooLong => "status_too_long",
+            Self::InvalidUptime => "invalid_uptime",
+            Self::BatchTooLarge => "batch_too_large",
+            Self::InvalidTimestamp => "invalid_timestamp",
+            Self::InvalidPublicKey => "invalid_public_key",
+            Self::InvalidProbeInterval => "invalid_probe_interval",
+            Self::InvalidProbeTarget => "invalid_probe_target",
+            Self::InvalidProbeUrl => "invalid_probe_url",
+            Self::InvalidProbeStatus => "invalid_probe_status",
+            Self::ProbeBodyTooLong => "probe_body_too_long",
+        }
+    }
+
+    /// A human-readable explanation of the problem.
+    #[must_use]
+    pub const fn detail(self) -> &'static str {
+        match self {
+            Self::NoToken => "No token provided.",
+            Self::InvalidToken => "Invalid token.",
+            Self::NoDevice => "No device provided.",
+            Self::InvalidDevice => "Invalid device.",
+            Self::SignatureRequired => "Signed beat required.",
+            Self::InvalidNonce => "Invalid nonce.",
+            Self::StaleTimestamp => "Timestamp outside of the allowed window.",
+            Self::InvalidSignature => "Invalid signature.",
+            Self::NonceReused => "Nonce already used.",
+            Self::InvalidMetadata => "Invalid metadata.",
+            Self::InvalidBattery => "Battery level must be between 0 and 100.",
+            Self::MetadataTooLong => "Metadata field too long.",
+            Self::StatusTooLong => "Status too long.",
+            Self::InvalidUptime => "Uptime must be at most 100 years.",
+            Self::BatchTooLarge => "Too many beats in one batch.",
+            Self::InvalidTimestamp => "Invalid timestamp.",
+            Self::InvalidPublicKey => "Invalid public key.",
+            Self::InvalidProbeInterval => "Probe interval must be between 10 and 86400 seconds.",
+            Self::InvalidProbeTarget => "Invalid probe target.",
+            Self::InvalidProbeUrl => "Probe URL must be an http:// or https:// URL.",
+            Self::InvalidProbeStatus => "Invalid probe status code.",
+            Self::ProbeBodyTooLong => "Probe body too long.",
+        }
+    }
+}
+
+/// An [RFC 7807] problem details object, the body of every error response
+/// from the API.
+///
+/// [RFC 7807]: https://www.rfc-editor.org/rfc/rfc7807
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct ProblemDetails {
+    /// A URI identifying the problem type, or `about:blank` if the status
+    /// code is all there is to it.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// The reason phrase of the status code.
+    pub title: String,
+    /// The HTTP status code.
+    pub status: u16,
+    /// A human-readable explanation of this occurrence of the problem.
+    pub detail: String,
+    /// The path of the request.
+    pub instance: String,
+    /// A machine-readable code for the problem. This is the fragment of
+    /// `type`, or the status code's reason phrase in `snake_case` for
+    /// `about:blank`.
+    pub code: String,
+}
@@ -0,0 +1,107 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The responses of the API routes.
+
+use crate::{
+    devices::{Device, Probe},
+    ts,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The response to `POST /api/v1/beats/batch`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct BatchResp {
+    /// The number of beats that were recorded.
+    pub inserted: u64,
+    /// The number of beats that had already been recorded.
+    pub duplicates: u64,
+    /// The beats that were skipped because they were older than the server
+    /// backfills.
+    pub too_old: Vec<i64>,
+    /// The beats that were skipped because they were in the future.
+    pub future: Vec<i64>,
+    /// The device's longest absence after the batch, in seconds.
+    pub longest_absence: i64,
+}
+
+/// The response to `GET /api/v1/stats`, also sent on `/api/v1/stats/ws`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct StatsResp {
+    /// The Unix timestamp of the last beat from any device.
+    pub last_seen: Option<i64>,
+    /// The number of seconds since the last beat from any device.
+    pub last_seen_relative: i64,
+    /// The longest absence, in seconds, including the current one.
+    pub longest_absence: i64,
+    /// The number of times the index page was visited.
+    pub num_visits: i64,
+    /// The total number of beats from all devices.
+    pub total_beats: i64,
+    /// All devices, as they are shown publicly.
+    pub devices: Vec<Device>,
+    /// The number of seconds since the server started.
+    pub uptime: i64,
+}
+
+/// The response to `POST /api/v1/devices`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct DeviceAddResp {
+    /// The ID of the new device.
+    pub id: i64,
+    /// The name of the new device.
+    pub name: Option<String>,
+    /// The token the device authenticates with.
+    pub token: String,
+    /// The secret beats from this device must be signed with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_secret: Option<String>,
+}
+
+/// The response to `POST /api/v1/devices/:device_id/token/generate`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct DeviceUpdateResp {
+    /// The ID of the device.
+    pub id: i64,
+    /// The name of the device.
+    pub name: Option<String>,
+    /// The device's new token.
+    pub token: String,
+    /// When the previous token stops being accepted, if it wasn't revoked
+    /// immediately.
+    #[serde(with = "ts")]
+    #[cfg_attr(feature = "utoipa", schema(value_type = Option<i64>))]
+    pub previous_token_expires_at: Option<DateTime<Utc>>,
+}
+
+/// The response to `POST` and `DELETE` on `/api/v1/devices/:device_id/secret`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct SigningSecretResp {
+    /// The ID of the device.
+    pub id: i64,
+    /// The name of the device.
+    pub name: Option<String>,
+    /// The device's new signing secret, or `None` if it was removed.
+    pub signing_secret: Option<String>,
+}
+
+/// The response to `PUT` and `DELETE` on `/api/v1/devices/:device_id/probe`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct ProbeResp {
+    /// The ID of the device.
+    pub id: i64,
+    /// The name of the device.
+    pub name: Option<String>,
+    /// The device's new probe, or `None` if it was removed.
+    pub probe: Option<Probe>,
+}
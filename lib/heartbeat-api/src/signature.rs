@@ -0,0 +1,198 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing and verification of signed beats.
+//!
+//! Signed beats are authenticated with a signature over the request method,
+//! path, timestamp, and nonce, each separated by a newline. This is either an
+//! HMAC-SHA256 keyed by the device's signing secret, or an Ed25519 signature
+//! made with the device's private key.
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The longest nonce we are willing to store.
+pub const MAX_NONCE_LEN: usize = 64;
+
+fn message(method: &str, path: &str, timestamp: i64, nonce: &str) -> String {
+    format!("{method}\n{path}\n{timestamp}\n{nonce}")
+}
+
+fn mac(secret: &str, method: &str, path: &str, timestamp: i64, nonce: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(message(method, path, timestamp, nonce).as_bytes());
+    mac
+}
+
+/// Returns the hex-encoded HMAC-SHA256 signature of a request.
+#[must_use]
+pub fn sign(secret: &str, method: &str, path: &str, timestamp: i64, nonce: &str) -> String {
+    hex::encode(mac(secret, method, path, timestamp, nonce).finalize().into_bytes())
+}
+
+/// Returns the hex-encoded Ed25519 signature of a request.
+#[must_use]
+pub fn sign_key(key: &SigningKey, method: &str, path: &str, timestamp: i64, nonce: &str) -> String {
+    hex::encode(key.sign(message(method, path, timestamp, nonce).as_bytes()).to_bytes())
+}
+
+/// Checks a hex-encoded signature in constant time.
+#[must_use]
+pub fn verify(secret: &str, method: &str, path: &str, timestamp: i64, nonce: &str, signature: &str) -> bool {
+    hex::decode(signature).is_ok_and(|sig| mac(secret, method, path, timestamp, nonce).verify_slice(&sig).is_ok())
+}
+
+/// Checks a truncated HMAC-SHA256 tag over raw bytes in constant time.
+#[must_use]
+#[allow(clippy::missing_panics_doc)] // infallible
+pub fn verify_truncated(secret: &str, message: &[u8], tag: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.verify_truncated_left(tag).is_ok()
+}
+
+/// Checks a hex-encoded Ed25519 signature against a raw public key.
+#[must_use]
+pub fn verify_key(public_key: &[u8], method: &str, path: &str, timestamp: i64, nonce: &str, signature: &str) -> bool {
+    let Some(key) = <[u8; PUBLIC_KEY_LENGTH]>::try_from(public_key)
+        .ok()
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+    else {
+        return false;
+    };
+    let Some(signature) = hex::decode(signature)
+        .ok()
+        .and_then(|sig| Signature::from_slice(&sig).ok())
+    else {
+        return false;
+    };
+    key.verify_strict(message(method, path, timestamp, nonce).as_bytes(), &signature)
+        .is_ok()
+}
+
+/// Parses a hex-encoded Ed25519 public key, returning its raw bytes if it is
+/// valid.
+#[must_use]
+pub fn parse_public_key(public_key: &str) -> Option<Vec<u8>> {
+    let bytes = <[u8; PUBLIC_KEY_LENGTH]>::try_from(hex::decode(public_key).ok()?).ok()?;
+    VerifyingKey::from_bytes(&bytes).ok().map(|key| key.to_bytes().to_vec())
+}
+
+/// Generates a random nonce for a signed request.
+#[must_use]
+pub fn generate_nonce() -> String {
+    let mut buf = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+/// Generates a new random signing secret.
+#[must_use]
+#[allow(clippy::missing_panics_doc)] // infallible
+pub fn generate_secret() -> String {
+    let mut buf = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut buf);
+    let mut dst = [0u8; 43];
+    Base64UrlUnpadded::encode(&buf, &mut dst)
+        .expect("encoded base64 exceeded 43 bytes")
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let secret = generate_secret();
+        let sig = sign(&secret, "POST", "/api/beat", 1_698_915_036, "abc");
+        assert!(verify(&secret, "POST", "/api/beat", 1_698_915_036, "abc", &sig));
+        assert!(verify(
+            &secret,
+            "POST",
+            "/api/beat",
+            1_698_915_036,
+            "abc",
+            &sig.to_uppercase()
+        ));
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        let secret = generate_secret();
+        let sig = sign(&secret, "POST", "/api/beat", 1_698_915_036, "abc");
+        assert!(!verify(&secret, "POST", "/api/beat", 1_698_915_037, "abc", &sig));
+        assert!(!verify(&secret, "POST", "/api/beat", 1_698_915_036, "abd", &sig));
+        assert!(!verify(&secret, "GET", "/api/beat", 1_698_915_036, "abc", &sig));
+        assert!(!verify(
+            &generate_secret(),
+            "POST",
+            "/api/beat",
+            1_698_915_036,
+            "abc",
+            &sig
+        ));
+        assert!(!verify(&secret, "POST", "/api/beat", 1_698_915_036, "abc", "not hex"));
+    }
+
+    #[test]
+    fn test_known_vector() {
+        // printf 'POST\n/api/beat\n0\nnonce' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", "POST", "/api/beat", 0, "nonce"),
+            "9347a61dd30f0b75abc669fd5dfc7f5bcfc3c6085c1283f29db8bb193f38ec59"
+        );
+    }
+
+    #[test]
+    fn test_key_round_trip() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = parse_public_key(&hex::encode(key.verifying_key().to_bytes())).expect("valid public key");
+        let sig = sign_key(&key, "POST", "/api/beat/key", 1_698_915_036, "abc");
+        assert!(verify_key(
+            &public_key,
+            "POST",
+            "/api/beat/key",
+            1_698_915_036,
+            "abc",
+            &sig
+        ));
+        assert!(!verify_key(
+            &public_key,
+            "POST",
+            "/api/beat/key",
+            1_698_915_037,
+            "abc",
+            &sig
+        ));
+        assert!(!verify_key(
+            &public_key,
+            "POST",
+            "/api/beat/key",
+            1_698_915_036,
+            "abc",
+            "00"
+        ));
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert!(!verify_key(&other, "POST", "/api/beat/key", 1_698_915_036, "abc", &sig));
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        assert!(parse_public_key("not hex").is_none());
+        assert!(parse_public_key("abcd").is_none());
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert_eq!(
+            parse_public_key(&hex::encode(key.to_bytes())),
+            Some(key.to_bytes().to_vec())
+        );
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Optional timestamps, serialized as Unix timestamps in seconds.
+
+use chrono::serde::ts_seconds;
+use serde::Deserialize;
+
+#[allow(clippy::ref_option)] // serde compat
+pub fn serialize<S: serde::Serializer>(
+    ts: &Option<chrono::DateTime<chrono::Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match ts {
+        Some(ts) => ts_seconds::serialize(ts, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, D::Error> {
+    Option::<i64>::deserialize(deserializer)?
+        .map(|ts| {
+            chrono::DateTime::from_timestamp(ts, 0).ok_or_else(|| serde::de::Error::custom("timestamp out of range"))
+        })
+        .transpose()
+}
@@ -0,0 +1,129 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use heartbeat_api::problem::{ProblemDetails, PROBLEM_CONTENT_TYPE};
+use reqwest::{header::CONTENT_TYPE, Response, StatusCode};
+use tokio_tungstenite::tungstenite;
+
+/// An error returned by the [`Client`](crate::Client).
+///
+/// Errors returned by the server are mapped to a variant by their status code,
+/// and carry the reason the server gave, if any.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The request was malformed, such as invalid metadata, a probe outside
+    /// the allowed limits, or beats that can't be backfilled.
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    /// The token, secret key or signature was missing or invalid.
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    /// The credentials were valid, but not for this request.
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    /// The device doesn't exist, or the route is disabled because the server
+    /// has no secret key.
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The request body was larger than the server accepts.
+    #[error("payload too large")]
+    PayloadTooLarge,
+    /// The server failed to handle the request.
+    #[error("server error ({0}): {1}")]
+    Server(StatusCode, String),
+    /// The server returned a status code the client doesn't know about.
+    #[error("unexpected status ({0}): {1}")]
+    Status(StatusCode, String),
+    /// The client wasn't given the credentials this request needs.
+    #[error("missing credentials: {0}")]
+    MissingCredentials(&'static str),
+    /// The response body could not be parsed.
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    /// A WebSocket message could not be serialized or deserialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// The request could not be sent, or the response could not be read.
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    /// The WebSocket connection failed.
+    #[error(transparent)]
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl Error {
+    /// Maps an error status returned by the server, along with its reason.
+    pub(crate) fn from_status(status: StatusCode, reason: String) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest(reason),
+            StatusCode::UNAUTHORIZED => Self::Unauthorized(reason),
+            StatusCode::FORBIDDEN => Self::Forbidden(reason),
+            StatusCode::NOT_FOUND => Self::NotFound(reason),
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            s if s.is_server_error() => Self::Server(s, reason),
+            s => Self::Status(s, reason),
+        }
+    }
+
+    /// Reads the reason from an unsuccessful response.
+    ///
+    /// The reason is the `detail` of the problem details object the server
+    /// responds with. Plain text bodies from older servers are used as-is.
+    pub(crate) async fn from_response(resp: Response) -> Self {
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let body = resp.text().await.unwrap_or_default();
+        let reason = if content_type.starts_with(PROBLEM_CONTENT_TYPE) {
+            serde_json::from_str::<ProblemDetails>(&body).ok().map(|p| p.detail)
+        } else {
+            (content_type.starts_with("text/plain") && !body.is_empty()).then_some(body)
+        };
+        let reason = reason.unwrap_or_else(|| status.canonical_reason().unwrap_or_default().to_owned());
+        Self::from_status(status, reason)
+    }
+
+    /// The status code returned by the server, if this error was caused by
+    /// one.
+    #[must_use]
+    pub const fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::BadRequest(_) => Some(StatusCode::BAD_REQUEST),
+            Self::Unauthorized(_) => Some(StatusCode::UNAUTHORIZED),
+            Self::Forbidden(_) => Some(StatusCode::FORBIDDEN),
+            Self::NotFound(_) => Some(StatusCode::NOT_FOUND),
+            Self::PayloadTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            Self::Server(s, _) | Self::Status(s, _) => Some(*s),
+            _ => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            // the handshake was rejected with an error status
+            tungstenite::Error::Http(resp) => {
+                let status = StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let reason = resp
+                    .into_body()
+                    .and_then(|body| match serde_json::from_slice::<ProblemDetails>(&body) {
+                        Ok(problem) => Some(problem.detail),
+                        Err(_) => String::from_utf8(body).ok(),
+                    })
+                    .filter(|body| !body.is_empty())
+                    .unwrap_or_else(|| status.canonical_reason().unwrap_or_default().to_owned());
+                Self::from_status(status, reason)
+            }
+            e => Self::WebSocket(Box::new(e)),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#![forbid(unsafe_code)]
+#![deny(
+    missing_docs,
+    clippy::all,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::unwrap_used,
+    clippy::unwrap_in_result
+)]
+
+//! A typed async client for the [Heartbeat](https://github.com/lmaotrigine/heartbeat) API.
+//!
+//! The request and response types are the ones the server itself uses, from
+//! the `heartbeat-api` crate, so the two can't drift apart.
+//!
+//! ```no_run
+//! # async fn run() -> heartbeat_client::Result<()> {
+//! use heartbeat_client::Client;
+//!
+//! let client = Client::new("https://hb.example.com").with_token("<device token>");
+//! let acknowledged_at = client.beat(None).await?;
+//! let stats = client.stats().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use chrono::{DateTime, Utc};
+use erased_debug::Erased;
+use heartbeat_api::{signature, DEVICE_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use reqwest::{header::AUTHORIZATION, Method, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+
+mod error;
+#[cfg(test)]
+mod tests;
+mod ws;
+
+pub use ed25519_dalek::SigningKey;
+pub use error::Error;
+pub use heartbeat_api::{
+    devices::{Credential, Device, Metadata, MetadataField, PostBeats, PostDevice, Probe, ProbeCheck},
+    routes::{BatchResp, DeviceAddResp, DeviceUpdateResp, ProbeResp, SigningSecretResp, StatsResp},
+};
+pub use ws::{Presence, StatsStream};
+
+/// A [`Result`](std::result::Result) with [`Error`] as the default error type.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A client for a single Heartbeat server.
+///
+/// Which routes can be used depends on the credentials the client was given:
+///
+/// - Beats need a device token, and the device's signing secret if it has one.
+/// - Key beats need the device's ID and Ed25519 signing key.
+/// - Managing devices needs the server's secret key.
+/// - Stats are public.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<Erased<String>>,
+    signing_secret: Option<Erased<String>>,
+    signing_key: Option<(i64, Erased<SigningKey>)>,
+    secret_key: Option<Erased<String>>,
+}
+
+impl Client {
+    /// Creates a client for the server at `base_url`, such as
+    /// `https://hb.example.com`.
+    #[must_use]
+    pub fn new(base_url: &str) -> Self {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Like [`Client::new`], but sends requests with the given
+    /// [`reqwest::Client`].
+    #[must_use]
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> Self {
+        Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token: None,
+            signing_secret: None,
+            signing_key: None,
+            secret_key: None,
+        }
+    }
+
+    /// Sets the device token beats are sent with.
+    #[must_use]
+    pub fn with_token(self, token: impl Into<String>) -> Self {
+        Self {
+            token: Some(Erased(token.into())),
+            ..self
+        }
+    }
+
+    /// Sets the secret beats are signed with, for devices that require signed
+    /// beats.
+    #[must_use]
+    pub fn with_signing_secret(self, signing_secret: impl Into<String>) -> Self {
+        Self {
+            signing_secret: Some(Erased(signing_secret.into())),
+            ..self
+        }
+    }
+
+    /// Sets the ID and private key [`Client::key_beat`] authenticates with.
+    #[must_use]
+    pub fn with_signing_key(self, device_id: i64, key: SigningKey) -> Self {
+        Self {
+            signing_key: Some((device_id, Erased(key))),
+            ..self
+        }
+    }
+
+    /// Sets the server's secret key, which is needed to manage devices.
+    #[must_use]
+    pub fn with_secret_key(self, secret_key: impl Into<String>) -> Self {
+        Self {
+            secret_key: Some(Erased(secret_key.into())),
+            ..self
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Headers that authenticate a request as the device, signing it if
+    /// needed.
+    fn device_headers(&self, method: &Method, path: &str) -> Result<Vec<(&'static str, String)>> {
+        let token = self.token.as_ref().ok_or(Error::MissingCredentials("device token"))?;
+        let mut headers = vec![(AUTHORIZATION.as_str(), token.0.clone())];
+        if let Some(secret) = &self.signing_secret {
+            let (timestamp, nonce) = (Utc::now().timestamp(), signature::generate_nonce());
+            let sig = signature::sign(secret, method.as_str(), path, timestamp, &nonce);
+            headers.extend([
+                (TIMESTAMP_HEADER, timestamp.to_string()),
+                (NONCE_HEADER, nonce),
+                (SIGNATURE_HEADER, sig),
+            ]);
+        }
+        Ok(headers)
+    }
+
+    fn device_request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let headers = self.device_headers(&method, path)?;
+        Ok(headers
+            .into_iter()
+            .fold(self.http.request(method, self.url(path)), |req, (k, v)| {
+                req.header(k, v)
+            }))
+    }
+
+    fn master_request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let secret_key = self
+            .secret_key
+            .as_ref()
+            .ok_or(Error::MissingCredentials("secret key"))?;
+        Ok(self
+            .http
+            .request(method, self.url(path))
+            .header(AUTHORIZATION, secret_key.as_str()))
+    }
+
+    async fn send(req: RequestBuilder) -> Result<Response> {
+        let resp = req.send().await?;
+        if resp.status().is_success() {
+            Ok(resp)
+        } else {
+            Err(Error::from_response(resp).await)
+        }
+    }
+
+    async fn send_json<T: DeserializeOwned>(req: RequestBuilder) -> Result<T> {
+        Ok(Self::send(req).await?.json().await?)
+    }
+
+    /// Sends a beat request, returning the time the beat was acknowledged.
+    async fn send_beat(req: RequestBuilder, metadata: Option<&Metadata>) -> Result<DateTime<Utc>> {
+        let req = match metadata {
+            Some(metadata) => req.json(metadata),
+            None => req,
+        };
+        let body = Self::send(req).await?.text().await?;
+        body.trim()
+            .parse()
+            .ok()
+            .and_then(|ts| DateTime::from_timestamp(ts, 0))
+            .ok_or(Error::InvalidResponse(body))
+    }
+
+    /// Checks that the server is up.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the server could not be reached or
+    /// isn't healthy.
+    pub async fn health(&self) -> Result<()> {
+        Self::send(self.http.get(self.url("/.well-known/health"))).await?;
+        Ok(())
+    }
+
+    /// Sends a beat with `POST /api/v1/beat`, returning the time the server
+    /// acknowledged it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no token was set, the request fails,
+    /// or the server rejects the beat.
+    pub async fn beat(&self, metadata: Option<&Metadata>) -> Result<DateTime<Utc>> {
+        Self::send_beat(self.device_request(Method::POST, "/api/v1/beat")?, metadata).await
+    }
+
+    /// Sends a beat signed with the device's Ed25519 key with
+    /// `POST /api/v1/beat/key`, returning the time the server acknowledged it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no signing key was set, the request
+    /// fails, or the server rejects the beat.
+    pub async fn key_beat(&self, metadata: Option<&Metadata>) -> Result<DateTime<Utc>> {
+        const PATH: &str = "/api/v1/beat/key";
+        let (device_id, key) = self
+            .signing_key
+            .as_ref()
+            .ok_or(Error::MissingCredentials("signing key"))?;
+        let (timestamp, nonce) = (Utc::now().timestamp(), signature::generate_nonce());
+        let sig = signature::sign_key(key, Method::POST.as_str(), PATH, timestamp, &nonce);
+        let req = self
+            .http
+            .post(self.url(PATH))
+            .header(DEVICE_HEADER, device_id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, nonce)
+            .header(SIGNATURE_HEADER, sig);
+        Self::send_beat(req, metadata).await
+    }
+
+    /// Backfills beats that were missed, given as Unix timestamps, with
+    /// `POST /api/v1/beats/batch`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no token was set, the request fails,
+    /// or the server rejects the batch.
+    pub async fn beat_batch(&self, beats: &[i64]) -> Result<BatchResp> {
+        let body = PostBeats { beats: beats.to_vec() };
+        Self::send_json(self.device_request(Method::POST, "/api/v1/beats/batch")?.json(&body)).await
+    }
+
+    /// Connects to `/api/v1/beat/ws`, which records beats for as long as the
+    /// connection is open.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no token was set, or the connection
+    /// fails or is rejected.
+    pub async fn presence(&self) -> Result<Presence> {
+        const PATH: &str = "/api/v1/beat/ws";
+        let headers = self.device_headers(&Method::GET, PATH)?;
+        Ok(Presence(ws::connect(&self.url(PATH), headers).await?))
+    }
+
+    /// Fetches the current stats with `GET /api/v1/stats`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the request fails.
+    pub async fn stats(&self) -> Result<StatsResp> {
+        Self::send_json(self.http.get(self.url("/api/v1/stats"))).await
+    }
+
+    /// Connects to `/api/v1/stats/ws`, which sends the current stats every
+    /// second.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the connection fails.
+    pub async fn stats_stream(&self) -> Result<StatsStream> {
+        Ok(StatsStream(
+            ws::connect(&self.url("/api/v1/stats/ws"), Vec::new()).await?,
+        ))
+    }
+
+    /// Fetches a device, including its probe and all of its metadata.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, or the device doesn't exist.
+    pub async fn device(&self, device_id: i64) -> Result<Device> {
+        Self::send_json(self.master_request(Method::GET, &format!("/api/v1/devices/{device_id}"))?).await
+    }
+
+    /// Adds a new device.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, or the device is invalid.
+    pub async fn add_device(&self, device: &PostDevice) -> Result<DeviceAddResp> {
+        Self::send_json(self.master_request(Method::POST, "/api/v1/devices")?.json(device)).await
+    }
+
+    /// Generates a new token for a device. The previous token is accepted
+    /// until the returned `previous_token_expires_at`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, or the device doesn't exist.
+    pub async fn regenerate_token(&self, device_id: i64) -> Result<DeviceUpdateResp> {
+        let path = format!("/api/v1/devices/{device_id}/token/generate");
+        Self::send_json(self.master_request(Method::POST, &path)?).await
+    }
+
+    /// Generates a new signing secret for a device, after which its beats
+    /// must be signed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, or the device doesn't exist.
+    pub async fn regenerate_signing_secret(&self, device_id: i64) -> Result<SigningSecretResp> {
+        let path = format!("/api/v1/devices/{device_id}/secret/generate");
+        Self::send_json(self.master_request(Method::POST, &path)?).await
+    }
+
+    /// Removes a device's signing secret, after which its beats no longer
+    /// need to be signed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, or the device doesn't exist.
+    pub async fn delete_signing_secret(&self, device_id: i64) -> Result<SigningSecretResp> {
+        let path = format!("/api/v1/devices/{device_id}/secret");
+        Self::send_json(self.master_request(Method::DELETE, &path)?).await
+    }
+
+    /// Sets how the server probes a device.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, the probe is invalid, or the device doesn't exist.
+    pub async fn set_probe(&self, device_id: i64, probe: &Probe) -> Result<ProbeResp> {
+        let path = format!("/api/v1/devices/{device_id}/probe");
+        Self::send_json(self.master_request(Method::PUT, &path)?.json(probe)).await
+    }
+
+    /// Stops the server from probing a device.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if no secret key was set, the request
+    /// fails, or the device doesn't exist.
+    pub async fn delete_probe(&self, device_id: i64) -> Result<ProbeResp> {
+        let path = format!("/api/v1/devices/{device_id}/probe");
+        Self::send_json(self.master_request(Method::DELETE, &path)?).await
+    }
+}
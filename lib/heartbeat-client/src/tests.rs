@@ -0,0 +1,259 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// These run against the server's router in-process, with an empty in-memory
+// store for each test.
+
+use crate::{Client, Error, Metadata, PostDevice, Probe, ProbeCheck, SigningKey};
+use axum::middleware;
+use chrono::Utc;
+use clap::Parser;
+use heartbeat::{handle_errors, routes::router, storage::Memory, AppState, Config, WebCli};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
+use tokio::net::TcpListener;
+
+const SECRET_KEY: &str = "client-test-secret";
+
+fn config() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let cli = WebCli::parse_from(["heartbeat", "--secret-key", SECRET_KEY]);
+        Config::try_new(cli).expect("valid config")
+    })
+}
+
+/// Serves the router on a random port, returning a client with the secret key.
+async fn serve() -> Client {
+    let config = config();
+    let state = AppState::with_storage(config, Arc::new(Memory::default())).await;
+    let app = router(config)
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state, handle_errors))
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("port to be free");
+    let addr = listener.local_addr().expect("listener to have an address");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Client::new(&format!("http://{addr}")).with_secret_key(SECRET_KEY)
+}
+
+fn new_device(name: &str) -> PostDevice {
+    PostDevice {
+        name: name.to_owned(),
+        signed_beats: false,
+        public_key: None,
+        probe: None,
+    }
+}
+
+#[tokio::test]
+async fn test_device_lifecycle() {
+    let admin = serve().await;
+    admin.health().await.expect("server to be healthy");
+    let added = admin
+        .add_device(&new_device("lifecycle"))
+        .await
+        .expect("device to be added");
+    assert_eq!(added.name.as_deref(), Some("lifecycle"));
+    assert!(added.signing_secret.is_none());
+
+    let device = admin.clone().with_token(&added.token);
+    let metadata = Metadata {
+        battery: Some(42),
+        ..Metadata::default()
+    };
+    let acknowledged_at = device.beat(Some(&metadata)).await.expect("beat to be recorded");
+    assert!((Utc::now() - acknowledged_at).num_seconds() < 5);
+    let fetched = admin.device(added.id).await.expect("device to exist");
+    assert_eq!(fetched.metadata.and_then(|m| m.battery), Some(42));
+
+    let ts = Utc::now().timestamp() - 60;
+    let batch = device.beat_batch(&[ts]).await.expect("batch to be recorded");
+    assert_eq!((batch.inserted, batch.duplicates), (1, 0));
+    let batch = device.beat_batch(&[ts]).await.expect("batch to be recorded");
+    assert_eq!((batch.inserted, batch.duplicates), (0, 1));
+
+    let rotated = admin.regenerate_token(added.id).await.expect("token to be rotated");
+    assert_ne!(rotated.token, added.token);
+    assert!(rotated.previous_token_expires_at.is_some());
+    admin
+        .clone()
+        .with_token(rotated.token)
+        .beat(None)
+        .await
+        .expect("new token to work");
+
+    let stats = admin.stats().await.expect("stats to be public");
+    let public = stats
+        .devices
+        .iter()
+        .find(|d| d.id == added.id)
+        .expect("device to be listed");
+    assert!(public.probe.is_none());
+}
+
+#[tokio::test]
+async fn test_errors_are_typed() {
+    let admin = serve().await;
+    let anonymous = Client::new(&admin.base_url);
+    assert!(matches!(anonymous.device(1).await, Err(Error::MissingCredentials(_))));
+    assert!(matches!(anonymous.beat(None).await, Err(Error::MissingCredentials(_))));
+    let err = anonymous
+        .with_token("not a token")
+        .beat(None)
+        .await
+        .expect_err("token to be rejected");
+    assert!(matches!(&err, Error::Unauthorized(reason) if reason == "Invalid token."));
+    assert_eq!(err.status(), Some(reqwest::StatusCode::UNAUTHORIZED));
+    let wrong_key = Client::new(&admin.base_url).with_secret_key("wrong");
+    assert!(matches!(wrong_key.device(1).await, Err(Error::Unauthorized(_))));
+    assert!(matches!(admin.device(-1).await, Err(Error::NotFound(_))));
+
+    let added = admin
+        .add_device(&new_device("errors"))
+        .await
+        .expect("device to be added");
+    let device = admin.clone().with_token(added.token);
+    let future = Utc::now().timestamp() + 3600;
+    let batch = device.beat_batch(&[future, 0]).await.expect("beats to be skipped");
+    assert_eq!(
+        (batch.inserted, batch.future, batch.too_old),
+        (0, vec![future], vec![0])
+    );
+    match device.beat_batch(&[i64::MAX]).await {
+        Err(Error::BadRequest(reason)) => assert_eq!(reason, "Invalid timestamp."),
+        res => panic!("expected a bad request, got {res:?}"),
+    }
+    let invalid = Metadata {
+        battery: Some(101),
+        ..Metadata::default()
+    };
+    assert!(matches!(device.beat(Some(&invalid)).await, Err(Error::BadRequest(_))));
+    let probe = Probe {
+        interval: 1,
+        check: ProbeCheck::Dns {
+            name: "localhost".into(),
+        },
+    };
+    assert!(matches!(
+        admin.set_probe(added.id, &probe).await,
+        Err(Error::BadRequest(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_signed_beats() {
+    let admin = serve().await;
+    let added = admin
+        .add_device(&PostDevice {
+            signed_beats: true,
+            ..new_device("signed")
+        })
+        .await
+        .expect("device to be added");
+    let secret = added.signing_secret.expect("device to have a signing secret");
+    let unsigned = admin.clone().with_token(&added.token);
+    assert!(matches!(unsigned.beat(None).await, Err(Error::Unauthorized(_))));
+    let signed = unsigned.clone().with_signing_secret(secret);
+    signed.beat(None).await.expect("signed beat to be recorded");
+    signed
+        .beat_batch(&[Utc::now().timestamp() - 10])
+        .await
+        .expect("signed batch to be recorded");
+
+    let regenerated = admin
+        .regenerate_signing_secret(added.id)
+        .await
+        .expect("secret to be regenerated");
+    assert!(matches!(signed.beat(None).await, Err(Error::Unauthorized(_))));
+    let new_secret = regenerated.signing_secret.expect("a new signing secret");
+    unsigned
+        .clone()
+        .with_signing_secret(new_secret)
+        .beat(None)
+        .await
+        .expect("new secret to work");
+    let removed = admin
+        .delete_signing_secret(added.id)
+        .await
+        .expect("secret to be removed");
+    assert!(removed.signing_secret.is_none());
+    unsigned.beat(None).await.expect("unsigned beat to be recorded");
+}
+
+#[tokio::test]
+async fn test_key_beats() {
+    let admin = serve().await;
+    let key = SigningKey::from_bytes(&[9; 32]);
+    let public_key = hex::encode(key.verifying_key().to_bytes());
+    let added = admin
+        .add_device(&PostDevice {
+            public_key: Some(public_key),
+            ..new_device("key")
+        })
+        .await
+        .expect("device to be added");
+    let device = Client::new(&admin.base_url).with_signing_key(added.id, key);
+    device.key_beat(None).await.expect("key beat to be recorded");
+    let other = Client::new(&admin.base_url).with_signing_key(added.id, SigningKey::from_bytes(&[10; 32]));
+    assert!(matches!(other.key_beat(None).await, Err(Error::Unauthorized(_))));
+}
+
+#[tokio::test]
+async fn test_probes() {
+    let admin = serve().await;
+    let added = admin
+        .add_device(&new_device("probe"))
+        .await
+        .expect("device to be added");
+    let probe = Probe {
+        interval: 60,
+        check: ProbeCheck::Tcp {
+            address: "127.0.0.1:1".into(),
+        },
+    };
+    let set = admin.set_probe(added.id, &probe).await.expect("probe to be set");
+    assert_eq!(set.probe.as_ref(), Some(&probe));
+    assert_eq!(
+        admin.device(added.id).await.expect("device to exist").probe,
+        Some(probe)
+    );
+    let removed = admin.delete_probe(added.id).await.expect("probe to be removed");
+    assert!(removed.probe.is_none());
+    assert!(matches!(admin.delete_probe(-1).await, Err(Error::NotFound(_))));
+}
+
+#[tokio::test]
+async fn test_realtime() {
+    let admin = serve().await;
+    let mut stream = admin.stats_stream().await.expect("stream to connect");
+    let stats = stream.next().await.expect("an update").expect("valid stats");
+    assert!(stats.uptime >= 0);
+    stream.close().await.expect("stream to close");
+
+    let added = admin
+        .add_device(&new_device("presence"))
+        .await
+        .expect("device to be added");
+    let device = admin.clone().with_token(&added.token);
+    device.beat(None).await.expect("beat to be recorded");
+    let before = admin.device(added.id).await.expect("device to exist").num_beats;
+    let mut presence = device.presence().await.expect("presence to connect");
+    presence.idle().await.expect("idle to be sent");
+    presence.close().await.expect("presence to close");
+    let after = admin.device(added.id).await.expect("device to exist").num_beats;
+    // one beat on connection, and one for the idle message
+    assert_eq!(after, before + 2);
+
+    let err = Client::new(&admin.base_url)
+        .with_token("not a token")
+        .presence()
+        .await
+        .expect_err("token to be rejected");
+    assert!(matches!(err, Error::Unauthorized(_)));
+}
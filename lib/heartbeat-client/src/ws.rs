@@ -0,0 +1,112 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Error, Metadata, Result, StatsResp};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Opens a WebSocket to an `http://` or `https://` URL with the given headers.
+pub async fn connect(url: &str, headers: Vec<(&'static str, String)>) -> Result<Socket> {
+    let url = url
+        .strip_prefix("http")
+        .map_or_else(|| url.to_owned(), |rest| format!("ws{rest}"));
+    let mut req = url.into_client_request()?;
+    for (name, value) in headers {
+        let value = HeaderValue::from_str(&value).map_err(|e| tungstenite::Error::HttpFormat(e.into()))?;
+        req.headers_mut().insert(name, value);
+    }
+    Ok(tokio_tungstenite::connect_async(req).await?.0)
+}
+
+/// The stats sent by `/api/v1/stats/ws`, returned by
+/// [`Client::stats_stream`](crate::Client::stats_stream).
+#[derive(Debug)]
+pub struct StatsStream(pub(crate) Socket);
+
+impl StatsStream {
+    /// Waits for the next update, returning `None` once the server closes the
+    /// connection.
+    pub async fn next(&mut self) -> Option<Result<StatsResp>> {
+        while let Some(msg) = self.0.next().await {
+            match msg {
+                Ok(Message::Text(text)) => return Some(serde_json::from_str(&text).map_err(Error::from)),
+                Ok(Message::Close(_)) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        None
+    }
+
+    /// Closes the connection.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the close frame could not be sent.
+    pub async fn close(mut self) -> Result<()> {
+        Ok(self.0.close(None).await?)
+    }
+}
+
+/// A connection to `/api/v1/beat/ws`, returned by
+/// [`Client::presence`](crate::Client::presence).
+///
+/// The server records a beat on connection, periodically while the device is
+/// active, and on every message.
+#[derive(Debug)]
+pub struct Presence(pub(crate) Socket);
+
+impl Presence {
+    async fn send(&mut self, text: String) -> Result<()> {
+        Ok(self.0.send(Message::Text(text)).await?)
+    }
+
+    /// Pauses the periodic beats until the device is active again.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message could not be sent.
+    pub async fn idle(&mut self) -> Result<()> {
+        self.send("idle".to_owned()).await
+    }
+
+    /// Records a beat and resumes the periodic beats.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message could not be sent.
+    pub async fn active(&mut self) -> Result<()> {
+        self.send("active".to_owned()).await
+    }
+
+    /// Records a beat with metadata and resumes the periodic beats.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message could not be sent.
+    pub async fn metadata(&mut self, metadata: &Metadata) -> Result<()> {
+        self.send(serde_json::to_string(metadata)?).await
+    }
+
+    /// Closes the connection cleanly, which records a final beat unless the
+    /// device is idle.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the close frame could not be sent.
+    pub async fn close(mut self) -> Result<()> {
+        self.0.close(None).await?;
+        // wait for the server to acknowledge the close
+        while self.0.next().await.is_some_and(|msg| msg.is_ok()) {}
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- tokens that have been rotated out but are still accepted until `expires_at`
+CREATE TABLE heartbeat.superseded_tokens (
+  token TEXT PRIMARY KEY,
+  device BIGINT NOT NULL REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
+  superseded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc'),
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+CREATE INDEX superseded_tokens_device_idx ON heartbeat.superseded_tokens (device);
+-- whether the beat was authenticated with a superseded token
+ALTER TABLE heartbeat.beats ADD COLUMN superseded_token BOOLEAN NOT NULL DEFAULT FALSE;
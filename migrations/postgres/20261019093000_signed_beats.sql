@@ -0,0 +1,16 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- when set, beats from this device must be signed with this secret
+ALTER TABLE heartbeat.devices ADD COLUMN signing_secret TEXT;
+-- nonces of signed beats, kept until their timestamp leaves the allowed window
+CREATE TABLE heartbeat.beat_nonces (
+  device BIGINT NOT NULL REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
+  nonce TEXT NOT NULL,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY (device, nonce)
+);
+CREATE INDEX beat_nonces_expires_at_idx ON heartbeat.beat_nonces (expires_at);
@@ -0,0 +1,13 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- an Ed25519 public key that may be used instead of the device's token
+ALTER TABLE heartbeat.devices ADD COLUMN public_key BYTEA;
+-- beats can now be authenticated with a key as well as a (superseded) token
+CREATE TYPE heartbeat.credential AS ENUM ('current', 'superseded', 'key');
+ALTER TABLE heartbeat.beats ADD COLUMN credential heartbeat.credential NOT NULL DEFAULT 'current';
+UPDATE heartbeat.beats SET credential = 'superseded' WHERE superseded_token;
+ALTER TABLE heartbeat.beats DROP COLUMN superseded_token;
@@ -0,0 +1,12 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- the most recent metadata sent along with a beat from each device
+CREATE TABLE heartbeat.device_state (
+  device BIGINT PRIMARY KEY REFERENCES heartbeat.devices(id) ON DELETE CASCADE,
+  metadata JSONB NOT NULL,
+  updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc')
+);
@@ -0,0 +1,10 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- devices that can't send beats themselves are polled by the server instead
+ALTER TABLE heartbeat.devices ADD COLUMN probe JSONB;
+
+ALTER TYPE heartbeat.credential ADD VALUE 'probe';
@@ -0,0 +1,21 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- events shown in the activity feeds. devices being added are read from
+-- heartbeat.devices instead.
+CREATE TYPE heartbeat.event_kind AS ENUM ('absence', 'longest_absence', 'server_start');
+
+CREATE TABLE heartbeat.events (
+    id BIGINT PRIMARY KEY,
+    kind heartbeat.event_kind NOT NULL,
+    time_stamp TIMESTAMPTZ NOT NULL,
+    -- when the absence started, for absences and records
+    started_at TIMESTAMPTZ,
+    -- the device whose beat ended the absence
+    device BIGINT REFERENCES heartbeat.devices (id) ON DELETE SET NULL
+);
+
+CREATE INDEX events_time_stamp_idx ON heartbeat.events (time_stamp DESC);
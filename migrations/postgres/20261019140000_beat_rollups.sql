@@ -0,0 +1,41 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- beats older than the retention period are compacted into hourly rollups, and
+-- those into daily rollups in turn. each keeps the number of beats in it, the
+-- first and last of them, and how the last one was authenticated.
+CREATE TABLE heartbeat.beats_hourly (
+    device BIGINT NOT NULL REFERENCES heartbeat.devices (id) ON DELETE CASCADE,
+    hour TIMESTAMPTZ NOT NULL,
+    num_beats BIGINT NOT NULL,
+    first_beat TIMESTAMPTZ NOT NULL,
+    last_beat TIMESTAMPTZ NOT NULL,
+    last_credential heartbeat.credential NOT NULL,
+    PRIMARY KEY (device, hour)
+);
+
+CREATE TABLE heartbeat.beats_daily (
+    device BIGINT NOT NULL REFERENCES heartbeat.devices (id) ON DELETE CASCADE,
+    day TIMESTAMPTZ NOT NULL,
+    num_beats BIGINT NOT NULL,
+    first_beat TIMESTAMPTZ NOT NULL,
+    last_beat TIMESTAMPTZ NOT NULL,
+    last_credential heartbeat.credential NOT NULL,
+    PRIMARY KEY (device, day)
+);
+
+-- the gaps between compacted beats can't be recomputed, so the longest of
+-- them is kept here
+ALTER TABLE heartbeat.stats ADD COLUMN compacted_absence INTERVAL NOT NULL DEFAULT '0 seconds';
+
+-- raw beats and rollups alike, as runs of beats. a raw beat is a run of one.
+CREATE VIEW heartbeat.beat_runs AS
+    SELECT device, time_stamp AS first_beat, time_stamp AS last_beat, credential AS last_credential
+    FROM heartbeat.beats
+    UNION ALL
+    SELECT device, first_beat, last_beat, last_credential FROM heartbeat.beats_hourly
+    UNION ALL
+    SELECT device, first_beat, last_beat, last_credential FROM heartbeat.beats_daily;
@@ -0,0 +1,24 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- the last beat of each device, and of any device, are kept up to date with
+-- every beat so they don't have to be found among all of them.
+ALTER TABLE heartbeat.devices
+    ADD COLUMN last_beat_at TIMESTAMPTZ,
+    ADD COLUMN last_credential heartbeat.credential;
+
+ALTER TABLE heartbeat.stats ADD COLUMN last_seen TIMESTAMPTZ;
+
+UPDATE heartbeat.devices d
+SET last_beat_at = r.last_beat, last_credential = r.last_credential
+FROM (
+    SELECT DISTINCT ON (device) device, last_beat, last_credential
+    FROM heartbeat.beat_runs
+    ORDER BY device, last_beat DESC
+) r
+WHERE r.device = d.id;
+
+UPDATE heartbeat.stats SET last_seen = (SELECT MAX(last_beat_at) FROM heartbeat.devices);
@@ -0,0 +1,8 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- the neighbours of a batch of beats are looked up across all devices
+CREATE INDEX beats_time_stamp_idx ON heartbeat.beats (time_stamp);
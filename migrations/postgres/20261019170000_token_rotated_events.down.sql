@@ -0,0 +1,12 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- values can't be dropped from an enum, so it is created again without it
+DELETE FROM heartbeat.events WHERE kind = 'token_rotated';
+ALTER TYPE heartbeat.event_kind RENAME TO event_kind_old;
+CREATE TYPE heartbeat.event_kind AS ENUM ('absence', 'longest_absence', 'server_start');
+ALTER TABLE heartbeat.events ALTER COLUMN kind TYPE heartbeat.event_kind USING kind::TEXT::heartbeat.event_kind;
+DROP TYPE heartbeat.event_kind_old;
@@ -0,0 +1,78 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- the same schema as the PostgreSQL migrations up to 20261019120000_events.
+-- timestamps are RFC 3339 text in UTC, and absences are in seconds.
+
+CREATE TABLE devices (
+  id INTEGER PRIMARY KEY,
+  name TEXT,
+  token TEXT NOT NULL,
+  num_beats INTEGER NOT NULL DEFAULT 0,
+  -- when set, beats from this device must be signed with this secret
+  signing_secret TEXT,
+  -- an Ed25519 public key that may be used instead of the device's token
+  public_key BLOB,
+  -- UDP beats carry a counter which must always increase, to prevent replays
+  udp_counter INTEGER NOT NULL DEFAULT 0,
+  -- devices that can't send beats themselves are polled by the server instead
+  probe TEXT
+);
+CREATE INDEX devices_token_idx ON devices (token);
+
+CREATE TABLE beats (
+  device INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+  time_stamp TEXT NOT NULL,
+  credential TEXT NOT NULL DEFAULT 'current'
+    CHECK (credential IN ('current', 'superseded', 'key', 'udp', 'probe')),
+  PRIMARY KEY (device, time_stamp)
+);
+CREATE INDEX beats_time_stamp_idx ON beats (time_stamp);
+
+CREATE TABLE stats (
+  _id INTEGER PRIMARY KEY,
+  total_visits INTEGER NOT NULL DEFAULT 0,
+  server_start_time TEXT NOT NULL,
+  longest_absence INTEGER NOT NULL DEFAULT 0
+);
+
+-- tokens that have been rotated out but are still accepted until `expires_at`
+CREATE TABLE superseded_tokens (
+  token TEXT PRIMARY KEY,
+  device INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+  superseded_at TEXT NOT NULL,
+  expires_at TEXT NOT NULL
+);
+CREATE INDEX superseded_tokens_device_idx ON superseded_tokens (device);
+
+-- nonces of signed beats, kept until their timestamp leaves the allowed window
+CREATE TABLE beat_nonces (
+  device INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+  nonce TEXT NOT NULL,
+  expires_at TEXT NOT NULL,
+  PRIMARY KEY (device, nonce)
+);
+CREATE INDEX beat_nonces_expires_at_idx ON beat_nonces (expires_at);
+
+-- the most recent metadata sent along with a beat from each device
+CREATE TABLE device_state (
+  device INTEGER PRIMARY KEY REFERENCES devices (id) ON DELETE CASCADE,
+  metadata TEXT NOT NULL,
+  updated_at TEXT NOT NULL
+);
+
+-- events shown in the activity feeds. devices being added are read from
+-- devices instead.
+CREATE TABLE events (
+  id INTEGER PRIMARY KEY,
+  kind TEXT NOT NULL CHECK (kind IN ('absence', 'longest_absence', 'server_start')),
+  time_stamp TEXT NOT NULL,
+  -- when the absence started, for absences and records
+  started_at TEXT,
+  -- the device whose beat ended the absence
+  device INTEGER REFERENCES devices (id) ON DELETE SET NULL
+);
+CREATE INDEX events_time_stamp_idx ON events (time_stamp DESC);
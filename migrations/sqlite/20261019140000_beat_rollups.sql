@@ -0,0 +1,41 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- beats older than the retention period are compacted into hourly rollups, and
+-- those into daily rollups in turn. each keeps the number of beats in it, the
+-- first and last of them, and how the last one was authenticated.
+CREATE TABLE beats_hourly (
+  device INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+  hour TEXT NOT NULL,
+  num_beats INTEGER NOT NULL,
+  first_beat TEXT NOT NULL,
+  last_beat TEXT NOT NULL,
+  last_credential TEXT NOT NULL,
+  PRIMARY KEY (device, hour)
+);
+
+CREATE TABLE beats_daily (
+  device INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+  day TEXT NOT NULL,
+  num_beats INTEGER NOT NULL,
+  first_beat TEXT NOT NULL,
+  last_beat TEXT NOT NULL,
+  last_credential TEXT NOT NULL,
+  PRIMARY KEY (device, day)
+);
+
+-- the gaps between compacted beats can't be recomputed, so the longest of
+-- them is kept here, in seconds
+ALTER TABLE stats ADD COLUMN compacted_absence INTEGER NOT NULL DEFAULT 0;
+
+-- raw beats and rollups alike, as runs of beats. a raw beat is a run of one.
+CREATE VIEW beat_runs AS
+  SELECT device, time_stamp AS first_beat, time_stamp AS last_beat, credential AS last_credential
+  FROM beats
+  UNION ALL
+  SELECT device, first_beat, last_beat, last_credential FROM beats_hourly
+  UNION ALL
+  SELECT device, first_beat, last_beat, last_credential FROM beats_daily;
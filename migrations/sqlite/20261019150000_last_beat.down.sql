@@ -0,0 +1,9 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+ALTER TABLE stats DROP COLUMN last_seen;
+ALTER TABLE devices DROP COLUMN last_credential;
+ALTER TABLE devices DROP COLUMN last_beat_at;
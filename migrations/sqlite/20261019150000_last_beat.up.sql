@@ -0,0 +1,19 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- the last beat of each device, and of any device, are kept up to date with
+-- every beat so they don't have to be found among all of them.
+ALTER TABLE devices ADD COLUMN last_beat_at TEXT;
+ALTER TABLE devices ADD COLUMN last_credential TEXT;
+ALTER TABLE stats ADD COLUMN last_seen TEXT;
+
+UPDATE devices SET
+  last_beat_at = (SELECT MAX(last_beat) FROM beat_runs WHERE device = devices.id),
+  last_credential = (
+    SELECT last_credential FROM beat_runs WHERE device = devices.id ORDER BY last_beat DESC LIMIT 1
+  );
+
+UPDATE stats SET last_seen = (SELECT MAX(last_beat_at) FROM devices);
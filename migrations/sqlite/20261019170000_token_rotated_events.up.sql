@@ -0,0 +1,21 @@
+-- Copyright (c) 2023 Isis <root@5ht2.me>
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- check constraints can't be altered, so the table is created again
+CREATE TABLE events_new (
+  id INTEGER PRIMARY KEY,
+  kind TEXT NOT NULL CHECK (kind IN ('absence', 'longest_absence', 'server_start', 'token_rotated')),
+  time_stamp TEXT NOT NULL,
+  -- when the absence started, for absences and records
+  started_at TEXT,
+  -- the device whose beat ended the absence, or whose token was rotated
+  device INTEGER REFERENCES devices (id) ON DELETE SET NULL
+);
+INSERT INTO events_new (id, kind, time_stamp, started_at, device)
+SELECT id, kind, time_stamp, started_at, device FROM events;
+DROP TABLE events;
+ALTER TABLE events_new RENAME TO events;
+CREATE INDEX events_time_stamp_idx ON events (time_stamp DESC);
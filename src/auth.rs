@@ -4,18 +4,27 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{config::Config, error::Error, AppState};
+use crate::{
+    config::Config,
+    devices::Credential,
+    error::{Error, Problem},
+    signature::{self, MAX_NONCE_LEN},
+    AppState,
+};
 use axum::{
     extract::{FromRef, FromRequestParts},
     http::{request::Parts, StatusCode},
 };
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
 use tracing::error;
 
-#[derive(Debug)]
+pub use heartbeat_api::{DEVICE_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+#[derive(Debug, Clone)]
 pub struct Device {
     pub id: i64,
     pub name: Option<String>,
+    pub credential: Credential,
 }
 
 #[axum::async_trait]
@@ -30,11 +39,10 @@ impl FromRequestParts<AppState> for Device {
                 StatusCode::UNAUTHORIZED,
                 &state.config.server_name,
             )
-            .with_reason("No token provided."));
+            .with_problem(Problem::NoToken));
         };
-        let pool = PgPool::from_ref(state);
-        let mut conn = pool.acquire().await.map_err(|e| {
-            error!("Failed to acquire connection from pool. {e:?}");
+        let lookup = state.storage.lookup_token(token).await.map_err(|e| {
+            error!("Failed to look up device token. {e:?}");
             Error::new(
                 req.uri.path(),
                 &req.method,
@@ -42,25 +50,139 @@ impl FromRequestParts<AppState> for Device {
                 &state.config.server_name,
             )
         })?;
-        sqlx::query_as!(
-            Device,
-            "SELECT id, name FROM heartbeat.devices WHERE token = $1;",
-            token
-        )
-        .fetch_one(&mut *conn)
-        .await
-        .map_err(|_| {
+        let (device, signing_secret) = lookup.ok_or_else(|| {
             Error::new(
                 req.uri.path(),
                 &req.method,
                 StatusCode::UNAUTHORIZED,
                 &state.config.server_name,
             )
-            .with_reason("Invalid token.")
-        })
+            .with_problem(Problem::InvalidToken)
+        })?;
+        if let Some(secret) = signing_secret {
+            verify_signature(req, state, device.id, Signer::Secret(&secret)).await?;
+        }
+        Ok(device)
     }
 }
 
+/// A device that authenticated with a signature made by its Ed25519 key,
+/// rather than a bearer token.
+#[derive(Debug)]
+pub struct KeyDevice(pub Device);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for KeyDevice {
+    type Rejection = Error;
+
+    async fn from_request_parts(req: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let unauthorized = |problem| {
+            Error::new(
+                req.uri.path(),
+                &req.method,
+                StatusCode::UNAUTHORIZED,
+                &state.config.server_name,
+            )
+            .with_problem(problem)
+        };
+        let Some(id) = req
+            .headers
+            .get(DEVICE_HEADER)
+            .and_then(|t| t.to_str().ok())
+            .and_then(|t| t.parse::<i64>().ok())
+        else {
+            return Err(unauthorized(Problem::NoDevice));
+        };
+        let (device, public_key) = state
+            .storage
+            .public_key(id)
+            .await
+            .map_err(|e| {
+                error!("Failed to look up device key. {e:?}");
+                Error::new(
+                    req.uri.path(),
+                    &req.method,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &state.config.server_name,
+                )
+            })?
+            .ok_or_else(|| unauthorized(Problem::InvalidDevice))?;
+        verify_signature(req, state, device.id, Signer::Key(&public_key)).await?;
+        Ok(Self(Device {
+            id: device.id,
+            name: device.name,
+            credential: Credential::Key,
+        }))
+    }
+}
+
+/// What a signed beat is verified with.
+enum Signer<'a> {
+    /// The device's HMAC signing secret.
+    Secret(&'a str),
+    /// The device's raw Ed25519 public key.
+    Key(&'a [u8]),
+}
+
+/// Checks the signature headers of a signed beat, and records its nonce so that
+/// the request cannot be replayed.
+async fn verify_signature(req: &Parts, state: &AppState, device: i64, signer: Signer<'_>) -> Result<(), Error> {
+    let unauthorized = |problem| {
+        Error::new(
+            req.uri.path(),
+            &req.method,
+            StatusCode::UNAUTHORIZED,
+            &state.config.server_name,
+        )
+        .with_problem(problem)
+    };
+    let header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+    let (Some(timestamp), Some(nonce), Some(signature)) = (
+        header(TIMESTAMP_HEADER).and_then(|t| t.parse::<i64>().ok()),
+        header(NONCE_HEADER),
+        header(SIGNATURE_HEADER),
+    ) else {
+        return Err(unauthorized(Problem::SignatureRequired));
+    };
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(unauthorized(Problem::InvalidNonce));
+    }
+    let now = Utc::now();
+    let skew = chrono::Duration::from_std(state.config.signed_beat_skew).unwrap_or(chrono::Duration::MAX);
+    let sent_at = DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .filter(|sent_at| (now - *sent_at).abs() <= skew)
+        .ok_or_else(|| unauthorized(Problem::StaleTimestamp))?;
+    let (method, path) = (req.method.as_str(), req.uri.path());
+    let valid = match signer {
+        Signer::Secret(secret) => signature::verify(secret, method, path, timestamp, nonce, signature),
+        Signer::Key(key) => signature::verify_key(key, method, path, timestamp, nonce, signature),
+    };
+    if !valid {
+        return Err(unauthorized(Problem::InvalidSignature));
+    }
+    // a nonce only needs to be remembered for as long as its timestamp would be
+    // accepted, after which it may be reused.
+    let expires_at = sent_at.checked_add_signed(skew).unwrap_or(DateTime::<Utc>::MAX_UTC);
+    let internal_error = |e| {
+        error!("Failed to record beat nonce: {e:?}");
+        Error::new(
+            req.uri.path(),
+            &req.method,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &state.config.server_name,
+        )
+    };
+    let fresh = state
+        .storage
+        .use_nonce(device, nonce, expires_at, now)
+        .await
+        .map_err(internal_error)?;
+    if !fresh {
+        return Err(unauthorized(Problem::NonceReused));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Master;
 
@@ -77,8 +199,7 @@ impl FromRequestParts<AppState> for Master {
                 &req.method,
                 StatusCode::NOT_FOUND,
                 &state.config.server_name,
-            )
-            .with_reason("Not found."));
+            ));
         }
         let token = req.headers.get("Authorization").map_or_else(
             || {
@@ -88,7 +209,7 @@ impl FromRequestParts<AppState> for Master {
                     StatusCode::UNAUTHORIZED,
                     &state.config.server_name,
                 )
-                .with_reason("No token provided."))
+                .with_problem(Problem::NoToken))
             },
             |t| Ok(t.to_str().unwrap_or_default()),
         )?;
@@ -101,7 +222,7 @@ impl FromRequestParts<AppState> for Master {
                 StatusCode::UNAUTHORIZED,
                 &state.config.server_name,
             )
-            .with_reason("Invalid token."))
+            .with_problem(Problem::InvalidToken))
         }
     }
 }
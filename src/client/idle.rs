@@ -0,0 +1,180 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// On Linux, the idle and lock state of the session is read from logind. If
+// logind is unavailable, keyboard and mouse interrupts in /proc/interrupts
+// are watched instead. On other platforms, the device is always assumed to
+// be in use.
+use std::time::Instant;
+#[cfg(target_os = "linux")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Active,
+    Idle,
+    Locked,
+}
+
+pub struct IdleDetector {
+    threshold: std::time::Duration,
+    last_input: Instant,
+    last_interrupts: Option<u64>,
+}
+
+impl IdleDetector {
+    pub fn new(threshold: std::time::Duration) -> Self {
+        Self {
+            threshold,
+            last_input: Instant::now(),
+            last_interrupts: None,
+        }
+    }
+
+    pub fn check(&mut self) -> Activity {
+        #[cfg(target_os = "linux")]
+        if let Some(session) = logind_session() {
+            return session.activity(self.threshold, SystemTime::now());
+        }
+        self.check_interrupts()
+    }
+
+    /// Falls back to counting input interrupts, considering the device idle
+    /// once the count has not changed for the idle threshold.
+    fn check_interrupts(&mut self) -> Activity {
+        #[cfg(target_os = "linux")]
+        {
+            let Some(count) = std::fs::read_to_string("/proc/interrupts")
+                .ok()
+                .and_then(|s| input_interrupts(&s))
+            else {
+                return Activity::Active;
+            };
+            if self.last_interrupts != Some(count) {
+                self.last_interrupts = Some(count);
+                self.last_input = Instant::now();
+            }
+        }
+        if self.last_input.elapsed() >= self.threshold {
+            Activity::Idle
+        } else {
+            Activity::Active
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq, Eq)]
+struct Session {
+    /// When the session became idle, if it is idle.
+    idle_since: Option<SystemTime>,
+    locked: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Session {
+    fn activity(&self, threshold: Duration, now: SystemTime) -> Activity {
+        if self.locked {
+            return Activity::Locked;
+        }
+        match self.idle_since {
+            Some(since) if now.duration_since(since).unwrap_or_default() >= threshold => Activity::Idle,
+            _ => Activity::Active,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn logind_session() -> Option<Session> {
+    let output = std::process::Command::new("loginctl")
+        .args([
+            "show-session",
+            "auto",
+            "-p",
+            "IdleHint",
+            "-p",
+            "IdleSinceHint",
+            "-p",
+            "LockedHint",
+        ])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    parse_session(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses the output of `loginctl show-session`.
+#[cfg(target_os = "linux")]
+fn parse_session(output: &str) -> Option<Session> {
+    let mut idle_hint = None;
+    let mut idle_since = None;
+    let mut locked = false;
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("IdleHint", value)) => idle_hint = Some(value == "yes"),
+            Some(("IdleSinceHint", value)) => idle_since = value.parse::<u64>().ok(),
+            Some(("LockedHint", value)) => locked = value == "yes",
+            _ => {}
+        }
+    }
+    let idle_since = idle_hint?.then(|| UNIX_EPOCH + Duration::from_micros(idle_since.unwrap_or_default()));
+    Some(Session { idle_since, locked })
+}
+
+/// Sums the interrupt counts of the PS/2 keyboard and mouse controller.
+#[cfg(target_os = "linux")]
+fn input_interrupts(interrupts: &str) -> Option<u64> {
+    interrupts
+        .lines()
+        .filter(|line| line.trim_end().ends_with("i8042"))
+        .map(|line| {
+            line.split_whitespace()
+                .skip(1)
+                .map_while(|count| count.parse::<u64>().ok())
+                .sum::<u64>()
+        })
+        .reduce(|a, b| a + b)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_logind_session() {
+        let session = parse_session("IdleHint=yes\nIdleSinceHint=1698915036000000\nLockedHint=no\n");
+        let since = UNIX_EPOCH + Duration::from_secs(1_698_915_036);
+        assert_eq!(
+            session,
+            Some(Session {
+                idle_since: Some(since),
+                locked: false
+            })
+        );
+        let session = session.expect("valid session");
+        let threshold = Duration::from_secs(300);
+        assert_eq!(
+            session.activity(threshold, since + Duration::from_secs(60)),
+            Activity::Active
+        );
+        assert_eq!(session.activity(threshold, since + threshold), Activity::Idle);
+        let session = parse_session("IdleHint=no\nIdleSinceHint=0\nLockedHint=yes\n").expect("valid session");
+        assert_eq!(session.activity(threshold, SystemTime::now()), Activity::Locked);
+        assert_eq!(parse_session(""), None);
+    }
+
+    #[test]
+    fn count_input_interrupts() {
+        let interrupts = "            CPU0       CPU1
+   1:         10          5   IO-APIC    1-edge      i8042
+   8:          0          0   IO-APIC    8-edge      rtc0
+  12:        100         20   IO-APIC   12-edge      i8042
+ NMI:          0          0   Non-maskable interrupts
+";
+        assert_eq!(input_interrupts(interrupts), Some(135));
+        assert_eq!(input_interrupts("   8:   0   0   IO-APIC    8-edge      rtc0\n"), None);
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A client that sends beats while this machine is in use.
+
+use crate::{config::ClientConfig, server::shutdown};
+use chrono::Utc;
+use heartbeat_api::devices::MAX_BATCH_SIZE;
+use heartbeat_client::{Client, Error};
+use heartbeat_sys::heartbeat_home;
+use idle::{Activity, IdleDetector};
+use queue::Queue;
+use reqwest::StatusCode;
+use std::{io, time::Duration};
+use tracing::{debug, error, info, warn};
+
+mod idle;
+mod queue;
+
+/// Delays between retries of a failed beat, after which it is queued.
+const RETRY_DELAYS: [Duration; 3] = [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(4)];
+
+/// Whether the server rejected a request, so retrying it won't help. Errors
+/// reaching the server, server errors and rate limiting are temporary.
+fn is_rejection(e: &Error) -> bool {
+    e.status()
+        .is_some_and(|status| status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// Sends a beat, retrying with backoff.
+async fn beat(client: &Client) -> Result<(), Error> {
+    let mut result = client.beat(None).await;
+    for delay in RETRY_DELAYS {
+        match &result {
+            Err(e) if !is_rejection(e) => debug!("failed to send beat: {e}"),
+            _ => break,
+        }
+        tokio::time::sleep(delay).await;
+        result = client.beat(None).await;
+    }
+    result.map(drop)
+}
+
+/// Sends queued beats in batches until the queue is empty or the server can't
+/// be reached. Batches the server rejects as invalid are split in half, so
+/// that only the beats the server rejects are discarded.
+async fn flush(client: &Client, queue: &mut Queue) -> io::Result<()> {
+    let mut size = MAX_BATCH_SIZE;
+    while !queue.is_empty() {
+        let beats = &queue.beats()[..queue.beats().len().min(size)];
+        let n = beats.len();
+        match client.beat_batch(beats).await {
+            Ok(batch) if !batch.too_old.is_empty() || !batch.future.is_empty() => warn!(
+                too_old = batch.too_old.len(),
+                future = batch.future.len(),
+                "Sent {n} queued beats, some of which the server skipped"
+            ),
+            Ok(_) => info!("Sent {n} queued beats"),
+            // the batch is too large, or has a beat the server won't accept
+            Err(Error::BadRequest(_) | Error::PayloadTooLarge) if n > 1 => {
+                size = n / 2;
+                continue;
+            }
+            Err(Error::BadRequest(reason)) => warn!("Server rejected a queued beat ({reason}), discarding it"),
+            Err(e) if is_rejection(&e) => {
+                warn!("Server rejected queued beats ({e}), keeping them");
+                return Ok(());
+            }
+            Err(e) => {
+                debug!("failed to send queued beats: {e}");
+                return Ok(());
+            }
+        }
+        queue.drain(n)?;
+    }
+    Ok(())
+}
+
+/// Send beats to the configured server while this machine is in use, until
+/// interrupted.
+///
+/// Beats that can't be delivered are queued in `$HEARTBEAT_HOME` and sent
+/// once the server can be reached again.
+///
+/// # Errors
+///
+/// This function returns an error if the offline queue can't be written to,
+/// or if the server rejects the token.
+pub async fn run(config: ClientConfig) -> io::Result<()> {
+    let queue_path = heartbeat_home()?.join("client-queue.json");
+    let mut queue = Queue::load(queue_path, config.max_queue_age);
+    let mut idle = IdleDetector::new(config.idle_threshold);
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(io::Error::other)?;
+    let client = Client::with_http_client(&config.url, http).with_token(config.token.as_str());
+    info!("Sending beats to {}", config.url);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown() => break,
+        }
+        match idle.check() {
+            Activity::Active => {}
+            activity => {
+                debug!("not sending beat, device is {activity:?}");
+                continue;
+            }
+        }
+        let now = Utc::now().timestamp();
+        match beat(&client).await {
+            Ok(()) => {
+                debug!("sent beat");
+                flush(&client, &mut queue).await?;
+            }
+            Err(Error::Unauthorized(reason)) => {
+                error!("The server rejected the token ({reason})");
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "invalid token"));
+            }
+            Err(e) if is_rejection(&e) => warn!("The server rejected the beat ({e})"),
+            Err(e) => {
+                warn!("Could not reach the server, queueing beat: {e}");
+                queue.push(now)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http, routing::post, Json, Router};
+    use parking_lot::Mutex;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    type Received = Arc<Mutex<Vec<usize>>>;
+
+    /// Rejects batches of more than two beats, or every batch with `status` if
+    /// it is set, and records the sizes of the batches it received.
+    async fn batch(
+        State((received, status)): State<(Received, Option<http::StatusCode>)>,
+        Json(batch): Json<Value>,
+    ) -> (http::StatusCode, Json<Value>) {
+        let n = batch["beats"].as_array().map_or(0, Vec::len);
+        received.lock().push(n);
+        match status {
+            Some(status) => (status, Json(json!({ "code": "unauthorized" }))),
+            None if n > 2 => (
+                http::StatusCode::BAD_REQUEST,
+                Json(json!({ "code": "batch_too_large" })),
+            ),
+            None => (
+                http::StatusCode::OK,
+                Json(json!({ "inserted": n, "duplicates": 0, "too_old": [], "future": [], "longest_absence": 0 })),
+            ),
+        }
+    }
+
+    /// Serves [`batch`], returning a client for it and the sizes of the batches
+    /// it received.
+    async fn server(status: Option<http::StatusCode>) -> (Client, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/api/v1/beats/batch", post(batch))
+            .with_state((received.clone(), status));
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("port to be free");
+        let url = format!("http://{}", listener.local_addr().expect("listener to be bound"));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (Client::new(&url).with_token("token"), received)
+    }
+
+    fn queue(name: &str) -> Queue {
+        let path = std::env::temp_dir().join(format!("heartbeat-{name}-{}.json", std::process::id()));
+        let mut queue = Queue::load(path, Duration::from_secs(86400));
+        let now = Utc::now().timestamp();
+        for beat in now - 5..now {
+            queue.push(beat).expect("beat to be queued");
+        }
+        queue
+    }
+
+    #[tokio::test]
+    async fn test_flush_splits_batches() {
+        let (client, received) = server(None).await;
+        let mut queue = queue("split");
+        flush(&client, &mut queue).await.expect("queue to be written");
+        assert!(queue.is_empty());
+        assert_eq!(*received.lock(), [5, 2, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_discards_invalid_beats() {
+        let (client, received) = server(Some(http::StatusCode::BAD_REQUEST)).await;
+        let mut queue = queue("invalid");
+        flush(&client, &mut queue).await.expect("queue to be written");
+        assert!(queue.is_empty());
+        assert_eq!(*received.lock(), [5, 2, 1, 1, 1, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_keeps_rejected_beats() {
+        for status in [http::StatusCode::UNAUTHORIZED, http::StatusCode::TOO_MANY_REQUESTS] {
+            let (client, _) = server(Some(status)).await;
+            let mut queue = queue(status.as_str());
+            flush(&client, &mut queue).await.expect("queue to be written");
+            assert_eq!(queue.beats().len(), 5, "{status}");
+            queue.drain(5).expect("queue to be written");
+        }
+    }
+}
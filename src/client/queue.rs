@@ -0,0 +1,75 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use std::{fs, io, path::PathBuf, time::Duration};
+use tracing::warn;
+
+/// Timestamps of beats that could not be sent, persisted to disk so that they
+/// survive restarts.
+pub struct Queue {
+    path: PathBuf,
+    beats: Vec<i64>,
+    /// Beats older than this are dropped, since the server won't accept them.
+    max_age: Duration,
+}
+
+impl Queue {
+    pub fn load(path: PathBuf, max_age: Duration) -> Self {
+        let beats = fs::read_to_string(&path).map_or_else(
+            |_| Vec::new(),
+            |s| {
+                serde_json::from_str(&s).unwrap_or_else(|e| {
+                    warn!("Discarding unreadable offline queue at {}: {e}", path.display());
+                    Vec::new()
+                })
+            },
+        );
+        let mut queue = Self { path, beats, max_age };
+        queue.prune();
+        queue
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.beats.is_empty()
+    }
+
+    pub fn beats(&self) -> &[i64] {
+        &self.beats
+    }
+
+    pub fn push(&mut self, timestamp: i64) -> io::Result<()> {
+        self.beats.push(timestamp);
+        self.prune();
+        self.save()
+    }
+
+    /// Removes the first `n` beats, once they have been sent.
+    pub fn drain(&mut self, n: usize) -> io::Result<()> {
+        self.beats.drain(..n.min(self.beats.len()));
+        self.save()
+    }
+
+    fn prune(&mut self) {
+        let oldest = Utc::now().timestamp() - i64::try_from(self.max_age.as_secs()).unwrap_or(i64::MAX);
+        self.beats.retain(|&ts| ts > oldest);
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if self.beats.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.beats)?)?;
+        fs::rename(tmp, &self.path)
+    }
+}
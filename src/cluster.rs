@@ -0,0 +1,283 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Keeps the stats of every instance sharing a database in step, and picks
+//! the one instance that runs background jobs.
+
+use crate::{
+    devices::{Credential, Device, Metadata, Probe},
+    server::shutdown,
+    stats::Stats,
+    storage::{Listener, Storage},
+    AppState,
+};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info};
+
+/// How often the leader checks that it still leads, and the others try to
+/// take over.
+const LEAD_TICK: Duration = Duration::from_secs(10);
+/// How long to wait before listening again after an error.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A change to the [`Stats`], made by this or another instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    /// A beat as it happened, after the last one from any device at
+    /// `previous`, if there was one.
+    Beat {
+        device: i64,
+        credential: Credential,
+        time: DateTime<Utc>,
+        previous: Option<DateTime<Utc>>,
+    },
+    /// A batch of backfilled beats, received at `time`.
+    Batch {
+        device: i64,
+        credential: Credential,
+        inserted: i64,
+        latest: Option<DateTime<Utc>>,
+        /// The longest absence after the batch, in seconds.
+        longest_absence: i64,
+        time: DateTime<Utc>,
+    },
+    /// A device was added.
+    Device(Device),
+    /// A device's probe was set or removed.
+    Probe { device: i64, probe: Option<Probe> },
+    /// A device sent metadata.
+    Metadata { device: i64, metadata: Metadata },
+    /// The site was visited this many times.
+    Visits { count: i64 },
+}
+
+/// A change as it is sent to the other instances.
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    /// The instance that made the change, which has already applied it.
+    origin: u64,
+    change: Change,
+}
+
+/// This instance's membership of the instances sharing a database.
+#[derive(Debug)]
+pub struct Cluster {
+    storage: Arc<dyn Storage>,
+    origin: u64,
+    leader: AtomicBool,
+}
+
+impl Cluster {
+    /// Joins the instances sharing the database behind `storage`, becoming
+    /// the leader if there is none yet.
+    pub async fn join(storage: Arc<dyn Storage>) -> Arc<Self> {
+        let cluster = Arc::new(Self {
+            storage,
+            origin: rand::thread_rng().next_u64(),
+            leader: AtomicBool::new(false),
+        });
+        lead(&cluster).await;
+        cluster
+    }
+
+    /// Returns whether this instance runs background jobs, such as probes and
+    /// compaction.
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Stops leading until the lead is next taken, to test followers.
+    #[cfg(all(test, feature = "mqtt"))]
+    pub(crate) fn step_down(&self) {
+        self.leader.store(false, Ordering::Relaxed);
+    }
+
+    /// Sends `change` to the other instances in the background.
+    pub fn send(&self, change: Change) {
+        let message = Message {
+            origin: self.origin,
+            change,
+        };
+        let payload = match serde_json::to_string(&message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize change: {e:?}");
+                return;
+            }
+        };
+        let storage = self.storage.clone();
+        tokio::spawn(async move {
+            if let Err(e) = storage.notify(&payload).await {
+                error!("Failed to send change to other instances: {e:?}");
+            }
+        });
+    }
+}
+
+/// Applies `change` to the stats and sends it to the other instances,
+/// returning whether the longest absence grew.
+pub fn publish(state: &AppState, change: Change) -> bool {
+    let grew = state.stats.lock().apply(&change);
+    state.cluster.send(change);
+    grew
+}
+
+/// Applies the changes made by other instances and keeps trying to lead until
+/// the server shuts down.
+pub async fn serve_cluster(state: AppState) {
+    let mut listener = state.storage.listen().await.unwrap_or_else(|e| {
+        error!("Failed to listen for changes from other instances: {e:?}");
+        None
+    });
+    let mut missed = false;
+    let mut interval = tokio::time::interval(LEAD_TICK);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                lead(&state.cluster).await;
+                if missed {
+                    missed = !resync(&state).await;
+                }
+            }
+            received = recv(&mut listener) => match received {
+                Ok(Some(payload)) => receive(&state, &payload),
+                Ok(None) => {
+                    debug!("lost connection to other instances, fetching stats again");
+                    missed = !resync(&state).await;
+                }
+                Err(e) => {
+                    error!("Failed to receive changes from other instances: {e:?}");
+                    missed = true;
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            },
+            () = shutdown() => {
+                debug!("shutdown signal received, no longer listening for changes");
+                break;
+            }
+        }
+    }
+}
+
+async fn recv(listener: &mut Option<Box<dyn Listener>>) -> sqlx::Result<Option<String>> {
+    match listener {
+        Some(listener) => listener.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn receive(state: &AppState, payload: &str) {
+    match serde_json::from_str::<Message>(payload) {
+        Ok(message) if message.origin == state.cluster.origin => {}
+        Ok(message) => {
+            state.stats.lock().apply(&message.change);
+        }
+        Err(e) => error!("Failed to parse change from another instance: {e:?}"),
+    }
+}
+
+/// Replaces the stats with those in the database, since changes may have been
+/// missed. Returns whether they could be fetched.
+async fn resync(state: &AppState) -> bool {
+    match Stats::try_fetch(&*state.storage).await {
+        Ok(fetched) => {
+            state.stats.lock().replace(fetched);
+            true
+        }
+        Err(e) => {
+            error!("Failed to fetch stats: {e:?}");
+            false
+        }
+    }
+}
+
+async fn lead(cluster: &Cluster) {
+    let leader = cluster.storage.lead().await.unwrap_or_else(|e| {
+        error!("Failed to take or keep the lead: {e:?}");
+        false
+    });
+    if cluster.leader.swap(leader, Ordering::Relaxed) != leader {
+        if leader {
+            info!("This instance now runs background jobs");
+        } else {
+            info!("This instance no longer runs background jobs");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_batch_then_beat() {
+        let start = Utc::now();
+        let mut stats = Stats {
+            last_seen: None,
+            devices: vec![],
+            longest_absence: chrono::TimeDelta::zero(),
+            num_visits: 0,
+            total_beats: 0,
+            changes: 0,
+        };
+        let device = Device {
+            id: 1,
+            name: Some("test".into()),
+            last_beat: None,
+            num_beats: 0,
+            last_credential: None,
+            metadata: None,
+            probe: None,
+        };
+        let changes = [
+            Change::Device(device.clone()),
+            // applying the same change twice doesn't add the device twice
+            Change::Device(device),
+            Change::Batch {
+                device: 1,
+                credential: Credential::Current,
+                inserted: 2,
+                latest: Some(start),
+                longest_absence: 60,
+                time: start,
+            },
+            Change::Visits { count: 3 },
+        ];
+        for change in &changes {
+            let message = serde_json::to_string(&Message {
+                origin: 0,
+                change: change.clone(),
+            })
+            .expect("change to be serialized");
+            let received = serde_json::from_str::<Message>(&message).expect("change to be deserialized");
+            assert!(!stats.apply(&received.change));
+        }
+        let beat = Change::Beat {
+            device: 1,
+            credential: Credential::Superseded,
+            time: start + chrono::TimeDelta::hours(1),
+            previous: Some(start),
+        };
+        assert!(stats.apply(&beat));
+        assert_eq!(stats.devices.len(), 1);
+        assert_eq!(stats.devices[0].num_beats, 3);
+        assert_eq!(stats.devices[0].last_credential, Some(Credential::Superseded));
+        assert_eq!((stats.total_beats, stats.num_visits), (3, 3));
+        assert_eq!(stats.longest_absence, chrono::TimeDelta::hours(1));
+    }
+}
@@ -4,6 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::{devices::MetadataField, storage::ConnectOptions};
 use clap::{Arg, Args, FromArgMatches, Parser, Subcommand};
 use erased_debug::Erased;
 use heartbeat_sys::heartbeat_home;
@@ -14,6 +15,7 @@ use std::{
     io::Error as IoError,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::{Path, PathBuf},
+    time::Duration,
 };
 use toml::de::Error as TomlDeError;
 use tracing::{debug, info};
@@ -39,6 +41,11 @@ pub enum Subcmd {
     Run(Box<WebCli>),
     /// Generate a new secret key.
     GenKey,
+    /// Compact old beats according to the retention policy, then exit.
+    Compact(Box<WebCli>),
+    /// Send beats from this machine while it is in use.
+    #[cfg(feature = "client")]
+    Client(ClientCli),
     /// Migrate the database.
     #[cfg(feature = "migrate")]
     Migrate(MigrateCli),
@@ -57,21 +64,100 @@ pub struct MigrateCli {
     /// The path to the configuration file.
     #[command(flatten)]
     pub config_file: __ConfigFile,
-    /// The `PostgreSQL` connection string. [default:
+    /// The `PostgreSQL` or `SQLite` connection string. [default:
     /// `postgres://heartbeat@db/heartbeat` if running in Docker,
     /// `postgres://postgres@localhost/postgres` otherwise]
-    #[clap(long, short, env = "HEARTBEAT_DATABASE_DSN")]
+    #[clap(long, short, env = "HEARTBEAT_DATABASE_DSN", global = true)]
     pub database_dsn: Option<String>,
+    /// The `PostgreSQL` schema to migrate. [default: heartbeat]
+    #[clap(long, env = "HEARTBEAT_DATABASE_SCHEMA", global = true)]
+    pub database_schema: Option<String>,
+    /// What to do. [default: up]
+    #[clap(subcommand)]
+    pub command: Option<MigrateCommand>,
+}
+
+/// Migration commands
+#[cfg(feature = "migrate")]
+#[derive(Debug, Clone, Copy, Default, Subcommand)]
+pub enum MigrateCommand {
+    /// List every migration and whether it has been applied.
+    Status,
+    /// Apply all pending migrations.
+    #[default]
+    Up,
+    /// Revert the latest migration.
+    Down {
+        /// Revert every migration after this version instead.
+        #[clap(long)]
+        to: Option<i64>,
+    },
+    /// Check that the database schema matches this build exactly.
+    Verify,
+}
+
+/// Send beats to a server.
+#[cfg(feature = "client")]
+#[derive(Debug, Parser)]
+pub struct ClientCli {
+    /// The path to the configuration file.
+    #[command(flatten)]
+    pub config_file: __ConfigFile,
+    /// The URL of the server to send beats to.
+    #[clap(long, short, env = "HEARTBEAT_CLIENT_URL")]
+    pub url: Option<String>,
+    /// The token of this device.
+    #[clap(long, short, env = "HEARTBEAT_CLIENT_TOKEN")]
+    pub token: Option<String>,
+    /// The number of seconds between beats. [default: 60]
+    #[clap(long, short, env = "HEARTBEAT_CLIENT_INTERVAL")]
+    pub interval: Option<u64>,
+    /// The number of seconds without input after which the device is
+    /// considered idle. [default: 300]
+    #[clap(long, env = "HEARTBEAT_CLIENT_IDLE_THRESHOLD")]
+    pub idle_threshold: Option<u64>,
+    /// The number of days after which queued beats are discarded. [default: 7]
+    #[clap(long, env = "HEARTBEAT_CLIENT_MAX_QUEUE_DAYS")]
+    pub max_queue_days: Option<u64>,
 }
 
 /// Run the web server.
 #[derive(Debug, Parser)]
 pub struct WebCli {
-    /// A `PostgreSQL` connection string. [default:
+    /// A `PostgreSQL` or `SQLite` connection string. [default:
     /// `postgres://heartbeat@db/heartbeat` if running in Docker,
     /// `postgres://postgres@localhost/postgres` otherwise]
     #[clap(long, short, env = "HEARTBEAT_DATABASE_DSN")]
     pub database_dsn: Option<String>,
+    /// Apply pending migrations when the server starts. Requires the
+    /// `migrate` feature. [default: false]
+    #[clap(long, env = "HEARTBEAT_DATABASE_AUTO_MIGRATE")]
+    pub database_auto_migrate: Option<bool>,
+    /// The `PostgreSQL` schema everything is stored in. [default: heartbeat]
+    #[clap(long, env = "HEARTBEAT_DATABASE_SCHEMA")]
+    pub database_schema: Option<String>,
+    /// The most database connections that are opened at once. [default: 10]
+    #[clap(long, env = "HEARTBEAT_DATABASE_MAX_CONNECTIONS")]
+    pub database_max_connections: Option<u32>,
+    /// The fewest database connections that are kept open. [default: 0]
+    #[clap(long, env = "HEARTBEAT_DATABASE_MIN_CONNECTIONS")]
+    pub database_min_connections: Option<u32>,
+    /// The number of seconds to wait for a database connection before giving
+    /// up. [default: 30]
+    #[clap(long, env = "HEARTBEAT_DATABASE_ACQUIRE_TIMEOUT")]
+    pub database_acquire_timeout: Option<u64>,
+    /// The number of seconds a database connection may sit idle before it is
+    /// closed. 0 keeps them open. [default: 600]
+    #[clap(long, env = "HEARTBEAT_DATABASE_IDLE_TIMEOUT")]
+    pub database_idle_timeout: Option<u64>,
+    /// The number of seconds a `PostgreSQL` statement may run before it is
+    /// cancelled. 0 lets them run for as long as they take. [default: 0]
+    #[clap(long, env = "HEARTBEAT_DATABASE_STATEMENT_TIMEOUT")]
+    pub database_statement_timeout: Option<u64>,
+    /// A comma-separated list of `PostgreSQL` read replica connection strings
+    /// that badges, calendars and feeds are read from. [default: none]
+    #[clap(long, env = "HEARTBEAT_DATABASE_REPLICAS", value_delimiter = ',')]
+    pub database_replicas: Option<Vec<String>>,
     #[cfg(feature = "webhook")]
     #[clap(long, env = "HEARTBEAT_WEBHOOK_URL")]
     /// The URL of the Discord webhook. [default: none]
@@ -80,6 +166,14 @@ pub struct WebCli {
     #[clap(long, env = "HEARTBEAT_WEBHOOK_LEVEL")]
     /// The minimum level of events that triggers a webhook. [default: none]
     pub webhook_level: Option<WebhookLevel>,
+    /// The URL of the MQTT broker to bridge beats from. [default: none]
+    #[cfg(feature = "mqtt")]
+    #[clap(long, env = "HEARTBEAT_MQTT_URL")]
+    pub mqtt_url: Option<String>,
+    /// The MQTT topic to publish server events to. [default: none]
+    #[cfg(feature = "mqtt")]
+    #[clap(long, env = "HEARTBEAT_MQTT_STATUS_TOPIC")]
+    pub mqtt_status_topic: Option<String>,
     /// A random URL-safe string used as a master Authorization header
     /// for adding new devices.
     #[clap(long, short = 's', env = "HEARTBEAT_SECRET_KEY")]
@@ -97,6 +191,58 @@ pub struct WebCli {
     /// The bind address for the server. [default: `127.0.0.1:6060`]
     #[clap(long, short, env = "HEARTBEAT_BIND")]
     pub bind: Option<SocketAddr>,
+    /// The number of seconds a device's previous token remains valid after
+    /// it is rotated. [default: 86400]
+    #[clap(long, env = "HEARTBEAT_TOKEN_GRACE_PERIOD")]
+    pub token_grace_period: Option<u64>,
+    /// The maximum number of seconds the timestamp of a signed beat may
+    /// differ from the server's clock. [default: 300]
+    #[clap(long, env = "HEARTBEAT_SIGNED_BEAT_SKEW")]
+    pub signed_beat_skew: Option<u64>,
+    /// The number of seconds between beats recorded for a device connected
+    /// to the presence WebSocket. [default: 60]
+    #[clap(long, env = "HEARTBEAT_PRESENCE_INTERVAL")]
+    pub presence_interval: Option<u64>,
+    /// The number of seconds a device still counts as present after its
+    /// presence WebSocket drops, in case it reconnects. [default: 30]
+    #[clap(long, env = "HEARTBEAT_PRESENCE_HYSTERESIS")]
+    pub presence_hysteresis: Option<u64>,
+    /// The maximum age, in days, of a beat submitted in a batch.
+    /// [default: 7]
+    #[clap(long, env = "HEARTBEAT_MAX_BACKFILL_DAYS")]
+    pub max_backfill_days: Option<u64>,
+    /// The number of days raw beats are kept before they are compacted into
+    /// hourly rollups. 0 keeps them forever. [default: 0]
+    #[clap(long, env = "HEARTBEAT_RETENTION_RAW_DAYS")]
+    pub retention_raw_days: Option<u64>,
+    /// The number of days hourly rollups are kept before they are compacted
+    /// into daily rollups. 0 keeps them forever. [default: 0]
+    #[clap(long, env = "HEARTBEAT_RETENTION_HOURLY_DAYS")]
+    pub retention_hourly_days: Option<u64>,
+    /// The number of milliseconds visits and beats are buffered for before
+    /// they are written to the database. 0 writes them right away.
+    /// [default: 5]
+    #[clap(long, env = "HEARTBEAT_WRITE_FLUSH_INTERVAL_MS")]
+    pub write_flush_interval_ms: Option<u64>,
+    /// The maximum number of beats written to the database at once. More are
+    /// written as soon as this many are buffered. [default: 100]
+    #[clap(long, env = "HEARTBEAT_WRITE_MAX_BATCH_SIZE")]
+    pub write_max_batch_size: Option<usize>,
+    /// The bind address for the UDP beat listener. [default: disabled]
+    #[clap(long, env = "HEARTBEAT_UDP_BIND")]
+    pub udp_bind: Option<SocketAddr>,
+    /// The maximum number of UDP datagrams accepted from a single address
+    /// per minute. [default: 60]
+    #[clap(long, env = "HEARTBEAT_UDP_RATE_LIMIT")]
+    pub udp_rate_limit: Option<u32>,
+    /// A comma-separated list of device metadata fields that are shown
+    /// publicly. [default: none]
+    #[clap(long, env = "HEARTBEAT_PUBLIC_METADATA", value_delimiter = ',')]
+    pub public_metadata: Option<Vec<MetadataField>>,
+    /// Run against an in-memory database with made-up devices and beats
+    /// instead of the configured one. Nothing is persisted.
+    #[clap(long)]
+    pub demo: bool,
     /// The path to the configuration file.
     #[command(flatten)]
     pub config_file: __ConfigFile,
@@ -174,6 +320,15 @@ pub struct Config {
     /// Webhook configuration.
     #[cfg(feature = "webhook")]
     pub webhook: Webhook,
+    /// UDP listener configuration.
+    pub udp: Udp,
+    /// How long beats are kept before they are compacted.
+    pub retention: Retention,
+    /// How visits and beats are buffered before they are written.
+    pub write_buffer: WriteBuffer,
+    /// MQTT bridge configuration.
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Mqtt,
     /// A random URL-safe string used as a master Authorization header
     /// for adding new devices.
     pub secret_key: Erased<String>,
@@ -186,12 +341,34 @@ pub struct Config {
     pub live_url: String,
     /// The bind address for the server.
     pub bind: SocketAddr,
+    /// How long a device's previous token remains valid after it is rotated.
+    pub token_grace_period: Duration,
+    /// The maximum difference between the timestamp of a signed beat and the
+    /// server's clock.
+    pub signed_beat_skew: Duration,
+    /// How often a beat is recorded for a device connected to the presence
+    /// WebSocket.
+    pub presence_interval: Duration,
+    /// How long a device still counts as present after its presence WebSocket
+    /// drops, in case it reconnects.
+    pub presence_hysteresis: Duration,
+    /// The maximum age of a beat submitted in a batch.
+    pub max_backfill_age: Duration,
+    /// The device metadata fields that are shown publicly.
+    pub public_metadata: Vec<MetadataField>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct Database {
-    /// A `PostgreSQL` connection string.
+    /// A `PostgreSQL` or `SQLite` connection string.
     pub dsn: String,
+    /// Whether to apply pending migrations when the server starts.
+    pub auto_migrate: bool,
+    /// The schema, pool size and timeouts of connections.
+    pub options: ConnectOptions,
+    /// `PostgreSQL` read replica connection strings, which badges, calendars
+    /// and feeds are read from.
+    pub replicas: Vec<String>,
 }
 
 #[cfg(feature = "webhook")]
@@ -203,6 +380,56 @@ pub struct Webhook {
     pub level: WebhookLevel,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Udp {
+    /// The bind address for the UDP beat listener, if enabled.
+    pub bind: Option<SocketAddr>,
+    /// The maximum number of datagrams accepted from a single address per
+    /// minute.
+    pub rate_limit: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// How long raw beats are kept before they are rolled up into hours, if
+    /// they are at all.
+    pub raw: Option<Duration>,
+    /// How long hourly rollups are kept before they are rolled up into days,
+    /// if they are at all.
+    pub hourly: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteBuffer {
+    /// How often buffered visits and beats are written, or zero to write them
+    /// right away.
+    pub flush_interval: Duration,
+    /// The maximum number of beats written at once.
+    pub max_batch_size: usize,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Debug, Deserialize)]
+pub struct Mqtt {
+    /// The URL of the MQTT broker, including a `client_id` query parameter.
+    pub url: String,
+    /// The topic to publish server events to.
+    pub status_topic: String,
+    /// The topics that are mapped to devices.
+    pub devices: Vec<MqttDevice>,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttDevice {
+    /// The topic filter to subscribe to.
+    pub topic: String,
+    /// The token of the device that messages on this topic are beats from.
+    pub token: Erased<String>,
+    /// If set, only messages with exactly this payload count as beats.
+    pub payload: Option<String>,
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "snake_case")]
 pub enum WebhookLevel {
@@ -318,12 +545,53 @@ impl<'a> Merge<'a> {
         }
     });
 
+    config_field!(database.auto_migrate, database_auto_migrate, bool, false);
+
+    config_field!(database.schema, database_schema, String, String::from("heartbeat"));
+
+    config_field!(database.max_connections, database_max_connections, u32, 10);
+
+    config_field!(database.min_connections, database_min_connections, u32, 0);
+
+    config_field!(database.acquire_timeout, database_acquire_timeout, u64, 30);
+
+    config_field!(database.idle_timeout, database_idle_timeout, u64, 600);
+
+    config_field!(database.statement_timeout, database_statement_timeout, u64, 0);
+
+    config_field!(database.replicas, database_replicas, Vec<String>, Vec::<String>::new());
+
     #[cfg(feature = "webhook")]
     config_field!(webhook.url, webhook_url, String, String::new());
 
     #[cfg(feature = "webhook")]
     config_field!(webhook.level, webhook_level, WebhookLevel, WebhookLevel::None);
 
+    #[cfg(feature = "mqtt")]
+    config_field!(mqtt.url, mqtt_url, String, String::new());
+
+    #[cfg(feature = "mqtt")]
+    config_field!(mqtt.status_topic, mqtt_status_topic, String, String::new());
+
+    #[cfg(feature = "mqtt")]
+    fn mqtt_devices(&self) -> Vec<MqttDevice> {
+        self.toml_value_nested("mqtt", "devices").unwrap_or_default()
+    }
+
+    fn udp_bind(&self) -> Option<SocketAddr> {
+        self.cli.udp_bind.or_else(|| self.toml_value_nested("udp", "bind"))
+    }
+
+    config_field!(udp.rate_limit, udp_rate_limit, u32, 60);
+
+    config_field!(retention.raw_days, retention_raw_days, u64, 0);
+
+    config_field!(retention.hourly_days, retention_hourly_days, u64, 0);
+
+    config_field!(write_buffer.flush_interval_ms, write_flush_interval_ms, u64, 5);
+
+    config_field!(write_buffer.max_batch_size, write_max_batch_size, usize, 100);
+
     config_field!(secret_key, String, String::new());
 
     config_field!(repo, String, String::from("https://github.com/lmaotrigine/heartbeat"));
@@ -338,6 +606,18 @@ impl<'a> Merge<'a> {
         SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6060))
     );
 
+    config_field!(token_grace_period, u64, 86400);
+
+    config_field!(signed_beat_skew, u64, 300);
+
+    config_field!(presence_interval, u64, 60);
+
+    config_field!(presence_hysteresis, u64, 30);
+
+    config_field!(max_backfill_days, u64, 7);
+
+    config_field!(public_metadata, Vec<MetadataField>, Vec::<MetadataField>::new());
+
     fn profile_value<T: Debug + Deserialize<'a>>(&self, field: &'a str) -> Option<T> {
         let value = self
             .toml
@@ -390,24 +670,92 @@ impl<'a> Merge<'a> {
     }
 
     pub fn try_into(self) -> Result<Config, Error> {
+        let max_connections = self.database_max_connections()?.max(1);
         Ok(Config {
             database: Database {
                 dsn: self.database_dsn()?,
+                auto_migrate: self.database_auto_migrate()?,
+                options: ConnectOptions {
+                    schema: self.database_schema()?,
+                    max_connections,
+                    min_connections: self.database_min_connections()?.min(max_connections),
+                    acquire_timeout: Duration::from_secs(self.database_acquire_timeout()?),
+                    idle_timeout: seconds(self.database_idle_timeout()?),
+                    statement_timeout: seconds(self.database_statement_timeout()?),
+                },
+                replicas: self.database_replicas()?,
             },
             #[cfg(feature = "webhook")]
             webhook: Webhook {
                 url: self.webhook_url()?,
                 level: self.webhook_level()?,
             },
+            #[cfg(feature = "mqtt")]
+            mqtt: Mqtt {
+                url: self.mqtt_url()?,
+                status_topic: self.mqtt_status_topic()?,
+                devices: self.mqtt_devices(),
+            },
+            udp: Udp {
+                bind: self.udp_bind(),
+                rate_limit: self.udp_rate_limit()?,
+            },
+            retention: Retention {
+                raw: days(self.retention_raw_days()?),
+                hourly: days(self.retention_hourly_days()?),
+            },
+            write_buffer: WriteBuffer {
+                flush_interval: Duration::from_millis(self.write_flush_interval_ms()?),
+                max_batch_size: self.write_max_batch_size()?.max(1),
+            },
             secret_key: self.secret_key()?.into(),
             repo: self.repo()?,
             server_name: self.server_name()?,
             live_url: self.live_url()?,
             bind: self.bind()?,
+            token_grace_period: Duration::from_secs(self.token_grace_period()?),
+            signed_beat_skew: Duration::from_secs(self.signed_beat_skew()?),
+            presence_interval: Duration::from_secs(self.presence_interval()?.max(1)),
+            presence_hysteresis: Duration::from_secs(self.presence_hysteresis()?),
+            max_backfill_age: Duration::from_secs(self.max_backfill_days()?.saturating_mul(86400)),
+            public_metadata: self.public_metadata()?,
         })
     }
 }
 
+/// Converts a number of days to a [`Duration`], or `None` if it is zero.
+fn days(days: u64) -> Option<Duration> {
+    seconds(days.saturating_mul(86400))
+}
+
+/// Converts a number of seconds to a [`Duration`], or `None` if it is zero.
+fn seconds(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Reads the TOML configuration file, or an empty table if no path was
+/// specified and the default file does not exist.
+fn read_toml(config_file: &__ConfigFile) -> Result<toml::Value, Error> {
+    let mut fail_on_not_exists = true;
+    let config_path = config_file.as_ref().map_or_else(
+        || {
+            fail_on_not_exists = false;
+            __ConfigFile::default()
+        },
+        Clone::clone,
+    );
+    if config_path.is_file() {
+        info!("Reading configuration from {}", config_path.display());
+        let config_str = read_to_string(config_path).map_err(Into::<Error>::into)?;
+        Ok(toml::from_str(&config_str)?)
+    } else if fail_on_not_exists {
+        Err(Error::InvalidConfigPath(config_path))
+    } else {
+        // just an empty table
+        Ok(toml::Value::Table(toml::map::Map::new()))
+    }
+}
+
 impl Config {
     /// Tries to parse a [`Config`] from the command line arguments, environment
     /// variables, and a TOML configuration file.
@@ -423,24 +771,7 @@ impl Config {
     /// (if it exists), is not valid TOML, or the required fields are not
     /// provided by any of the sources.
     pub fn try_new(cli: WebCli) -> Result<Self, Error> {
-        let mut fail_on_not_exists = true;
-        let config_path = cli.config_file.as_ref().map_or_else(
-            || {
-                fail_on_not_exists = false;
-                __ConfigFile::default()
-            },
-            Clone::clone,
-        );
-        let toml_config = if config_path.is_file() {
-            info!("Reading configuration from {}", config_path.display());
-            let config_str = read_to_string(config_path).map_err(Into::<Error>::into)?;
-            toml::from_str(&config_str)?
-        } else if fail_on_not_exists {
-            return Err(Error::InvalidConfigPath(config_path));
-        } else {
-            // just an empty table
-            toml::Value::Table(toml::map::Map::new())
-        };
+        let toml_config = read_toml(&cli.config_file)?;
         let config = Merge {
             cli,
             toml: &toml_config,
@@ -448,3 +779,64 @@ impl Config {
         config.try_into()
     }
 }
+
+/// The configuration for the `client` subcommand.
+#[cfg(feature = "client")]
+#[derive(Debug)]
+pub struct ClientConfig {
+    /// The URL of the server to send beats to.
+    pub url: String,
+    /// The token of this device.
+    pub token: Erased<String>,
+    /// How often to send beats.
+    pub interval: Duration,
+    /// How long without input before the device is considered idle.
+    pub idle_threshold: Duration,
+    /// How long beats are queued for before they are discarded.
+    pub max_queue_age: Duration,
+}
+
+#[cfg(feature = "client")]
+impl ClientConfig {
+    /// Tries to parse a [`ClientConfig`] from the command line arguments,
+    /// environment variables, and the `[client]` table of the TOML
+    /// configuration file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the configuration file could not
+    /// be read, or the URL or token are not provided by any of the sources.
+    pub fn try_new(cli: ClientCli) -> Result<Self, Error> {
+        #[derive(Default, Deserialize)]
+        #[serde(default)]
+        struct ClientToml {
+            url: Option<String>,
+            token: Option<String>,
+            interval: Option<u64>,
+            idle_threshold: Option<u64>,
+            max_queue_days: Option<u64>,
+        }
+
+        let toml_config = read_toml(&cli.config_file)?;
+        let toml = match toml_config.get("client") {
+            Some(table) => ClientToml::deserialize(table.clone())?,
+            None => ClientToml::default(),
+        };
+        Ok(Self {
+            url: cli.url.or(toml.url).ok_or(Error::MissingField("client.url"))?,
+            token: cli
+                .token
+                .or(toml.token)
+                .ok_or(Error::MissingField("client.token"))?
+                .into(),
+            interval: Duration::from_secs(cli.interval.or(toml.interval).unwrap_or(60).max(1)),
+            idle_threshold: Duration::from_secs(cli.idle_threshold.or(toml.idle_threshold).unwrap_or(300)),
+            max_queue_age: Duration::from_secs(
+                cli.max_queue_days
+                    .or(toml.max_queue_days)
+                    .unwrap_or(7)
+                    .saturating_mul(86400),
+            ),
+        })
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fake devices and beats, for screenshots and working on the UI.
+
+use crate::{
+    devices::{Credential, Metadata},
+    events::{self, ABSENCE_THRESHOLD},
+    storage::{NewDevice, Storage},
+    util::{generate_token, SnowflakeGenerator},
+};
+use chrono::{DateTime, Datelike, Days, NaiveTime, TimeDelta, Utc, Weekday};
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::info;
+
+/// How many days of beats are made up.
+pub const DAYS: i64 = 30;
+
+/// How a made-up device is used.
+struct Profile {
+    name: &'static str,
+    credential: Credential,
+    /// The hours of the day the device is in use, or `None` if it is always
+    /// on.
+    hours: Option<(i64, i64)>,
+    weekends: bool,
+    /// The time between beats.
+    interval: TimeDelta,
+    metadata: fn() -> Metadata,
+}
+
+const PROFILES: [Profile; 3] = [
+    Profile {
+        name: "Laptop",
+        credential: Credential::Current,
+        hours: Some((9, 18)),
+        weekends: false,
+        interval: TimeDelta::minutes(5),
+        metadata: || Metadata {
+            battery: Some(82),
+            hostname: Some("laptop".into()),
+            os: Some("Arch Linux".into()),
+            uptime: Some(31_337),
+            ip: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 23))),
+            status: Some("Writing some Rust".into()),
+        },
+    },
+    Profile {
+        name: "Phone",
+        credential: Credential::Key,
+        hours: Some((7, 23)),
+        weekends: true,
+        interval: TimeDelta::minutes(10),
+        metadata: || Metadata {
+            battery: Some(54),
+            os: Some("Android 15".into()),
+            ..Metadata::default()
+        },
+    },
+    Profile {
+        name: "Home server",
+        credential: Credential::Probe,
+        hours: None,
+        weekends: true,
+        interval: TimeDelta::minutes(5),
+        metadata: Metadata::default,
+    },
+];
+
+/// When the always-on devices were down, in days before today and hours.
+const OUTAGE: (u64, i64, i64) = (10, 2, 6);
+
+/// Returns the beats of `profile` from `start` until `now`. `seed` shifts the
+/// times the device is picked up each day.
+fn beats(profile: &Profile, seed: u64, start: DateTime<Utc>, now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+    let today = now.date_naive();
+    let mut time_stamps = vec![];
+    for days_ago in (0..=DAYS.unsigned_abs()).rev() {
+        let Some(day) = today.checked_sub_days(Days::new(days_ago)) else {
+            continue;
+        };
+        let midnight = day.and_time(NaiveTime::MIN).and_utc();
+        let sessions = match profile.hours {
+            Some(_) if !profile.weekends && matches!(day.weekday(), Weekday::Sat | Weekday::Sun) => vec![],
+            Some((from, to)) => {
+                let jitter = TimeDelta::minutes(i64::try_from((days_ago * 37 + seed * 11) % 60).unwrap_or_default());
+                vec![(
+                    midnight + TimeDelta::hours(from) + jitter,
+                    midnight + TimeDelta::hours(to) + jitter,
+                )]
+            }
+            None if days_ago == OUTAGE.0 => vec![
+                (midnight, midnight + TimeDelta::hours(OUTAGE.1)),
+                (midnight + TimeDelta::hours(OUTAGE.2), midnight + TimeDelta::days(1)),
+            ],
+            None => vec![(midnight, midnight + TimeDelta::days(1))],
+        };
+        for (from, to) in sessions {
+            let mut time = from.max(start);
+            while time < to.min(now) {
+                time_stamps.push(time);
+                time += profile.interval;
+            }
+        }
+    }
+    time_stamps
+}
+
+/// Adds a few made-up devices to `storage`, with [`DAYS`] days of beats until
+/// `now` and the absences between them.
+///
+/// # Errors
+///
+/// Returns an error if anything couldn't be stored.
+pub async fn seed(storage: &dyn Storage, now: DateTime<Utc>) -> sqlx::Result<()> {
+    let start = now - TimeDelta::days(DAYS);
+    let mut generator = SnowflakeGenerator::default();
+    let mut all_beats = vec![];
+    for (seed, profile) in (0..).zip(&PROFILES) {
+        let snowflake = generator.generate();
+        let id = i64::try_from(snowflake.id()).unwrap_or(i64::MAX);
+        let token = generate_token(snowflake);
+        storage
+            .add_device(NewDevice {
+                id,
+                name: profile.name,
+                token: &token,
+                signing_secret: None,
+                public_key: None,
+                probe: None,
+            })
+            .await?;
+        let time_stamps = beats(profile, seed, start, now);
+        storage.insert_beats(id, profile.credential, &time_stamps).await?;
+        let metadata = (profile.metadata)();
+        if !metadata.is_empty() {
+            storage.store_metadata(id, now, &metadata).await?;
+        }
+        info!(id, "Added demo device {} with token {token}", profile.name);
+        all_beats.extend(time_stamps.into_iter().map(|time| (time, id)));
+    }
+    all_beats.sort_unstable();
+    let mut longest = TimeDelta::zero();
+    for pair in all_beats.windows(2) {
+        let ((from, _), (to, device)) = (pair[0], pair[1]);
+        if to - from >= ABSENCE_THRESHOLD {
+            let is_longest = to - from > longest;
+            longest = longest.max(to - from);
+            events::record_absence(storage, device, from, to, is_longest).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beats() {
+        let now = "2024-03-06T12:02:00Z"
+            .parse::<DateTime<Utc>>()
+            .expect("valid timestamp");
+        let start = now - TimeDelta::days(DAYS);
+        for (seed, profile) in (0..).zip(&PROFILES) {
+            let time_stamps = beats(profile, seed, start, now);
+            assert!(!time_stamps.is_empty());
+            assert!(time_stamps.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(time_stamps.iter().all(|time| (start..now).contains(time)));
+        }
+        // 2024-03-02 and 2024-03-03 were a weekend
+        let laptop = beats(&PROFILES[0], 0, start, now);
+        assert!(!laptop.iter().any(|time| time.date_naive().weekday() == Weekday::Sat));
+    }
+}
@@ -4,19 +4,6 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::util::serde::ts;
-use serde::{Deserialize, Serialize};
+//! Devices and the types they are exchanged as over the API.
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Device {
-    pub id: i64,
-    pub name: Option<String>,
-    #[serde(with = "ts")]
-    pub last_beat: Option<chrono::DateTime<chrono::Utc>>,
-    pub num_beats: i64,
-}
-
-#[derive(Deserialize)]
-pub struct PostDevice {
-    pub name: String,
-}
+pub use heartbeat_api::devices::*;
@@ -8,19 +8,25 @@ use crate::{templates::error, AppState};
 use axum::{
     body::Body,
     extract::State,
-    http::{Method, Request, StatusCode},
+    http::{header::CONTENT_TYPE, HeaderValue, Method, Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
-    RequestExt,
+    Json, RequestExt,
 };
 use axum_realip::RealIp;
+pub use heartbeat_api::problem::{Problem, ProblemDetails, PROBLEM_CONTENT_TYPE, PROBLEM_TYPE_BASE};
+use std::borrow::Cow;
 use tracing::{error, warn};
 
+/// The most of an unhandled error's body that is kept as its detail.
+const MAX_DETAIL_LEN: usize = 1024;
+
 #[derive(Debug)]
 pub struct Error {
     path: String,
     method: Method,
-    message: &'static str,
+    problem: Option<Problem>,
+    detail: Cow<'static, str>,
     status: StatusCode,
     server_name: String,
 }
@@ -32,27 +38,69 @@ impl Error {
             method: method.clone(),
             status,
             server_name: server_name.into(),
-            message: status.canonical_reason().unwrap_or_default(),
+            problem: None,
+            detail: Cow::Borrowed(status.canonical_reason().unwrap_or_default()),
         }
     }
 
-    #[allow(clippy::missing_const_for_fn)] // false positive
-    pub fn with_reason(self, message: &'static str) -> Self {
-        Self { message, ..self }
+    pub fn with_problem(self, problem: Problem) -> Self {
+        Self {
+            problem: Some(problem),
+            detail: Cow::Borrowed(problem.detail()),
+            ..self
+        }
+    }
+
+    pub fn with_detail(self, detail: String) -> Self {
+        Self {
+            detail: Cow::Owned(detail),
+            ..self
+        }
+    }
+
+    fn problem_details(&self) -> ProblemDetails {
+        let title = self.status.canonical_reason().unwrap_or_default();
+        let (type_, code) = self.problem.map_or_else(
+            || ("about:blank".to_owned(), title.to_lowercase().replace([' ', '-'], "_")),
+            |problem| {
+                (
+                    format!("{PROBLEM_TYPE_BASE}{}", problem.code()),
+                    problem.code().to_owned(),
+                )
+            },
+        );
+        ProblemDetails {
+            type_,
+            title: title.to_owned(),
+            status: self.status.as_u16(),
+            detail: self.detail.clone().into_owned(),
+            instance: self.path.clone(),
+            code,
+        }
     }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         if self.path.starts_with("/api") {
-            return (self.status, self.message).into_response();
+            let headers = [(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE))];
+            return (self.status, headers, Json(self.problem_details())).into_response();
         }
-        let markup = error(self.message, self.method.as_str(), &self.path, &self.server_name);
+        let markup = error(&self.detail, self.method.as_str(), &self.path, &self.server_name);
         (self.status, markup).into_response()
     }
 }
 
+/// Whether a response is already a problem details object.
+fn is_problem(resp: &Response) -> bool {
+    resp.headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(PROBLEM_CONTENT_TYPE.as_bytes()))
+}
+
 /// An Axum middleware that serves error pages for unhandled client errors.
+///
+/// Every other error from the API is turned into a problem details object.
 /// This also logs the client IP and the attempted request for debugging
 /// purposes.
 ///
@@ -86,12 +134,66 @@ pub async fn handle_errors(
         .map_or("", |auth| auth.to_str().unwrap_or_default());
     let resp = next.run(req).await;
     let status = resp.status();
-    match status {
-        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
-            let code = status.as_u16();
-            warn!("returned {code} to {ip} - tried to {method} {path} with Authorization {auth}");
-            Err(Error::new(&path, &method, status, server_name))
+    let unhandled = matches!(
+        status,
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+    );
+    if unhandled {
+        let code = status.as_u16();
+        warn!("returned {code} to {ip} - tried to {method} {path} with Authorization {auth}");
+    }
+    if path.starts_with("/api") {
+        if !(status.is_client_error() || status.is_server_error()) || is_problem(&resp) {
+            return Ok(resp);
         }
-        _ => Ok(resp),
+        // rejections from extractors explain themselves in plain text
+        let is_text = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|v| v.as_bytes().starts_with(b"text/plain"));
+        let body = axum::body::to_bytes(resp.into_body(), MAX_DETAIL_LEN)
+            .await
+            .unwrap_or_default();
+        let err = Error::new(&path, &method, status, server_name);
+        return Err(match String::from_utf8(body.to_vec()) {
+            Ok(detail) if is_text && !detail.is_empty() => err.with_detail(detail),
+            _ => err,
+        });
+    }
+    if unhandled {
+        return Err(Error::new(&path, &method, status, server_name));
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem_details() {
+        let err = Error::new("/api/v1/beat", &Method::POST, StatusCode::UNAUTHORIZED, "test")
+            .with_problem(Problem::InvalidToken);
+        let problem = err.problem_details();
+        assert_eq!(problem.type_, format!("{PROBLEM_TYPE_BASE}invalid_token"));
+        assert_eq!(problem.title, "Unauthorized");
+        assert_eq!(problem.status, 401);
+        assert_eq!(problem.detail, "Invalid token.");
+        assert_eq!(problem.instance, "/api/v1/beat");
+        assert_eq!(problem.code, "invalid_token");
+
+        let err = Error::new("/api/v1/nope", &Method::GET, StatusCode::METHOD_NOT_ALLOWED, "test");
+        let problem = err.problem_details();
+        assert_eq!(problem.type_, "about:blank");
+        assert_eq!(problem.detail, "Method Not Allowed");
+        assert_eq!(problem.code, "method_not_allowed");
+    }
+
+    #[test]
+    fn test_problem_response() {
+        let resp = Error::new("/api/v1/stats", &Method::GET, StatusCode::NOT_FOUND, "test").into_response();
+        assert!(is_problem(&resp));
+        let resp = Error::new("/stats", &Method::GET, StatusCode::NOT_FOUND, "test").into_response();
+        assert!(!is_problem(&resp));
     }
 }
@@ -0,0 +1,194 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Events shown in the activity feeds.
+
+use crate::{
+    devices::Device,
+    storage::{Storage, StoredEvent},
+    util::Snowflake,
+    AppState,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// How long the server must go without beats for it to count as an absence.
+pub const ABSENCE_THRESHOLD: TimeDelta = TimeDelta::hours(1);
+
+/// The kinds of events that are stored, rather than derived from other
+/// tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "event_kind", rename_all = "snake_case")]
+pub enum StoredKind {
+    Absence,
+    LongestAbsence,
+    ServerStart,
+    TokenRotated,
+}
+
+/// What happened. The discriminant is the sequence number of the IDs of
+/// derived events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    DeviceAdded = 1,
+    AbsenceStarted,
+    AbsenceEnded,
+    LongestAbsence,
+    ServerStarted,
+    TokenRotated,
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub id: Snowflake,
+    pub kind: Kind,
+    pub time: DateTime<Utc>,
+    /// How long the absence lasted, or has lasted so far.
+    pub absence: Option<TimeDelta>,
+    /// The device the event is about, as it is shown publicly.
+    pub device: Option<Device>,
+}
+
+impl Event {
+    fn derived(kind: Kind, time: DateTime<Utc>) -> Self {
+        Self {
+            id: Snowflake::at(time, kind as u64),
+            kind,
+            time,
+            absence: None,
+            device: None,
+        }
+    }
+}
+
+/// Records an absence that was ended by a beat from `device`, and whether it
+/// was the longest one so far.
+pub async fn record_absence(
+    storage: &dyn Storage,
+    device: i64,
+    started_at: DateTime<Utc>,
+    ended_at: DateTime<Utc>,
+    longest: bool,
+) -> sqlx::Result<()> {
+    let mut events = vec![(Kind::AbsenceEnded, StoredKind::Absence)];
+    if longest {
+        events.push((Kind::LongestAbsence, StoredKind::LongestAbsence));
+    }
+    for (kind, stored) in events {
+        storage
+            .insert_event(StoredEvent {
+                id: id(Event::derived(kind, ended_at).id),
+                kind: stored,
+                time: ended_at,
+                started_at: Some(started_at),
+                device: Some(device),
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Records that the server started at `time`.
+pub async fn record_server_start(storage: &dyn Storage, time: DateTime<Utc>) -> sqlx::Result<()> {
+    storage
+        .insert_event(StoredEvent {
+            id: id(Event::derived(Kind::ServerStarted, time).id),
+            kind: StoredKind::ServerStart,
+            time,
+            started_at: None,
+            device: None,
+        })
+        .await
+}
+
+/// Records that the token of `device` was rotated at `time`.
+pub async fn record_token_rotation(storage: &dyn Storage, device: i64, time: DateTime<Utc>) -> sqlx::Result<()> {
+    storage
+        .insert_event(StoredEvent {
+            id: id(Event::derived(Kind::TokenRotated, time).id),
+            kind: StoredKind::TokenRotated,
+            time,
+            started_at: None,
+            device: Some(device),
+        })
+        .await
+}
+
+fn id(snowflake: Snowflake) -> i64 {
+    i64::try_from(snowflake.id()).unwrap_or(i64::MAX)
+}
+
+/// Returns up to `limit` of the most recent events, newest first.
+pub async fn recent(state: &AppState, limit: usize) -> sqlx::Result<Vec<Event>> {
+    let max = i64::try_from(limit).unwrap_or(i64::MAX);
+    let reader = state.reader();
+    let rows = reader.recent_events(max).await?;
+    let added = reader.recent_devices(max).await?;
+    let (last_seen, devices) = {
+        let guard = state.stats.lock();
+        (guard.last_seen, guard.devices.clone())
+    };
+    let device = |id: i64| {
+        devices
+            .iter()
+            .find(|d| d.id == id)
+            .map(|d| d.public(&state.config.public_metadata))
+    };
+    let mut events = Vec::with_capacity(rows.len() * 2 + added.len() + 1);
+    for record in rows {
+        let absence = record.started_at.map(|started_at| record.time - started_at);
+        let kind = match record.kind {
+            StoredKind::Absence => {
+                if let Some(started_at) = record.started_at {
+                    events.push(Event {
+                        absence,
+                        ..Event::derived(Kind::AbsenceStarted, started_at)
+                    });
+                }
+                Kind::AbsenceEnded
+            }
+            StoredKind::LongestAbsence => Kind::LongestAbsence,
+            StoredKind::ServerStart => Kind::ServerStarted,
+            StoredKind::TokenRotated => Kind::TokenRotated,
+        };
+        events.push(Event {
+            absence,
+            device: record.device.and_then(device),
+            ..Event::derived(kind, record.time)
+        });
+    }
+    for record in added {
+        let id = Snowflake::from(record.id);
+        events.push(Event {
+            id,
+            kind: Kind::DeviceAdded,
+            time: id.created_at(),
+            absence: None,
+            device: Some(device(record.id).unwrap_or(Device {
+                id: record.id,
+                name: record.name,
+                last_beat: None,
+                num_beats: 0,
+                last_credential: None,
+                metadata: None,
+                probe: None,
+            })),
+        });
+    }
+    // the current absence, which will be stored once it ends
+    if let Some(last_seen) = last_seen {
+        let absence = Utc::now() - last_seen;
+        if absence >= ABSENCE_THRESHOLD {
+            events.push(Event {
+                absence: Some(absence),
+                ..Event::derived(Kind::AbsenceStarted, last_seen)
+            });
+        }
+    }
+    events.sort_unstable_by(|a, b| b.time.cmp(&a.time).then(b.id.id().cmp(&a.id.id())));
+    events.dedup_by_key(|e| e.id.id());
+    events.truncate(limit);
+    Ok(events)
+}
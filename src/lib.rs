@@ -11,10 +11,10 @@
 //! A server to keep a live heartbeat (ping) of your devices.
 
 // if we're using reqwest, ensure a tls backend is configured
-#[cfg(not(all(
+#[cfg(all(
     feature = "reqwest",
-    any(feature = "tls-rustls", feature = "tls-native", feature = "tls-native-vendored")
-)))]
+    not(any(feature = "tls-rustls", feature = "tls-native", feature = "tls-native-vendored"))
+))]
 compile_error!(
     "reqwest requires a TLS backend to be configured in order to fire webhooks, please enable one of tls-rustls, \
      tls-native, or tls-native-vendored. The former is recommended unless `rustls` does not run on your architecture \
@@ -24,30 +24,51 @@ compile_error!(
 use axum::extract::FromRef;
 use chrono::{DateTime, Utc};
 use parking_lot::Mutex;
-use sqlx::{postgres::PgPoolOptions, PgPool};
 use stats::Stats;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
+use storage::Storage;
 use tracing::Level;
 use tracing_subscriber::util::SubscriberInitExt;
-use traits::PoolExt;
 
 mod auth;
+#[cfg(feature = "client")]
+pub mod client;
+mod cluster;
 mod config;
-mod devices;
+mod demo;
+pub mod devices;
 mod error;
+mod events;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod probe;
+mod retention;
 mod server;
 mod stats;
+pub mod storage;
 mod templates;
-mod traits;
+mod udp;
 mod util;
+mod writer;
 
 pub mod routes;
 
-#[cfg(feature = "migrate")]
-pub use config::MigrateCli;
+pub use auth::{DEVICE_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+pub use cluster::serve_cluster;
 pub use config::{Cli, Config, Subcmd, WebCli};
-pub use error::handle_errors;
+#[cfg(feature = "client")]
+pub use config::{ClientCli, ClientConfig};
+#[cfg(feature = "migrate")]
+pub use config::{MigrateCli, MigrateCommand};
+pub use error::{handle_errors, Problem, ProblemDetails, PROBLEM_CONTENT_TYPE};
+pub use heartbeat_api::signature;
+#[cfg(feature = "mqtt")]
+pub use mqtt::serve_mqtt;
+pub use probe::serve_probes;
+pub use retention::{compact, serve_compaction};
 pub use server::serve;
+pub use stats::serve_reconciliation;
+pub use udp::serve_udp;
 
 /// Crate version and git commit hash.
 pub const VERSION: &str = env!("HB_VERSION");
@@ -72,12 +93,24 @@ pub fn init_logging() {
 #[derive(Debug, Clone, FromRef)]
 pub struct AppState {
     stats: Arc<Mutex<stats::Stats>>,
-    pool: PgPool,
+    storage: Arc<dyn Storage>,
+    /// Read replicas for reads that may lag behind, such as badges.
+    replicas: Arc<storage::Replicas>,
     config: &'static Config,
     git_revision: &'static str,
     #[cfg(feature = "webhook")]
     webhook: Arc<util::Webhook>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<Arc<mqtt::Bridge>>,
     server_start_time: DateTime<Utc>,
+    /// How many presence connections each device has opened, to tell whether
+    /// it reconnected after a connection dropped.
+    presence: Arc<Mutex<HashMap<i64, u64>>>,
+    writer: Arc<writer::Writer>,
+    cluster: Arc<cluster::Cluster>,
+    /// How far the stats had drifted from the database when they were last
+    /// reconciled.
+    drift: Arc<Mutex<Option<stats::Drift>>>,
 }
 
 impl AppState {
@@ -89,29 +122,91 @@ impl AppState {
     ///
     /// # Errors
     ///
-    /// This function will return an error if the database pool could not be
-    /// created, or (for some reason), fetching the stats using the pool
-    /// fails.
-    pub async fn from_config(config: &'static Config) -> sqlx::Result<Self> {
+    /// This function will return an error if the DSN's scheme isn't supported
+    /// or is `memory:`, the database pool could not be created, pending migrations could not be
+    /// applied with `database.auto_migrate`, or the database schema doesn't
+    /// match this build.
+    pub async fn from_config(config: &'static Config) -> Result<Self, storage::migrations::Error> {
+        for dsn in std::iter::once(&config.database.dsn).chain(&config.database.replicas) {
+            storage::ensure_persistent(dsn)?;
+        }
+        let storage = storage::connect(&config.database.dsn, &config.database.options)?;
+        if config.database.auto_migrate {
+            #[cfg(feature = "migrate")]
+            {
+                tracing::info!("Applying pending migrations");
+                storage.migrate().await?;
+            }
+            #[cfg(not(feature = "migrate"))]
+            return Err(storage::migrations::Error::AutoMigrateUnsupported);
+        }
+        storage::migrations::check(&*storage).await?;
+        let replicas = storage::Replicas::connect(&config.database.replicas, &config.database.options)?;
+        Ok(Self::with_storage(config, storage).await.with_replicas(replicas))
+    }
+
+    /// Returns a new [`AppState`] backed by an in-memory [`storage::Memory`]
+    /// with made-up devices and beats in it, ignoring the configured
+    /// database.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the made-up data could not be
+    /// stored.
+    pub async fn demo(config: &'static Config) -> sqlx::Result<Self> {
+        let now = Utc::now();
+        let storage = Arc::new(storage::Memory::new(now - chrono::TimeDelta::days(demo::DAYS)));
+        demo::seed(&*storage, now).await?;
+        Ok(Self::with_storage(config, storage).await)
+    }
+
+    /// Returns a new [`AppState`] backed by `storage`, such as an empty
+    /// [`storage::Memory`] in tests.
+    pub async fn with_storage(config: &'static Config, storage: Arc<dyn Storage>) -> Self {
         #[cfg(feature = "webhook")]
         let webhook = util::Webhook::new(&config.webhook);
-        let pool = PgPoolOptions::default()
-            .max_connections(10)
-            .connect_lazy(&config.database.dsn)?;
         let (server_start_time, stats) = {
             (
-                pool.server_start_time().await,
-                Arc::new(Mutex::new(Stats::fetch(&pool).await)),
+                storage.server_start_time().await,
+                Arc::new(Mutex::new(Stats::fetch(&*storage).await)),
             )
         };
-        Ok(Self {
+        if let Err(e) = events::record_server_start(&*storage, Utc::now()).await {
+            tracing::error!("Failed to record server start: {e:?}");
+        }
+        let cluster = cluster::Cluster::join(storage.clone()).await;
+        let writer = writer::Writer::start(storage.clone(), cluster.clone(), config.write_buffer);
+        Self {
             stats,
-            pool,
+            storage,
+            replicas: Arc::default(),
             config,
             git_revision: env!("HB_GIT_REVISION"),
             #[cfg(feature = "webhook")]
             webhook: Arc::new(webhook),
+            #[cfg(feature = "mqtt")]
+            mqtt: mqtt::Bridge::new(&config.mqtt).map(Arc::new),
             server_start_time,
-        })
+            presence: Arc::default(),
+            writer,
+            cluster,
+            drift: Arc::default(),
+        }
+    }
+
+    /// Reads from `replicas` where it doesn't matter if they lag a little
+    /// behind.
+    #[must_use]
+    pub fn with_replicas(self, replicas: storage::Replicas) -> Self {
+        Self {
+            replicas: Arc::new(replicas),
+            ..self
+        }
+    }
+
+    /// Returns the next read replica, or the database itself if there are
+    /// none.
+    fn reader(&self) -> &dyn Storage {
+        self.replicas.next().unwrap_or(&self.storage).as_ref()
     }
 }
@@ -19,7 +19,7 @@ use clap::Parser;
 use color_eyre::eyre::Result;
 use heartbeat::{handle_errors, routes::router, AppState, Cli, Config, Subcmd, WebCli};
 use std::{net::SocketAddr, sync::OnceLock, time::Duration};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UdpSocket};
 use tower_http::{
     timeout::TimeoutLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
@@ -56,40 +56,69 @@ async fn main() -> Result<()> {
         #[cfg(feature = "migrate")]
         Subcmd::Migrate(cli) => migrate(cli).await,
         Subcmd::GenKey => gen_key(),
+        Subcmd::Compact(cli) => compact(*cli).await,
+        #[cfg(feature = "client")]
+        Subcmd::Client(cli) => client(cli).await,
     }
 }
 
 async fn web(cli: WebCli) -> Result<()> {
     static CONFIG: OnceLock<Config> = OnceLock::new();
+    let demo = cli.demo;
     let config = Config::try_new(cli)?;
     CONFIG.set(config).expect("config to not be set");
     let config = CONFIG.get().expect("config to be set");
     info!(config = ?config, "Loaded config");
     let bind = config.bind;
     let router = router(config);
-    let app_state = AppState::from_config(config).await?;
+    let app_state = if demo {
+        info!("Running in demo mode, nothing will be persisted");
+        AppState::demo(config).await?
+    } else {
+        AppState::from_config(config).await?
+    };
+    tokio::spawn(heartbeat::serve_cluster(app_state.clone()).instrument(span!(Level::INFO, "cluster")));
+    tokio::spawn(heartbeat::serve_reconciliation(app_state.clone()).instrument(span!(Level::INFO, "stats")));
+    tokio::spawn(heartbeat::serve_probes(app_state.clone()).instrument(span!(Level::INFO, "probe")));
+    tokio::spawn(heartbeat::serve_compaction(app_state.clone()).instrument(span!(Level::INFO, "retention")));
+    #[cfg(feature = "mqtt")]
+    tokio::spawn(heartbeat::serve_mqtt(app_state.clone()).instrument(span!(Level::INFO, "mqtt")));
+    if let Some(udp_bind) = config.udp.bind {
+        let socket = UdpSocket::bind(udp_bind).await?;
+        info!("Listening for UDP beats on {}", socket.local_addr()?);
+        tokio::spawn(heartbeat::serve_udp(socket, app_state.clone()).instrument(span!(Level::INFO, "udp")));
+    }
     let trace_service = TraceLayer::new_for_http()
         .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
         .on_response(DefaultOnResponse::new().level(Level::INFO));
     let router = router
         .with_state(app_state.clone())
-        .layer(middleware::from_fn_with_state(app_state, handle_errors))
+        .layer(middleware::from_fn_with_state(app_state.clone(), handle_errors))
         .layer(trace_service)
         .layer(TimeoutLayer::new(Duration::from_secs(10)))
         .into_make_service_with_connect_info::<SocketAddr>();
     let bind = TcpListener::bind(&bind).await?;
     info!("Listening on {}", bind.local_addr()?);
-    let server = heartbeat::serve(bind, router);
+    let server = heartbeat::serve(bind, router, app_state);
     Ok(server.instrument(span!(Level::INFO, "server")).await?)
 }
 
+#[cfg(feature = "client")]
+async fn client(cli: heartbeat::ClientCli) -> Result<()> {
+    let config = heartbeat::ClientConfig::try_new(cli)?;
+    Ok(heartbeat::client::run(config).await?)
+}
+
 #[cfg(feature = "migrate")]
 async fn migrate(cli: heartbeat::MigrateCli) -> Result<()> {
     use std::io;
 
+    use heartbeat::{
+        storage::{migrations, ConnectOptions},
+        MigrateCommand,
+    };
     use heartbeat_sys::heartbeat_home;
-    use sqlx::PgPool;
-    let from_toml = || {
+    let from_toml = |field: &str| -> Result<Option<String>> {
         let default = || {
             let mut path = heartbeat_home().ok()?;
             path.push("config.toml");
@@ -102,22 +131,67 @@ async fn migrate(cli: heartbeat::MigrateCli) -> Result<()> {
                 .or_else(default)
                 .ok_or_else(|| io::Error::other("could not determine heartbeat home dir"))?,
         )?)?;
-        let maybe_dsn = config
+        Ok(config
             .get("database")
-            .and_then(|v| v.get("dsn"))
+            .and_then(|v| v.get(field))
             .and_then(toml::Value::as_str)
-            .map(String::from);
-        maybe_dsn.ok_or_else(|| color_eyre::eyre::eyre!("Database DSN not provided."))
+            .map(String::from))
     };
     let dsn = if let Some(dsn) = cli.database_dsn {
         dsn
     } else {
-        from_toml()?
+        from_toml("dsn")?.ok_or_else(|| color_eyre::eyre::eyre!("Database DSN not provided."))?
     };
+    let mut options = ConnectOptions::default();
+    if let Some(schema) = cli.database_schema.or_else(|| from_toml("schema").ok().flatten()) {
+        options.schema = schema;
+    }
     info!("Using DSN: {dsn}");
-    let pool = PgPool::connect(&dsn).await?;
-    info!("Running migrations...");
-    Ok(sqlx::migrate!().run(&pool).await?)
+    let storage = heartbeat::storage::connect(&dsn, &options)?;
+    match cli.command.unwrap_or_default() {
+        MigrateCommand::Status => {
+            for status in migrations::status(&*storage).await? {
+                println!(
+                    "{:<16}{:<10}{}{}",
+                    status.version,
+                    status.state,
+                    status.description.as_deref().unwrap_or("<not in this build>"),
+                    if status.reversible { " (reversible)" } else { "" }
+                );
+            }
+        }
+        MigrateCommand::Up => {
+            info!("Running migrations...");
+            storage.migrate().await?;
+        }
+        MigrateCommand::Down { to } => {
+            for status in migrations::down(&*storage, to).await? {
+                println!(
+                    "Reverted {} {}",
+                    status.version,
+                    status.description.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        MigrateCommand::Verify => {
+            migrations::verify(&*storage).await?;
+            println!("The database schema matches this build");
+        }
+    }
+    Ok(())
+}
+
+async fn compact(cli: WebCli) -> Result<()> {
+    let config = Config::try_new(cli)?;
+    let storage = heartbeat::storage::connect(&config.database.dsn, &config.database.options)?;
+    match heartbeat::compact(&*storage, &config, chrono::Utc::now()).await? {
+        Some(compacted) => println!(
+            "Compacted {} beats into hourly rollups and {} hourly rollups into daily ones",
+            compacted.beats, compacted.hours
+        ),
+        None => println!("No retention period is configured, so nothing was compacted"),
+    }
+    Ok(())
 }
 
 fn gen_key() -> color_eyre::Result<()> {
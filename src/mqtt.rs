@@ -0,0 +1,437 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    config::{Mqtt as MqttConfig, MqttDevice},
+    events::ABSENCE_THRESHOLD,
+    routes::record_beat,
+    server::shutdown,
+    AppState,
+};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rumqttc::{matches, AsyncClient, Event as MqttEvent, EventLoop, MqttOptions, Packet, Publish, QoS};
+use serde::Serialize;
+use std::{fmt, time::Duration};
+use tracing::{debug, error, info, warn};
+
+/// How long to wait before reconnecting to the broker after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How often to check whether this instance started or stopped leading.
+const LEADER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to check whether an absence has started.
+const ABSENCE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A connection to an MQTT broker.
+pub struct Bridge {
+    config: &'static MqttConfig,
+    client: AsyncClient,
+    eventloop: Mutex<Option<EventLoop>>,
+}
+
+impl fmt::Debug for Bridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bridge")
+            .field("config", &self.config)
+            .field("client", &self.client)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Bridge {
+    /// Returns a new [`Bridge`], or [`None`] if no broker is configured or its
+    /// URL is invalid.
+    pub fn new(config: &'static MqttConfig) -> Option<Self> {
+        if config.url.is_empty() {
+            return None;
+        }
+        let options = match MqttOptions::parse_url(&config.url) {
+            Ok(options) => options,
+            Err(e) => {
+                error!("Invalid MQTT broker URL, not starting the MQTT bridge: {e}");
+                return None;
+            }
+        };
+        let (client, eventloop) = AsyncClient::new(options, 64);
+        Some(Self {
+            config,
+            client,
+            eventloop: Mutex::new(Some(eventloop)),
+        })
+    }
+
+    /// Publishes an event to the status topic, if one is configured.
+    ///
+    /// This never waits for the broker, so events are dropped if the
+    /// connection is backed up.
+    pub fn publish(&self, event: &Event) {
+        if self.config.status_topic.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_vec(event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize MQTT event: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self
+            .client
+            .try_publish(&self.config.status_topic, QoS::AtLeastOnce, false, payload)
+        {
+            debug!("failed to publish MQTT event: {e}");
+        }
+    }
+
+    fn subscribe(&self) {
+        for device in &self.config.devices {
+            if let Err(e) = self.client.try_subscribe(&device.topic, QoS::AtLeastOnce) {
+                error!(topic = device.topic, "Failed to subscribe to MQTT topic: {e}");
+            }
+        }
+    }
+
+    fn unsubscribe(&self) {
+        for device in &self.config.devices {
+            if let Err(e) = self.client.try_unsubscribe(&device.topic) {
+                error!(topic = device.topic, "Failed to unsubscribe from MQTT topic: {e}");
+            }
+        }
+    }
+}
+
+/// An event that is published to the status topic.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A beat was received from a device.
+    Beat {
+        device: i64,
+        name: Option<String>,
+        #[serde(with = "chrono::serde::ts_seconds")]
+        time: DateTime<Utc>,
+    },
+    /// No beats have been received for a while.
+    AbsenceStarted {
+        #[serde(with = "chrono::serde::ts_seconds")]
+        since: DateTime<Utc>,
+    },
+    /// A beat was received after a long absence.
+    AbsenceEnded {
+        #[serde(with = "chrono::serde::ts_seconds")]
+        from: DateTime<Utc>,
+        #[serde(with = "chrono::serde::ts_seconds")]
+        to: DateTime<Utc>,
+    },
+}
+
+/// Publishes an event to the MQTT status topic, if the bridge is enabled.
+pub fn publish(state: &AppState, event: &Event) {
+    if let Some(bridge) = &state.mqtt {
+        bridge.publish(event);
+    }
+}
+
+/// Returns the configured devices that a message is a beat from.
+fn mapped_devices<'a>(
+    devices: &'a [MqttDevice],
+    topic: &'a str,
+    payload: &'a [u8],
+) -> impl Iterator<Item = &'a MqttDevice> {
+    devices.iter().filter(move |device| {
+        matches(topic, &device.topic) && device.payload.as_ref().is_none_or(|p| p.as_bytes() == payload)
+    })
+}
+
+/// Bridge beats from the MQTT broker until the server shuts down.
+///
+/// Every instance stays connected to publish events, but only the leader
+/// subscribes to the device topics, so each message is recorded once.
+///
+/// This returns immediately if the MQTT bridge is not configured.
+pub async fn serve_mqtt(state: AppState) {
+    let Some(bridge) = state.mqtt.clone() else {
+        return;
+    };
+    let Some(mut eventloop) = bridge.eventloop.lock().take() else {
+        return;
+    };
+    info!("Connecting to MQTT broker");
+    let monitor = tokio::spawn(monitor_absences(state.clone()));
+    let mut connected = false;
+    let mut subscribed = false;
+    let mut interval = tokio::time::interval(LEADER_CHECK_INTERVAL);
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            _ = interval.tick() => {
+                if connected {
+                    subscribed = sync_subscriptions(&state, &bridge, subscribed);
+                }
+                continue;
+            }
+            () = shutdown() => {
+                debug!("shutdown signal received, disconnecting from MQTT broker");
+                let _ = bridge.client.try_disconnect();
+                break;
+            }
+        };
+        match event {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                connected = true;
+                subscribed = sync_subscriptions(&state, &bridge, false);
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                tokio::spawn(handle_publish(state.clone(), publish));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                connected = false;
+                warn!("MQTT connection error, reconnecting in {RECONNECT_DELAY:?}: {e}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+    monitor.abort();
+}
+
+/// Subscribes to the device topics if this instance leads, and unsubscribes
+/// if it no longer does. Returns whether it is now subscribed.
+fn sync_subscriptions(state: &AppState, bridge: &Bridge, subscribed: bool) -> bool {
+    let leader = state.cluster.is_leader();
+    if leader && !subscribed {
+        debug!("leading, subscribing to MQTT topics");
+        bridge.subscribe();
+    } else if !leader && subscribed {
+        debug!("no longer leading, unsubscribing from MQTT topics");
+        bridge.unsubscribe();
+    }
+    leader
+}
+
+async fn handle_publish(state: AppState, publish: Publish) {
+    let config = &state.config.mqtt;
+    // messages can still arrive for a while after unsubscribing
+    if publish.topic == config.status_topic || !state.cluster.is_leader() {
+        return;
+    }
+    for device in mapped_devices(&config.devices, &publish.topic, &publish.payload) {
+        match state.storage.lookup_token(&device.token).await {
+            Ok(Some((device, None))) => {
+                record_beat(state.clone(), device, None).await;
+            }
+            Ok(Some((device, Some(_)))) => {
+                warn!(id = %device.id, topic = publish.topic, "Ignoring MQTT beat for a device that requires signed beats");
+            }
+            Ok(None) => warn!(topic = publish.topic, "Invalid token configured for MQTT topic"),
+            Err(e) => error!("Failed to look up device for MQTT beat: {e:?}"),
+        }
+    }
+}
+
+/// Publishes an event when no beats have been received for a while, if this
+/// instance leads.
+///
+/// The others keep track of the absences too, so that an absence the leader
+/// already announced isn't announced again when another instance takes over.
+async fn monitor_absences(state: AppState) {
+    let mut announced = None;
+    let mut interval = tokio::time::interval(ABSENCE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let last_seen = state.stats.lock().last_seen;
+        let Some(since) = last_seen else {
+            continue;
+        };
+        if announced != Some(since) && Utc::now() - since >= ABSENCE_THRESHOLD {
+            if state.cluster.is_leader() {
+                publish(&state, &Event::AbsenceStarted { since });
+            }
+            announced = Some(since);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices::Credential,
+        storage::{Memory, NewDevice, Storage},
+        Config, WebCli,
+    };
+    use clap::Parser;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    fn device(topic: &str, payload: Option<&str>) -> MqttDevice {
+        MqttDevice {
+            topic: topic.into(),
+            token: String::new().into(),
+            payload: payload.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn test_map_topics_to_devices() {
+        let devices = [
+            device("home/phone/presence", Some("home")),
+            device("home/+/online", None),
+        ];
+        let mapped = |topic, payload: &[u8]| mapped_devices(&devices, topic, payload).count();
+        assert_eq!(mapped("home/phone/presence", b"home"), 1);
+        assert_eq!(mapped("home/phone/presence", b"not_home"), 0);
+        assert_eq!(mapped("home/laptop/online", b""), 1);
+        assert_eq!(mapped("home/laptop/offline", b""), 0);
+    }
+
+    #[test]
+    fn test_serialize_events() {
+        let time = DateTime::from_timestamp(1_698_915_036, 0).expect("valid timestamp");
+        let event = Event::Beat {
+            device: 1,
+            name: Some("Laptop".into()),
+            time,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).expect("serializable"),
+            r#"{"event":"beat","device":1,"name":"Laptop","time":1698915036}"#
+        );
+        let event = Event::AbsenceEnded { from: time, to: time };
+        assert_eq!(
+            serde_json::to_string(&event).expect("serializable"),
+            r#"{"event":"absence_ended","from":1698915036,"to":1698915036}"#
+        );
+    }
+
+    /// Connects an instance with one device, which last sent a beat at
+    /// `last_beat`, to the broker at `url`.
+    async fn bridged(url: &str, prefix: &str, name: &str, last_beat: DateTime<Utc>) -> (AppState, Arc<Memory>) {
+        let cli = WebCli::parse_from(["heartbeat", "--secret-key", "sekrit", "--database-dsn", "memory:"]);
+        let mut config = Config::try_new(cli).expect("config to be valid");
+        config.mqtt = MqttConfig {
+            url: format!("{url}?client_id={}-{name}", prefix.replace('/', "-")),
+            status_topic: format!("{prefix}/status"),
+            devices: vec![MqttDevice {
+                topic: format!("{prefix}/beat"),
+                token: String::from("token").into(),
+                payload: Some("home".into()),
+            }],
+        };
+        let storage = Arc::new(Memory::default());
+        storage
+            .add_device(NewDevice {
+                id: 1,
+                name: "phone",
+                token: "token",
+                signing_secret: None,
+                public_key: None,
+                probe: None,
+            })
+            .await
+            .expect("device to be added");
+        storage
+            .insert_beats(1, Credential::Current, &[last_beat])
+            .await
+            .expect("beat to be inserted");
+        let config = Box::leak(Box::new(config));
+        (AppState::with_storage(config, storage.clone()).await, storage)
+    }
+
+    /// Subscribes to `topic`, returning a client to send beats with and the
+    /// events published to the topic.
+    async fn observe(url: &str, topic: &str) -> (AsyncClient, mpsc::UnboundedReceiver<Value>) {
+        let options = MqttOptions::parse_url(format!("{url}?client_id={}-observer", topic.replace('/', "-")))
+            .expect("valid broker URL");
+        let (client, mut eventloop) = AsyncClient::new(options, 16);
+        client
+            .subscribe(topic, QoS::AtLeastOnce)
+            .await
+            .expect("subscription to be sent");
+        while !matches!(
+            eventloop.poll().await.expect("broker to be reachable"),
+            MqttEvent::Incoming(Packet::SubAck(_))
+        ) {}
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(event) = eventloop.poll().await {
+                if let MqttEvent::Incoming(Packet::Publish(publish)) = event {
+                    let _ = tx.send(serde_json::from_slice(&publish.payload).expect("event to be JSON"));
+                }
+            }
+        });
+        (client, rx)
+    }
+
+    /// Waits up to `millis` for the next event.
+    async fn next(events: &mut mpsc::UnboundedReceiver<Value>, millis: u64) -> Option<Value> {
+        tokio::time::timeout(Duration::from_millis(millis), events.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn num_beats(storage: &Memory) -> i64 {
+        storage.devices().await.expect("devices to be read")[0].num_beats
+    }
+
+    /// Bridges beats through the broker at `HEARTBEAT_TEST_MQTT_URL`, such as
+    /// `mqtt://localhost:1883`, which is skipped if it isn't set.
+    #[tokio::test]
+    async fn test_bridge() {
+        let Ok(url) = std::env::var("HEARTBEAT_TEST_MQTT_URL") else {
+            return;
+        };
+        let prefix = format!("heartbeat-test/{}", std::process::id());
+        let (observer, mut events) = observe(&url, &format!("{prefix}/status")).await;
+        let since = Utc::now() - ABSENCE_THRESHOLD * 2;
+        let (leader, leader_storage) = bridged(&url, &prefix, "leader", since).await;
+        let (follower, follower_storage) = bridged(&url, &prefix, "follower", since).await;
+        follower.cluster.step_down();
+        let tasks = [&leader, &follower].map(|state| tokio::spawn(serve_mqtt(state.clone())));
+
+        // only the leader announces the absence
+        assert_eq!(
+            next(&mut events, 5000).await,
+            Some(json!({"event": "absence_started", "since": since.timestamp()}))
+        );
+        // beats are only received once the leader has subscribed
+        let mut event = None;
+        for _ in 0..10 {
+            observer
+                .publish(format!("{prefix}/beat"), QoS::AtLeastOnce, false, "home")
+                .await
+                .expect("beat to be sent");
+            event = next(&mut events, 500).await;
+            if event.is_some() {
+                break;
+            }
+        }
+        let event = event.expect("absence to end");
+        assert_eq!(
+            (&event["event"], &event["from"]),
+            (&json!("absence_ended"), &json!(since.timestamp()))
+        );
+        let event = next(&mut events, 5000).await.expect("beat to be announced");
+        assert_eq!((&event["event"], &event["name"]), (&json!("beat"), &json!("phone")));
+        assert_eq!(num_beats(&leader_storage).await, 2);
+
+        // messages that aren't beats, and any that reach followers, are ignored
+        handle_publish(leader, Publish::new(format!("{prefix}/beat"), QoS::AtLeastOnce, "away")).await;
+        handle_publish(
+            follower,
+            Publish::new(format!("{prefix}/beat"), QoS::AtLeastOnce, "home"),
+        )
+        .await;
+        assert_eq!(num_beats(&leader_storage).await, 2);
+        assert_eq!(num_beats(&follower_storage).await, 1);
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    auth::Device as DeviceAuth,
+    devices::{Credential, ProbeCheck},
+    routes::record_beat,
+    server::shutdown,
+    storage::ProbeTarget,
+    AppState,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+use tracing::{debug, error};
+
+/// How often to check whether any probes are due.
+const PROBE_TICK: Duration = Duration::from_secs(5);
+/// How long a single probe may take before it counts as a failure.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Poll devices that have a probe configured until the server shuts down.
+/// Only the leader of the instances sharing the database polls them.
+///
+/// Successful probes are recorded as beats, the same way as those sent to
+/// `POST /api/v1/beat`.
+pub async fn serve_probes(state: AppState) {
+    #[cfg(feature = "reqwest")]
+    let client = reqwest::Client::builder()
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    let mut last_run = HashMap::<i64, Instant>::new();
+    let mut interval = tokio::time::interval(PROBE_TICK);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown() => {
+                debug!("shutdown signal received, no longer probing devices");
+                break;
+            }
+        }
+        if !state.cluster.is_leader() {
+            continue;
+        }
+        let targets = match state.storage.probe_targets().await {
+            Ok(targets) => targets,
+            Err(e) => {
+                error!("Failed to load probes: {e:?}");
+                continue;
+            }
+        };
+        let now = Instant::now();
+        let due = targets
+            .into_iter()
+            .filter(|target| {
+                last_run
+                    .get(&target.id)
+                    .is_none_or(|last| now.duration_since(*last) >= Duration::from_secs(target.probe.interval))
+            })
+            .collect::<Vec<_>>();
+        for ProbeTarget { id, name, probe } in due {
+            last_run.insert(id, now);
+            let device = DeviceAuth {
+                id,
+                name,
+                credential: Credential::Probe,
+            };
+            #[cfg(feature = "reqwest")]
+            let client = client.clone();
+            let state = state.clone();
+            tokio::spawn(async move {
+                let res = tokio::time::timeout(
+                    PROBE_TIMEOUT,
+                    run(
+                        &probe.check,
+                        #[cfg(feature = "reqwest")]
+                        &client,
+                    ),
+                )
+                .await
+                .unwrap_or_else(|_| Err("timed out".into()));
+                match res {
+                    Ok(()) => {
+                        record_beat(state, device, None).await;
+                    }
+                    Err(reason) => debug!(id = %device.id, "Probe failed: {reason}"),
+                }
+            });
+        }
+    }
+}
+
+async fn run(check: &ProbeCheck, #[cfg(feature = "reqwest")] client: &reqwest::Client) -> Result<(), String> {
+    match check {
+        ProbeCheck::Tcp { address } => TcpStream::connect(address.as_str())
+            .await
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        ProbeCheck::Dns { name } => {
+            let mut addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await
+                .map_err(|e| e.to_string())?;
+            addrs.next().map(drop).ok_or_else(|| "no addresses found".into())
+        }
+        #[cfg(feature = "reqwest")]
+        ProbeCheck::Http { url, status, body } => {
+            let resp = client.get(url).send().await.map_err(|e| e.to_string())?;
+            let ok = status.map_or_else(|| resp.status().is_success(), |s| resp.status().as_u16() == s);
+            if !ok {
+                return Err(format!("unexpected status {}", resp.status()));
+            }
+            if let Some(body) = body {
+                let text = resp.text().await.map_err(|e| e.to_string())?;
+                if !text.contains(body.as_str()) {
+                    return Err("body did not match".into());
+                }
+            }
+            Ok(())
+        }
+        #[cfg(not(feature = "reqwest"))]
+        ProbeCheck::Http { .. } => Err("HTTP probes require the `webhook` feature".into()),
+    }
+}
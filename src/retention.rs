@@ -0,0 +1,126 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compacts old beats into rollups, according to the retention policy.
+
+use crate::{
+    config::Retention,
+    server::shutdown,
+    storage::{Compacted, Storage},
+    AppState, Config,
+};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// How often old beats are compacted while the server is running.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Returns the time before which raw beats are rolled up into hours, and the
+/// one before which hourly rollups are rolled up into days, at `now`.
+///
+/// Beats that may still be backfilled are never compacted, and neither
+/// cutoff splits an hour or day.
+fn cutoffs(
+    retention: Retention,
+    max_backfill_age: Duration,
+    now: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, Option<DateTime<Utc>>)> {
+    let before = |age: Duration, bucket: TimeDelta| {
+        let age = TimeDelta::from_std(age).ok()?;
+        now.checked_sub_signed(age)?.duration_trunc(bucket).ok()
+    };
+    let raw = retention.raw?.max(max_backfill_age);
+    let raw_before = before(raw, TimeDelta::hours(1))?;
+    let hourly_before = retention
+        .hourly
+        .and_then(|hourly| before(hourly.max(raw), TimeDelta::days(1)));
+    Some((raw_before, hourly_before))
+}
+
+/// Compacts the beats that are older than the retention periods in `config`
+/// allow at `now`. Returns `None` if raw beats are kept forever.
+///
+/// # Errors
+///
+/// Returns an error if the beats couldn't be compacted, in which case none of
+/// them are.
+pub async fn compact(storage: &dyn Storage, config: &Config, now: DateTime<Utc>) -> sqlx::Result<Option<Compacted>> {
+    let Some((raw_before, hourly_before)) = cutoffs(config.retention, config.max_backfill_age, now) else {
+        return Ok(None);
+    };
+    debug!(%raw_before, ?hourly_before, "Compacting beats");
+    storage.compact(raw_before, hourly_before).await.map(Some)
+}
+
+/// Compacts old beats every hour until the server shuts down, if a retention
+/// period is configured. Only the leader of the instances sharing the database
+/// compacts them.
+pub async fn serve_compaction(state: AppState) {
+    if state.config.retention.raw.is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown() => {
+                debug!("shutdown signal received, no longer compacting beats");
+                break;
+            }
+        }
+        if !state.cluster.is_leader() {
+            continue;
+        }
+        match compact(&*state.storage, state.config, Utc::now()).await {
+            Ok(Some(compacted)) if compacted.beats > 0 || compacted.hours > 0 => {
+                info!(
+                    "Compacted {} beats into hourly rollups and {} hourly rollups into daily ones",
+                    compacted.beats, compacted.hours
+                );
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to compact beats: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    #[test]
+    fn test_cutoffs() {
+        let now = "2024-03-06T12:34:56Z"
+            .parse::<DateTime<Utc>>()
+            .expect("valid timestamp");
+        let time = |s: &str| s.parse::<DateTime<Utc>>().expect("valid timestamp");
+        let forever = Retention {
+            raw: None,
+            hourly: None,
+        };
+        assert_eq!(cutoffs(forever, DAY * 7, now), None);
+        let raw_only = Retention {
+            raw: Some(DAY * 30),
+            hourly: None,
+        };
+        assert_eq!(
+            cutoffs(raw_only, DAY * 7, now),
+            Some((time("2024-02-05T12:00:00Z"), None))
+        );
+        // backfilled beats must not land in compacted hours
+        let both = Retention {
+            raw: Some(DAY),
+            hourly: Some(DAY * 2),
+        };
+        assert_eq!(
+            cutoffs(both, DAY * 7, now),
+            Some((time("2024-02-28T12:00:00Z"), Some(time("2024-02-28T00:00:00Z"))))
+        );
+    }
+}
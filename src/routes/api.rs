@@ -4,30 +4,36 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, Event as MqttEvent};
 #[cfg(feature = "webhook")]
 use crate::util::WebhookColour;
 use crate::{
-    auth::{Device as DeviceAuth, Master as MasterAuth},
+    auth::{Device as DeviceAuth, KeyDevice as KeyDeviceAuth, Master as MasterAuth},
+    cluster::{self, Change},
     config::WebhookLevel,
-    devices::{Device, PostDevice},
-    error::Error,
+    devices::{Device, Metadata, PostBeats, PostDevice, Probe, MAX_BATCH_SIZE, MAX_METADATA_SIZE},
+    error::{Error, Problem, ProblemDetails},
+    events::{self, ABSENCE_THRESHOLD},
+    routes::{BatchResp, DeviceAddResp, DeviceUpdateResp, ProbeResp, SigningSecretResp, StatsResp},
+    signature,
+    storage::{Batch, NewBeat, NewDevice},
     util::{generate_token, Snowflake, SnowflakeGenerator},
     AppState,
 };
 use axum::{
+    body::Bytes,
     extract::{
         ws::{Message, WebSocket},
         Path, State, WebSocketUpgrade,
     },
-    http::StatusCode,
+    http::{Method, StatusCode, Uri},
     response::Response,
     Json,
 };
-use chrono::Utc;
-use serde::Serialize;
-use sqlx::postgres::types::PgInterval;
+use chrono::{DateTime, Utc};
 use std::time::UNIX_EPOCH;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 #[allow(unused_variables)]
 async fn fire_webhook(state: AppState, title: &str, message: &str, level: WebhookLevel) {
@@ -48,73 +54,129 @@ async fn fire_webhook(state: AppState, title: &str, message: &str, level: Webhoo
     }
 }
 
-#[axum::debug_handler]
-pub async fn handle_beat_req(State(state): State<AppState>, info: DeviceAuth) -> (StatusCode, String) {
-    let now = Utc::now();
-    let prev_beat = sqlx::query!(
-        r"
-    WITH discard AS (
-        INSERT INTO heartbeat.beats (time_stamp, device) VALUES ($1, $2)
+/// Parses the optional metadata in the body of a beat.
+fn parse_metadata(state: &AppState, method: &Method, uri: &Uri, body: &[u8]) -> Result<Option<Metadata>, Error> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let bad_request = |problem| {
+        Error::new(uri.path(), method, StatusCode::BAD_REQUEST, &state.config.server_name).with_problem(problem)
+    };
+    let metadata = serde_json::from_slice::<Metadata>(body).map_err(|_| bad_request(Problem::InvalidMetadata))?;
+    metadata.validate().map_err(bad_request)?;
+    Ok(Some(metadata))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/beat",
+    tag = "beats",
+    request_body(content = Option<Metadata>, description = "Optional metadata about the device."),
+    responses(
+        (status = 200, description = "The Unix timestamp the beat was acknowledged at.", body = String, content_type = "text/plain", example = "1698915036"),
+        (status = 400, description = "Invalid metadata.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid token, or a missing or invalid signature.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 413, description = "The request body is larger than 4096 bytes.", body = ProblemDetails, content_type = "application/problem+json"),
     ),
-    discard_this_too AS (
-        UPDATE heartbeat.devices SET num_beats = num_beats + 1 WHERE id = $2
+    security(("token" = [])),
+)]
+#[axum::debug_handler]
+pub async fn handle_beat_req(
+    State(state): State<AppState>,
+    info: DeviceAuth,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Result<(StatusCode, String), Error> {
+    let metadata = parse_metadata(&state, &method, &uri, &body)?;
+    Ok(record_beat(state, info, metadata).await)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/beat/key",
+    tag = "beats",
+    request_body(content = Option<Metadata>, description = "Optional metadata about the device."),
+    responses(
+        (status = 200, description = "The Unix timestamp the beat was acknowledged at.", body = String, content_type = "text/plain", example = "1698915036"),
+        (status = 400, description = "Invalid metadata.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Unknown device, or an invalid, stale or replayed signature.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 413, description = "The request body is larger than 4096 bytes.", body = ProblemDetails, content_type = "application/problem+json"),
     ),
-    longest_absence AS (
-        SELECT longest_absence FROM heartbeat.stats
-    )
-    SELECT beats.time_stamp, longest_absence.longest_absence
-    FROM heartbeat.beats beats, longest_absence
-    ORDER BY time_stamp DESC
-    LIMIT 1;
-    ",
-        now,
-        info.id
+    security(("key" = [])),
+)]
+#[axum::debug_handler]
+pub async fn handle_key_beat_req(
+    State(state): State<AppState>,
+    KeyDeviceAuth(info): KeyDeviceAuth,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Result<(StatusCode, String), Error> {
+    let metadata = parse_metadata(&state, &method, &uri, &body)?;
+    Ok(record_beat(state, info, metadata).await)
+}
+
+/// Records and announces an absence that was ended by a beat from `device`.
+async fn absence_ended(state: &AppState, device: i64, from: DateTime<Utc>, to: DateTime<Utc>, longest: bool) {
+    if let Err(e) = events::record_absence(&*state.storage, device, from, to, longest).await {
+        error!("Failed to record absence: {e:?}");
+    }
+    #[cfg(feature = "mqtt")]
+    mqtt::publish(state, &MqttEvent::AbsenceEnded { from, to });
+    fire_webhook(
+        state.clone(),
+        "Absence longer than 1 hour",
+        &format!("From <t:{}> to <t:{}>", from.timestamp(), to.timestamp()),
+        WebhookLevel::LongAbsences,
     )
-    .fetch_optional(&state.pool)
-    .await
-    .unwrap_or_else(|e| {
-        error!("Failed to update database on successful beat: {e:?}");
-        None
-    });
-    if let Some(record) = prev_beat {
-        let diff = now - record.time_stamp;
-        let update_longest_absence = {
-            let mut ret = false;
-            let mut w = state.stats.lock();
-            if diff > w.longest_absence {
-                w.longest_absence = diff;
-                ret = true;
+    .await;
+}
+
+pub async fn record_beat(state: AppState, info: DeviceAuth, metadata: Option<Metadata>) -> (StatusCode, String) {
+    let now = Utc::now();
+    // errors have already been logged
+    let written = state
+        .writer
+        .beat(NewBeat {
+            device: info.id,
+            credential: info.credential,
+            time: now,
+        })
+        .await;
+    if let Some(metadata) = metadata {
+        store_metadata(&state, info.id, now, metadata).await;
+    }
+    if let Ok(prev_beat) = written {
+        // the very first beat counts too, it just doesn't end an absence
+        let update_longest_absence = cluster::publish(
+            &state,
+            Change::Beat {
+                device: info.id,
+                credential: info.credential,
+                time: now,
+                previous: prev_beat,
+            },
+        );
+        if let Some(prev_beat) = prev_beat {
+            let diff = now - prev_beat;
+            if update_longest_absence {
+                let _ = state.storage.set_longest_absence(diff).await;
             }
-            w.last_seen = Some(now);
-            if let Some(x) = w.devices.iter_mut().find(|x| x.id == info.id) {
-                x.last_beat = Some(now);
-                x.num_beats += 1;
+            if diff >= ABSENCE_THRESHOLD {
+                absence_ended(&state, info.id, prev_beat, now, update_longest_absence).await;
             }
-            w.total_beats += 1;
-            ret
-        };
-        if update_longest_absence {
-            let pg_diff = PgInterval::try_from(chrono::Duration::microseconds(
-                diff.num_microseconds().unwrap_or(i64::MAX - 1),
-            ))
-            .expect("We have travelled way too far into the future.");
-            let _ = sqlx::query!(
-                "UPDATE heartbeat.stats SET longest_absence = $1 WHERE _id = 0;",
-                pg_diff
-            )
-            .execute(&state.pool)
-            .await;
-        }
-        if diff.num_hours() >= 1 {
-            fire_webhook(
-                state.clone(),
-                "Absence longer than 1 hour",
-                &format!("From <t:{}> to <t:{}>", record.time_stamp.timestamp(), now.timestamp()),
-                WebhookLevel::LongAbsences,
-            )
-            .await;
         }
     }
+    #[cfg(feature = "mqtt")]
+    mqtt::publish(
+        &state,
+        &MqttEvent::Beat {
+            device: info.id,
+            name: info.name.clone(),
+            time: now,
+        },
+    );
     let name = info.name.unwrap_or_else(|| format!("<unknown> ({})", info.id));
     info!(id = %info.id, "Successful beat from device {name}");
     fire_webhook(
@@ -127,17 +189,122 @@ pub async fn handle_beat_req(State(state): State<AppState>, info: DeviceAuth) ->
     (StatusCode::OK, format!("{}", now.timestamp()))
 }
 
-fn get_stats(state: &AppState) -> impl Serialize {
-    #[derive(Serialize)]
-    struct StatsResp {
-        last_seen: Option<i64>,
-        last_seen_relative: i64,
-        longest_absence: i64,
-        num_visits: i64,
-        total_beats: i64,
-        devices: Vec<Device>,
-        uptime: i64,
+async fn store_metadata(state: &AppState, device: i64, now: DateTime<Utc>, metadata: Metadata) {
+    if let Err(e) = state.storage.store_metadata(device, now, &metadata).await {
+        error!("Failed to store device metadata: {e:?}");
+    }
+    cluster::publish(state, Change::Metadata { device, metadata });
+}
+
+/// The beats of a batch, sorted and deduplicated, and those that were skipped.
+struct ParsedBatch {
+    time_stamps: Vec<DateTime<Utc>>,
+    too_old: Vec<i64>,
+    future: Vec<i64>,
+}
+
+/// Validates the timestamps of a batch, skipping those that are too old or in
+/// the future.
+fn parse_batch(
+    state: &AppState,
+    method: &Method,
+    uri: &Uri,
+    now: DateTime<Utc>,
+    beats: &[i64],
+) -> Result<ParsedBatch, Error> {
+    let bad_request = |problem| {
+        Error::new(uri.path(), method, StatusCode::BAD_REQUEST, &state.config.server_name).with_problem(problem)
+    };
+    if beats.len() > MAX_BATCH_SIZE {
+        return Err(bad_request(Problem::BatchTooLarge));
+    }
+    let oldest = now - chrono::Duration::from_std(state.config.max_backfill_age).unwrap_or(chrono::TimeDelta::MAX);
+    let mut batch = ParsedBatch {
+        time_stamps: Vec::with_capacity(beats.len()),
+        too_old: Vec::new(),
+        future: Vec::new(),
+    };
+    for &beat in beats {
+        match DateTime::from_timestamp(beat, 0) {
+            Some(ts) if ts > now => batch.future.push(beat),
+            Some(ts) if ts < oldest => batch.too_old.push(beat),
+            Some(ts) => batch.time_stamps.push(ts),
+            None => return Err(bad_request(Problem::InvalidTimestamp)),
+        }
     }
+    batch.time_stamps.sort_unstable();
+    batch.time_stamps.dedup();
+    Ok(batch)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/beats/batch",
+    tag = "beats",
+    request_body = PostBeats,
+    responses(
+        (status = 200, body = BatchResp),
+        (status = 400, description = "Too many beats, or a timestamp out of range.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid token, or a missing or invalid signature.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("token" = [])),
+)]
+#[axum::debug_handler]
+pub async fn handle_batch_beat_req(
+    State(state): State<AppState>,
+    info: DeviceAuth,
+    method: Method,
+    uri: Uri,
+    Json(batch): Json<PostBeats>,
+) -> Result<Json<BatchResp>, Error> {
+    let now = Utc::now();
+    let ParsedBatch {
+        time_stamps,
+        too_old,
+        future,
+    } = parse_batch(&state, &method, &uri, now, &batch.beats)?;
+    let internal_error = |e| {
+        error!("Failed to insert batch of beats: {e:?}");
+        Error::new(
+            uri.path(),
+            &method,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &state.config.server_name,
+        )
+    };
+    let Batch {
+        inserted,
+        latest,
+        longest_absence,
+    } = state
+        .storage
+        .insert_beats(info.id, info.credential, &time_stamps)
+        .await
+        .map_err(internal_error)?;
+    cluster::publish(
+        &state,
+        Change::Batch {
+            device: info.id,
+            credential: info.credential,
+            inserted,
+            latest,
+            longest_absence: longest_absence.num_seconds(),
+            time: now,
+        },
+    );
+    let inserted = inserted.unsigned_abs();
+    let name = info.name.unwrap_or_else(|| format!("<unknown> ({})", info.id));
+    info!(id = %info.id, inserted, too_old = too_old.len(), future = future.len(), "Backfilled beats from device {name}");
+    Ok(Json(BatchResp {
+        inserted,
+        duplicates: (batch.beats.len() - too_old.len() - future.len()) as u64 - inserted,
+        too_old,
+        future,
+        longest_absence: longest_absence.num_seconds(),
+    }))
+}
+
+pub fn get_stats(state: &AppState) -> StatsResp {
     let r = state.stats.lock().clone();
     let last_seen_relative = (Utc::now() - r.last_seen.unwrap_or_else(|| UNIX_EPOCH.into())).num_seconds();
     StatsResp {
@@ -150,16 +317,27 @@ fn get_stats(state: &AppState) -> impl Serialize {
         }),
         num_visits: r.num_visits,
         total_beats: r.total_beats,
-        devices: r.devices.as_slice().to_vec(),
+        devices: r
+            .devices
+            .iter()
+            .map(|d| d.public(&state.config.public_metadata))
+            .collect(),
         uptime: (Utc::now() - state.server_start_time).num_seconds(),
     }
 }
 
+#[utoipa::path(get, path = "/api/v1/stats", tag = "stats", responses((status = 200, body = StatsResp)))]
 #[axum::debug_handler]
-pub async fn get_stats_(State(stats): State<AppState>) -> Json<impl Serialize> {
+pub async fn get_stats_(State(stats): State<AppState>) -> Json<StatsResp> {
     Json(get_stats(&stats))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/stats/ws",
+    tag = "stats",
+    responses((status = 101, description = "A WebSocket that sends the same body as `GET /api/v1/stats` every second.")),
+)]
 #[axum::debug_handler]
 pub async fn realtime_stats(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
     ws.on_upgrade(|ws| async { stream_stats(state, ws).await })
@@ -175,74 +353,240 @@ async fn stream_stats(app_state: AppState, mut ws: WebSocket) {
     }
 }
 
-#[derive(Serialize)]
-pub struct DeviceAddResp {
-    id: i64,
-    name: Option<String>,
-    token: String,
+#[utoipa::path(
+    get,
+    path = "/api/v1/beat/ws",
+    tag = "beats",
+    responses(
+        (status = 101, description = "A WebSocket that records beats while it is open. Send `idle`, `active`, or metadata as JSON."),
+        (status = 401, description = "Invalid token, or a missing or invalid signature.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("token" = [])),
+)]
+#[axum::debug_handler]
+pub async fn presence_ws(ws: WebSocketUpgrade, State(state): State<AppState>, info: DeviceAuth) -> Response {
+    ws.max_message_size(MAX_METADATA_SIZE)
+        .on_upgrade(|ws| async { track_presence(state, info, ws).await })
+}
+
+/// Records beats while a device is connected.
+///
+/// A beat is recorded on connection, every `presence_interval` and on every
+/// message from the client. Clients may send `idle` to pause the periodic
+/// beats, `active` to resume them, or a JSON object with metadata.
+async fn track_presence(state: AppState, info: DeviceAuth, mut ws: WebSocket) {
+    let session = *state
+        .presence
+        .lock()
+        .entry(info.id)
+        .and_modify(|session| *session += 1)
+        .or_insert(1);
+    record_beat(state.clone(), info.clone(), None).await;
+    let mut idle = false;
+    let mut interval = tokio::time::interval(state.config.presence_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.reset();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if !idle {
+                    record_beat(state.clone(), info.clone(), None).await;
+                }
+            }
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let metadata = match text.trim() {
+                        "idle" => {
+                            idle = true;
+                            None
+                        }
+                        "active" => {
+                            idle = false;
+                            None
+                        }
+                        text => match serde_json::from_str::<Metadata>(text) {
+                            Ok(metadata) if metadata.validate().is_ok() => {
+                                idle = false;
+                                Some(metadata)
+                            }
+                            _ => {
+                                debug!(id = %info.id, "Ignoring invalid presence message");
+                                continue;
+                            }
+                        },
+                    };
+                    interval.reset();
+                    record_beat(state.clone(), info.clone(), metadata).await;
+                }
+                // a clean close means the device is going away now, rather
+                // than whenever the connection happened to be noticed as dead
+                Some(Ok(Message::Close(_))) => {
+                    if !idle {
+                        record_beat(state.clone(), info.clone(), None).await;
+                    }
+                    break;
+                }
+                Some(Ok(_)) => {}
+                // the device may be back in a moment, so it still counts as
+                // present until the hysteresis has passed
+                Some(Err(_)) | None => {
+                    if !idle {
+                        tokio::spawn(end_presence(state, info, session));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Records the final beat of a dropped presence connection once
+/// `presence_hysteresis` has passed, unless the device has reconnected since.
+async fn end_presence(state: AppState, info: DeviceAuth, session: u64) {
+    tokio::time::sleep(state.config.presence_hysteresis).await;
+    let reconnected = state.presence.lock().get(&info.id) != Some(&session);
+    if !reconnected {
+        record_beat(state, info, None).await;
+    }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/devices/{device_id}",
+    tag = "devices",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    responses(
+        (status = 200, description = "The device, including its probe and all of its metadata.", body = Device),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The device doesn't exist.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
+#[axum::debug_handler]
+pub async fn get_device(
+    _: MasterAuth,
+    State(state): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: Method,
+    uri: Uri,
+) -> Result<Json<Device>, Error> {
+    let device = state.stats.lock().devices.iter().find(|d| d.id == device_id).cloned();
+    device
+        .map(Json)
+        .ok_or_else(|| Error::new(uri.path(), &method, StatusCode::NOT_FOUND, &state.config.server_name))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/devices",
+    tag = "devices",
+    request_body = PostDevice,
+    responses(
+        (status = 200, body = DeviceAddResp),
+        (status = 400, description = "Invalid public key or probe.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
 #[axum::debug_handler]
 pub async fn post_device(
     _: MasterAuth,
     State(state): State<AppState>,
+    method: Method,
+    uri: Uri,
     Json(device): Json<PostDevice>,
-) -> (StatusCode, Json<DeviceAddResp>) {
-    let id = SnowflakeGenerator::default().generate();
-    let res = match sqlx::query!(
-        r"INSERT INTO heartbeat.devices (id, name, token) VALUES ($1, $2, $3) RETURNING *;",
-        i64::try_from(id.id()).expect("snowflake out of i64 range. Is it 2089 already?"),
-        device.name,
-        generate_token(id),
-    )
-    .fetch_one(&state.pool)
-    .await
-    {
-        Ok(record) => record,
-        Err(e) => {
-            error!("Failed to insert new device into database: {e:?}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(DeviceAddResp {
-                    id: -1,
-                    name: None,
-                    token: String::new(),
-                }),
-            );
-        }
+) -> Result<Json<DeviceAddResp>, Error> {
+    let bad_request = |problem| {
+        Error::new(uri.path(), &method, StatusCode::BAD_REQUEST, &state.config.server_name).with_problem(problem)
     };
-    {
-        let mut w = state.stats.lock();
-        w.devices.push(Device {
-            id: res.id,
-            name: res.name.clone(),
+    if let Some(probe) = &device.probe {
+        probe.validate().map_err(bad_request)?;
+    }
+    let public_key = match device.public_key.as_deref().map(signature::parse_public_key) {
+        None => None,
+        Some(Some(key)) => Some(key),
+        Some(None) => return Err(bad_request(Problem::InvalidPublicKey)),
+    };
+    let snowflake = SnowflakeGenerator::default().generate();
+    let id = i64::try_from(snowflake.id()).expect("snowflake out of i64 range. Is it 2089 already?");
+    let token = generate_token(snowflake);
+    let signing_secret = device.signed_beats.then(signature::generate_secret);
+    let new_device = NewDevice {
+        id,
+        name: &device.name,
+        token: &token,
+        signing_secret: signing_secret.as_deref(),
+        public_key: public_key.as_deref(),
+        probe: device.probe.as_ref(),
+    };
+    if let Err(e) = state.storage.add_device(new_device).await {
+        error!("Failed to insert new device into database: {e:?}");
+        return Err(Error::new(
+            uri.path(),
+            &method,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &state.config.server_name,
+        ));
+    }
+    cluster::publish(
+        &state,
+        Change::Device(Device {
+            id,
+            name: Some(device.name.clone()),
             last_beat: None,
             num_beats: 0,
-        });
-    }
+            last_credential: None,
+            metadata: None,
+            probe: device.probe.clone(),
+        }),
+    );
     fire_webhook(
         state,
         "New Device added",
         &format!(
             "A new device called `{}` was added on <t:{}:D> at <t:{}:T>",
             device.name,
-            id.created_at().timestamp(),
-            id.created_at().timestamp()
+            snowflake.created_at().timestamp(),
+            snowflake.created_at().timestamp()
         ),
         WebhookLevel::NewDevices,
     )
     .await;
 
-    (
-        StatusCode::OK,
-        Json(DeviceAddResp {
-            id: res.id,
-            name: res.name,
-            token: res.token,
-        }),
-    )
+    Ok(Json(DeviceAddResp {
+        id,
+        name: Some(device.name),
+        token,
+        signing_secret,
+    }))
+}
+
+fn token_rotated_message(name: &str, now: DateTime<Utc>, previous_token_expires_at: Option<DateTime<Utc>>) -> String {
+    let rotated = format!(
+        "The token for `{name}` was rotated on <t:{0}:D> at <t:{0}:T>",
+        now.timestamp()
+    );
+    match previous_token_expires_at {
+        Some(expires_at) => format!(
+            "{rotated}. The previous token remains valid until <t:{}:f>",
+            expires_at.timestamp()
+        ),
+        None => rotated,
+    }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/devices/{device_id}/token/generate",
+    tag = "devices",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    responses(
+        (status = 200, body = DeviceUpdateResp),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The device doesn't exist.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
 #[axum::debug_handler]
 pub async fn regenerate_device_token(
     _: MasterAuth,
@@ -250,55 +594,217 @@ pub async fn regenerate_device_token(
     Path(device_id): Path<i64>,
     method: axum::http::Method,
     uri: axum::http::Uri,
-) -> Result<Json<impl Serialize>, Error> {
-    #[derive(Serialize)]
-    struct DeviceUpdateResp {
-        id: i64,
-        name: Option<String>,
-        token: String,
+) -> Result<Json<DeviceUpdateResp>, Error> {
+    let now = Utc::now();
+    // a zero grace period means the previous token is revoked immediately.
+    let previous_token_expires_at = (!state.config.token_grace_period.is_zero()).then(|| {
+        chrono::Duration::from_std(state.config.token_grace_period)
+            .ok()
+            .and_then(|grace| now.checked_add_signed(grace))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    });
+    let token = generate_token(Snowflake::from(device_id));
+    let res = state
+        .storage
+        .rotate_token(device_id, &token, now, previous_token_expires_at)
+        .await
+        .map_err(|e| {
+            error!("Failed to rotate device token: {e:?}");
+            Error::new(
+                uri.path(),
+                &method,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &state.config.server_name,
+            )
+        })?
+        .ok_or_else(|| Error::new(uri.path(), &method, StatusCode::NOT_FOUND, &state.config.server_name))?;
+    let name = res.name.clone().unwrap_or_else(|| format!("<unknown> ({})", res.id));
+    info!(event = "token_rotated", id = %res.id, "Rotated token for device {name}");
+    if let Err(e) = events::record_token_rotation(&*state.storage, res.id, now).await {
+        error!("Failed to record token rotation: {e:?}");
     }
-
-    let found = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM heartbeat.devices WHERE id = $1);",
-        device_id
+    fire_webhook(
+        state,
+        "Device token rotated",
+        &token_rotated_message(&name, now, previous_token_expires_at),
+        WebhookLevel::NewDevices,
     )
-    .fetch_one(&state.pool)
-    .await
-    .map_err(|e| {
-        error!("Failed to check if device exists: {e:?}");
-        Error::new(
-            uri.path(),
-            &method,
-            StatusCode::INTERNAL_SERVER_ERROR,
-            &state.config.server_name,
-        )
-    })?
-    .unwrap_or(false);
-    if !found {
-        return Err(Error::new(
-            uri.path(),
-            &method,
-            StatusCode::NOT_FOUND,
-            &state.config.server_name,
-        ));
-    }
-    let token = generate_token(Snowflake::from(device_id));
-    let res = sqlx::query_as!(
-        DeviceUpdateResp,
-        "UPDATE heartbeat.devices SET token = $1 WHERE id = $2 RETURNING id, name, token;",
+    .await;
+    Ok(Json(DeviceUpdateResp {
+        id: res.id,
+        name: res.name,
         token,
-        device_id
-    )
-    .fetch_one(&state.pool)
-    .await
-    .map_err(|e| {
-        error!("Failed to update device token: {e:?}");
-        Error::new(
-            uri.path(),
-            &method,
-            StatusCode::INTERNAL_SERVER_ERROR,
-            &state.config.server_name,
-        )
+        previous_token_expires_at,
+    }))
+}
+
+async fn set_signing_secret(
+    state: &AppState,
+    device_id: i64,
+    signing_secret: Option<String>,
+    method: &axum::http::Method,
+    uri: &axum::http::Uri,
+) -> Result<Json<SigningSecretResp>, Error> {
+    state
+        .storage
+        .set_signing_secret(device_id, signing_secret.as_deref())
+        .await
+        .map_err(|e| {
+            error!("Failed to update device signing secret: {e:?}");
+            Error::new(
+                uri.path(),
+                method,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &state.config.server_name,
+            )
+        })?
+        .map(|device| {
+            Json(SigningSecretResp {
+                id: device.id,
+                name: device.name,
+                signing_secret,
+            })
+        })
+        .ok_or_else(|| Error::new(uri.path(), method, StatusCode::NOT_FOUND, &state.config.server_name))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/devices/{device_id}/secret/generate",
+    tag = "devices",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    responses(
+        (status = 200, body = SigningSecretResp),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The device doesn't exist.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
+#[axum::debug_handler]
+pub async fn regenerate_signing_secret(
+    _: MasterAuth,
+    State(state): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+) -> Result<Json<SigningSecretResp>, Error> {
+    let res = set_signing_secret(&state, device_id, Some(signature::generate_secret()), &method, &uri).await?;
+    info!(id = %device_id, "Generated signing secret for device");
+    Ok(res)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/devices/{device_id}/secret",
+    tag = "devices",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    responses(
+        (status = 200, body = SigningSecretResp),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The device doesn't exist.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
+#[axum::debug_handler]
+pub async fn delete_signing_secret(
+    _: MasterAuth,
+    State(state): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+) -> Result<Json<SigningSecretResp>, Error> {
+    let res = set_signing_secret(&state, device_id, None, &method, &uri).await?;
+    info!(id = %device_id, "Removed signing secret for device");
+    Ok(res)
+}
+
+async fn set_probe(
+    state: &AppState,
+    device_id: i64,
+    probe: Option<Probe>,
+    method: &axum::http::Method,
+    uri: &axum::http::Uri,
+) -> Result<Json<ProbeResp>, Error> {
+    let res = state
+        .storage
+        .set_probe(device_id, probe.as_ref())
+        .await
+        .map_err(|e| {
+            error!("Failed to update device probe: {e:?}");
+            Error::new(
+                uri.path(),
+                method,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &state.config.server_name,
+            )
+        })?
+        .ok_or_else(|| Error::new(uri.path(), method, StatusCode::NOT_FOUND, &state.config.server_name))?;
+    cluster::publish(
+        state,
+        Change::Probe {
+            device: device_id,
+            probe: probe.clone(),
+        },
+    );
+    Ok(Json(ProbeResp {
+        id: res.id,
+        name: res.name,
+        probe,
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/devices/{device_id}/probe",
+    tag = "devices",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    request_body = Probe,
+    responses(
+        (status = 200, body = ProbeResp),
+        (status = 400, description = "Invalid probe.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The device doesn't exist.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
+#[axum::debug_handler]
+pub async fn put_probe(
+    _: MasterAuth,
+    State(state): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+    Json(probe): Json<Probe>,
+) -> Result<Json<ProbeResp>, Error> {
+    probe.validate().map_err(|problem| {
+        Error::new(uri.path(), &method, StatusCode::BAD_REQUEST, &state.config.server_name).with_problem(problem)
     })?;
-    Ok(Json(res))
+    let res = set_probe(&state, device_id, Some(probe), &method, &uri).await?;
+    info!(id = %device_id, "Set probe for device");
+    Ok(res)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/devices/{device_id}/probe",
+    tag = "devices",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    responses(
+        (status = 200, body = ProbeResp),
+        (status = 401, description = "Invalid secret key.", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "The device doesn't exist.", body = ProblemDetails, content_type = "application/problem+json"),
+    ),
+    security(("secret_key" = [])),
+)]
+#[axum::debug_handler]
+pub async fn delete_probe(
+    _: MasterAuth,
+    State(state): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+) -> Result<Json<ProbeResp>, Error> {
+    let res = set_probe(&state, device_id, None, &method, &uri).await?;
+    info!(id = %device_id, "Removed probe for device");
+    Ok(res)
 }
@@ -6,7 +6,7 @@
 
 use crate::{
     util::{formats::FormatNum, hf_time::HumanTime},
-    AppState, PoolExt,
+    AppState,
 };
 use axum::{
     extract::State,
@@ -69,18 +69,15 @@ impl IntoResponse for BadgeResponse {
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/badge/last-seen",
+    tag = "badges",
+    responses((status = 200, description = "A badge with the time since the last beat.", content_type = "image/svg+xml")),
+)]
 #[axum::debug_handler]
-pub async fn last_seen(State(AppState { stats, pool, .. }): State<AppState>) -> BadgeResponse {
-    let last_seen = sqlx::query_scalar!(
-        r"
-        SELECT
-            MAX(time_stamp) AS last_seen
-        FROM heartbeat.beats;
-        "
-    )
-    .fetch_one(&pool)
-    .await
-    .unwrap_or_default();
+pub async fn last_seen(State(state): State<AppState>) -> BadgeResponse {
+    let last_seen = state.reader().last_seen().await.unwrap_or_default();
     let message = last_seen.map_or_else(
         || "never".to_string(),
         |last_seen| {
@@ -88,25 +85,22 @@ pub async fn last_seen(State(AppState { stats, pool, .. }): State<AppState>) ->
             format!("{:#}", HumanTime::from(diff))
         },
     );
-    tokio::spawn(async move {
-        stats.lock().num_visits += 1;
-        let _ = pool.incr_visits().await;
-    });
+    state.stats.lock().num_visits += 1;
+    state.writer.visit();
     BadgeResponse::new("Last Online", &message, BLUE_MAGENTA)
 }
 
+#[utoipa::path(
+    get,
+    path = "/badge/total-beats",
+    tag = "badges",
+    responses((status = 200, description = "A badge with the total number of beats.", content_type = "image/svg+xml")),
+)]
 #[axum::debug_handler]
-pub async fn total_beats(State(AppState { stats, pool, .. }): State<AppState>) -> BadgeResponse {
-    let total_beats = sqlx::query_scalar!("SELECT SUM(num_beats)::BIGINT AS total_beats FROM heartbeat.devices;")
-        .fetch_one(&pool)
-        .await
-        .unwrap_or_default()
-        .unwrap_or_default();
-
-    tokio::spawn(async move {
-        stats.lock().num_visits += 1;
-        let _ = pool.incr_visits().await;
-    });
+pub async fn total_beats(State(state): State<AppState>) -> BadgeResponse {
+    let total_beats = state.reader().total_beats().await.unwrap_or_default();
+    state.stats.lock().num_visits += 1;
+    state.writer.visit();
     BadgeResponse::new("Total Beats", total_beats.format().as_str(), Colour::CORNFLOWER_BLUE)
 }
 
@@ -0,0 +1,268 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{feeds::host, pages::query_flag};
+use crate::{
+    error::Error,
+    events::ABSENCE_THRESHOLD,
+    storage::Span,
+    util::{formats::format_relative, Snowflake},
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header::CONTENT_TYPE, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Days, NaiveTime, TimeDelta, Utc};
+use tracing::error;
+
+/// How far back the calendar goes.
+const CALENDAR_DAYS: i64 = 90;
+
+/// The sequence numbers of the UIDs of each kind of event.
+const ABSENCE_SEQ: u64 = 1;
+const BUSY_SEQ: u64 = 2;
+
+/// Splits a span at each midnight in it.
+fn split_days((mut start, end): Span) -> Vec<Span> {
+    let mut days = vec![];
+    while let Some(midnight) = start
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .map(|day| day.and_time(NaiveTime::MIN).and_utc())
+        .filter(|midnight| *midnight < end)
+    {
+        days.push((start, midnight));
+        start = midnight;
+    }
+    days.push((start, end));
+    days
+}
+
+/// Escapes a text value.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Writes a content line, folded at 75 octets.
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn format_time(time: DateTime<Utc>) -> impl std::fmt::Display {
+    time.format("%Y%m%dT%H%M%SZ")
+}
+
+struct Calendar {
+    out: String,
+    /// The host and device of the UIDs.
+    uid_suffix: String,
+    now: DateTime<Utc>,
+}
+
+impl Calendar {
+    fn new(name: &str, host: &str, device: Option<i64>) -> Self {
+        let mut out = String::new();
+        line(&mut out, "BEGIN:VCALENDAR");
+        line(&mut out, "VERSION:2.0");
+        line(&mut out, "PRODID:-//Heartbeat//Heartbeat//EN");
+        line(&mut out, "CALSCALE:GREGORIAN");
+        line(&mut out, &format!("X-WR-CALNAME:{}", escape(name)));
+        line(&mut out, "X-WR-TIMEZONE:UTC");
+        let uid_suffix = device.map_or_else(|| format!("@{host}"), |device| format!("-{device}@{host}"));
+        Self {
+            out,
+            uid_suffix,
+            now: Utc::now(),
+        }
+    }
+
+    fn event(&mut self, (start, end): Span, seq: u64, summary: &str, transparent: bool) {
+        let out = &mut self.out;
+        line(out, "BEGIN:VEVENT");
+        line(out, &format!("UID:{}{}", Snowflake::at(start, seq), self.uid_suffix));
+        line(out, &format!("DTSTAMP:{}", format_time(self.now)));
+        line(out, &format!("DTSTART:{}", format_time(start)));
+        line(out, &format!("DTEND:{}", format_time(end)));
+        line(out, &format!("SUMMARY:{}", escape(summary)));
+        line(
+            out,
+            if transparent {
+                "TRANSP:TRANSPARENT"
+            } else {
+                "TRANSP:OPAQUE"
+            },
+        );
+        line(out, "END:VEVENT");
+    }
+
+    fn finish(mut self) -> String {
+        line(&mut self.out, "END:VCALENDAR");
+        self.out
+    }
+}
+
+/// Renders the absences, and optionally the active days, of `device` or of all
+/// devices.
+async fn calendar(
+    state: &AppState,
+    device: Option<i64>,
+    last_beat: Option<DateTime<Utc>>,
+    name: &str,
+    busy: bool,
+) -> sqlx::Result<String> {
+    let since = Utc::now() - TimeDelta::days(CALENDAR_DAYS);
+    let reader = state.reader();
+    let mut absences = reader.absences(device, since, ABSENCE_THRESHOLD).await?;
+    let mut calendar = Calendar::new(name, host(&state.config.live_url), device);
+    // the current absence, which has lasted until now so far
+    if let Some(last_beat) = last_beat.filter(|last_beat| calendar.now - *last_beat >= ABSENCE_THRESHOLD) {
+        absences.push((last_beat, calendar.now));
+    }
+    for absence in absences {
+        let summary = format!("Away ({})", format_relative(absence.1 - absence.0));
+        calendar.event(absence, ABSENCE_SEQ, &summary, true);
+    }
+    if busy {
+        let spans = reader.active_spans(device, since, ABSENCE_THRESHOLD).await?;
+        for day in spans.into_iter().flat_map(split_days) {
+            calendar.event(day, BUSY_SEQ, "Busy", false);
+        }
+    }
+    Ok(calendar.finish())
+}
+
+fn respond(body: String) -> Response {
+    let headers = [(CONTENT_TYPE, HeaderValue::from_static("text/calendar; charset=utf-8"))];
+    (headers, body).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/calendar.ics",
+    tag = "feeds",
+    params(("busy" = Option<bool>, Query, description = "Also includes the days' active spans, as busy blocks.")),
+    responses((status = 200, description = "An iCalendar of the absences of the last 90 days.", body = String, content_type = "text/calendar")),
+)]
+#[axum::debug_handler]
+pub async fn server_calendar(State(state): State<AppState>, method: Method, uri: Uri) -> Result<Response, Error> {
+    let last_seen = state.stats.lock().last_seen;
+    let name = state.config.server_name.clone();
+    calendar(&state, None, last_seen, &name, query_flag(&uri, &["busy"]))
+        .await
+        .map(respond)
+        .map_err(|e| {
+            error!("Failed to build calendar: {e:?}");
+            Error::new(
+                uri.path(),
+                &method,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &state.config.server_name,
+            )
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/devices/{device_id}/calendar.ics",
+    tag = "feeds",
+    params(
+        ("device_id" = i64, Path, description = "The ID of the device."),
+        ("busy" = Option<bool>, Query, description = "Also includes the days' active spans, as busy blocks."),
+    ),
+    responses(
+        (status = 200, description = "An iCalendar of the device's absences of the last 90 days.", body = String, content_type = "text/calendar"),
+        (status = 404, description = "The device doesn't exist.", content_type = "text/html"),
+    ),
+)]
+#[axum::debug_handler]
+pub async fn device_calendar(
+    State(state): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: Method,
+    uri: Uri,
+) -> Result<Response, Error> {
+    let error = |status| Error::new(uri.path(), &method, status, &state.config.server_name);
+    let device = state.stats.lock().devices.iter().find(|d| d.id == device_id).cloned();
+    let device = device.ok_or_else(|| error(StatusCode::NOT_FOUND))?;
+    let name = format!(
+        "{} on {}",
+        device.name.as_deref().unwrap_or("Unnamed device"),
+        state.config.server_name
+    );
+    calendar(
+        &state,
+        Some(device_id),
+        device.last_beat,
+        &name,
+        query_flag(&uri, &["busy"]),
+    )
+    .await
+    .map(respond)
+    .map_err(|e| {
+        error!("Failed to build calendar: {e:?}");
+        error(StatusCode::INTERNAL_SERVER_ERROR)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+    }
+
+    #[test]
+    fn test_split_days() {
+        let time = |s: &str| s.parse::<DateTime<Utc>>().expect("valid timestamp");
+        let span = (time("2024-03-01T22:00:00Z"), time("2024-03-03T01:30:00Z"));
+        assert_eq!(
+            split_days(span),
+            [
+                (time("2024-03-01T22:00:00Z"), time("2024-03-02T00:00:00Z")),
+                (time("2024-03-02T00:00:00Z"), time("2024-03-03T00:00:00Z")),
+                (time("2024-03-03T00:00:00Z"), time("2024-03-03T01:30:00Z")),
+            ]
+        );
+        let span = (time("2024-03-01T09:00:00Z"), time("2024-03-01T17:00:00Z"));
+        assert_eq!(split_days(span), [span]);
+    }
+
+    #[test]
+    fn test_line_folding() {
+        let mut out = String::new();
+        line(&mut out, &"é".repeat(50));
+        let lines = out.split("\r\n").collect::<Vec<_>>();
+        assert!(lines.iter().all(|l| l.len() <= 75));
+        assert_eq!(lines[1..].iter().filter(|l| !l.is_empty()).count(), 1);
+        assert!(lines[1].starts_with(' '));
+        assert_eq!(out.replace("\r\n ", "").trim_end(), "é".repeat(50));
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    config::Config,
+    devices::{Device, Metadata},
+    error::Error,
+    events::{self, Event, Kind},
+    util::formats::format_relative,
+    AppState, VERSION,
+};
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+use tracing::error;
+
+/// The number of events in each feed.
+const FEED_LEN: usize = 50;
+
+/// An event, as it is shown in a feed.
+struct Entry {
+    id: String,
+    title: String,
+    content: String,
+    url: String,
+    time: DateTime<Utc>,
+}
+
+fn format_time(time: DateTime<Utc>) -> impl std::fmt::Display {
+    time.format("%d %B %Y %H:%M:%S UTC")
+}
+
+/// Describes the publicly visible metadata of a device.
+fn describe_metadata(metadata: &Metadata) -> String {
+    let mut out = String::new();
+    if let Some(status) = &metadata.status {
+        let _ = write!(out, " Status: {status}.");
+    }
+    if let Some(battery) = metadata.battery {
+        let _ = write!(out, " Battery: {battery}%.");
+    }
+    if let Some(hostname) = &metadata.hostname {
+        let _ = write!(out, " Hostname: {hostname}.");
+    }
+    if let Some(os) = &metadata.os {
+        let _ = write!(out, " OS: {os}.");
+    }
+    out
+}
+
+impl Entry {
+    fn new(event: &Event, config: &Config) -> Self {
+        let base = config.live_url.trim_end_matches('/');
+        let absence = event.absence.map(format_relative).unwrap_or_default();
+        let name = |device: &Device| device.name.clone().unwrap_or_else(|| "An unnamed device".into());
+        let (title, content) = match (event.kind, &event.device) {
+            (Kind::DeviceAdded, device) => {
+                let name = device.as_ref().map_or_else(|| "An unnamed device".into(), name);
+                (
+                    format!("{name} was added"),
+                    format!("{name} was added as a new device."),
+                )
+            }
+            (Kind::AbsenceStarted, _) => (
+                "Absence started".into(),
+                format!(
+                    "The last beat before an absence of {absence} was received at {}.",
+                    format_time(event.time)
+                ),
+            ),
+            (Kind::AbsenceEnded, Some(device)) => (
+                format!("Absence ended after {absence}"),
+                format!(
+                    "{} sent a beat after an absence of {absence}.{}",
+                    name(device),
+                    device.metadata.as_ref().map(describe_metadata).unwrap_or_default()
+                ),
+            ),
+            (Kind::AbsenceEnded, None) => (
+                format!("Absence ended after {absence}"),
+                format!("A beat was received after an absence of {absence}."),
+            ),
+            (Kind::LongestAbsence, _) => (
+                format!("New longest absence: {absence}"),
+                format!(
+                    "The absence that ended at {} is the longest so far, at {absence}.",
+                    format_time(event.time)
+                ),
+            ),
+            (Kind::ServerStarted, _) => (
+                "Server started".into(),
+                format!("{} was started on version {VERSION}.", config.server_name),
+            ),
+            (Kind::TokenRotated, device) => {
+                let name = device.as_ref().map_or_else(|| "An unnamed device".into(), name);
+                (
+                    format!("Token rotated for {name}"),
+                    format!("The token of {name} was rotated."),
+                )
+            }
+        };
+        let url = match (event.kind, &event.device) {
+            (Kind::DeviceAdded | Kind::AbsenceEnded | Kind::TokenRotated, Some(device)) => {
+                format!("{base}/devices/{}", device.id)
+            }
+            _ => format!("{base}/"),
+        };
+        Self {
+            id: event.id.to_string(),
+            title,
+            content,
+            url,
+            time: event.time,
+        }
+    }
+}
+
+async fn entries(state: &AppState, method: &Method, uri: &Uri) -> Result<Vec<Entry>, Error> {
+    let events = events::recent(state, FEED_LEN).await.map_err(|e| {
+        error!("Failed to fetch events: {e:?}");
+        Error::new(
+            uri.path(),
+            method,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &state.config.server_name,
+        )
+    })?;
+    Ok(events.iter().map(|event| Entry::new(event, state.config)).collect())
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The host of a URL, used in `tag:` URIs.
+pub fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    match authority.find(']') {
+        Some(end) if authority.starts_with('[') => &authority[..=end],
+        _ => authority.split(':').next().unwrap_or(authority),
+    }
+}
+
+fn atom(entries: &[Entry], config: &Config) -> String {
+    let base = config.live_url.trim_end_matches('/');
+    let server_name = escape(&config.server_name);
+    let updated = entries.first().map_or_else(Utc::now, |e| e.time);
+    // tag URIs are dated with the epoch of the snowflakes in them
+    let tag = format!("tag:{},2020:", escape(host(base)));
+    let mut out = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>{server_name}</title>
+  <id>{tag}feed</id>
+  <link rel="alternate" href="{base}/"/>
+  <link rel="self" href="{base}/feed.atom"/>
+  <updated>{}</updated>
+  <author><name>{server_name}</name></author>
+  <generator uri="{}" version="{VERSION}">Heartbeat</generator>
+"#,
+        rfc3339(updated),
+        escape(&config.repo),
+        base = escape(base),
+    );
+    for entry in entries {
+        let _ = write!(
+            out,
+            r#"  <entry>
+    <id>{tag}{}</id>
+    <title>{}</title>
+    <link rel="alternate" href="{}"/>
+    <published>{time}</published>
+    <updated>{time}</updated>
+    <content type="text">{}</content>
+  </entry>
+"#,
+            entry.id,
+            escape(&entry.title),
+            escape(&entry.url),
+            escape(&entry.content),
+            time = rfc3339(entry.time),
+        );
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'a str,
+    home_page_url: String,
+    feed_url: String,
+    authors: [JsonFeedAuthor<'a>; 1],
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    title: String,
+    content_text: String,
+    date_published: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/feed.atom",
+    tag = "feeds",
+    responses((status = 200, description = "An Atom feed of recent events.", body = String, content_type = "application/atom+xml")),
+)]
+#[axum::debug_handler]
+pub async fn atom_feed(State(state): State<AppState>, method: Method, uri: Uri) -> Result<Response, Error> {
+    let entries = entries(&state, &method, &uri).await?;
+    let headers = [(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/atom+xml; charset=utf-8"),
+    )];
+    Ok((headers, atom(&entries, state.config)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/feed.json",
+    tag = "feeds",
+    responses((status = 200, description = "A JSON Feed of recent events.", body = String, content_type = "application/feed+json")),
+)]
+#[axum::debug_handler]
+pub async fn json_feed(State(state): State<AppState>, method: Method, uri: Uri) -> Result<Response, Error> {
+    let base = state.config.live_url.trim_end_matches('/');
+    let items = entries(&state, &method, &uri)
+        .await?
+        .into_iter()
+        .map(|entry| JsonFeedItem {
+            id: entry.id,
+            url: entry.url,
+            title: entry.title,
+            content_text: entry.content,
+            date_published: rfc3339(entry.time),
+        })
+        .collect();
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: &state.config.server_name,
+        home_page_url: format!("{base}/"),
+        feed_url: format!("{base}/feed.json"),
+        authors: [JsonFeedAuthor {
+            name: &state.config.server_name,
+        }],
+        items,
+    };
+    let headers = [(CONTENT_TYPE, HeaderValue::from_static("application/feed+json"))];
+    Ok((headers, Json(feed)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("https://hb.5ht2.me"), "hb.5ht2.me");
+        assert_eq!(host("http://127.0.0.1:6060/"), "127.0.0.1");
+        assert_eq!(host("http://[::1]:6060"), "[::1]");
+        assert_eq!(host("example.com/path"), "example.com");
+    }
+}
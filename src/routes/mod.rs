@@ -13,27 +13,219 @@
 
 //! Router utilities
 
-use crate::{config::Config, AppState};
-use api::{get_stats_, handle_beat_req, post_device, realtime_stats, regenerate_device_token};
+use crate::{config::Config, devices::MAX_METADATA_SIZE, AppState};
+pub(crate) use api::record_beat;
+use api::{
+    delete_probe, delete_signing_secret, get_device, get_stats_, handle_batch_beat_req, handle_beat_req,
+    handle_key_beat_req, post_device, presence_ws, put_probe, realtime_stats, regenerate_device_token,
+    regenerate_signing_secret,
+};
 use axum::{
-    routing::{get, post},
+    extract::{DefaultBodyLimit, State},
+    handler::Handler,
+    http::header::CONTENT_TYPE,
+    response::IntoResponse,
+    routing::{get, on, MethodFilter, MethodRouter},
     Router,
 };
 #[cfg(feature = "badges")]
 use badge_routes::{last_seen, total_beats};
-use pages::{index_page, privacy_page, stats_page};
+use calendar::{device_calendar, server_calendar};
+use feeds::{atom_feed, json_feed};
+pub use heartbeat_api::routes::{BatchResp, DeviceAddResp, DeviceUpdateResp, ProbeResp, SigningSecretResp, StatsResp};
+use openapi::openapi_json;
+use pages::{device_page, index_page, privacy_page, stats_page};
+use std::fmt::Write;
+use utoipa::{
+    __dev::{SchemaReferences, Tags},
+    openapi::{
+        path::{HttpMethod, Operation},
+        schema::Schema,
+        RefOr,
+    },
+};
 
 mod api;
 mod assets;
 #[cfg(feature = "badges")]
 #[path = "badges.rs"]
 mod badge_routes;
+mod calendar;
+mod feeds;
+mod openapi;
 mod pages;
 
+/// The prefix of the current version of the API.
+pub const API_PREFIX: &str = "/api/v1";
+
+#[utoipa::path(
+    get,
+    path = "/.well-known/health",
+    tag = "meta",
+    responses((status = 200, description = "The server is up.", body = String, content_type = "text/plain", example = "OK")),
+)]
 pub(crate) async fn health_check() -> &'static str {
     "OK"
 }
 
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "meta",
+    responses((status = 200, description = "Metrics in the Prometheus text format.", body = String, content_type = "text/plain")),
+)]
+#[axum::debug_handler]
+pub(crate) async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = String::new();
+    let drift = *state.drift.lock();
+    if let Some(drift) = drift {
+        body.push_str(
+            "# HELP heartbeat_stats_drift How far the stats in memory were from the database when they were last \
+             reconciled.\n# TYPE heartbeat_stats_drift gauge\n",
+        );
+        for (counter, value) in [
+            ("total_beats", drift.total_beats),
+            ("num_visits", drift.num_visits),
+            ("device_beats", drift.device_beats),
+            ("devices", drift.devices),
+        ] {
+            let _ = writeln!(body, "heartbeat_stats_drift{{counter=\"{counter}\"}} {value}");
+        }
+        let _ = writeln!(
+            body,
+            "# HELP heartbeat_stats_reconciled_timestamp_seconds When the stats were last reconciled.\n# TYPE \
+             heartbeat_stats_reconciled_timestamp_seconds gauge\nheartbeat_stats_reconciled_timestamp_seconds {}",
+            drift.at.timestamp()
+        );
+    }
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// A route of the server, along with the `OpenAPI` description of its
+/// handler.
+pub(crate) struct Route {
+    /// The path of the route, in `OpenAPI` syntax.
+    path: String,
+    methods: Vec<HttpMethod>,
+    handler: MethodRouter<AppState>,
+    operation: Operation,
+    schemas: Vec<(String, RefOr<Schema>)>,
+}
+
+impl Route {
+    /// Creates a route serving `handler` at the path and for the methods of
+    /// `doc`, the description generated for it by [`utoipa::path`].
+    fn new<D, H, T>(_doc: D, handler: H) -> Self
+    where
+        D: utoipa::Path + for<'t> Tags<'t> + SchemaReferences,
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        let methods = D::methods();
+        let filter = methods
+            .iter()
+            .map(|method| match method {
+                HttpMethod::Get => MethodFilter::GET,
+                HttpMethod::Post => MethodFilter::POST,
+                HttpMethod::Put => MethodFilter::PUT,
+                HttpMethod::Delete => MethodFilter::DELETE,
+                HttpMethod::Options => MethodFilter::OPTIONS,
+                HttpMethod::Head => MethodFilter::HEAD,
+                HttpMethod::Patch => MethodFilter::PATCH,
+                HttpMethod::Trace => MethodFilter::TRACE,
+            })
+            .reduce(MethodFilter::or)
+            .expect("handler to have a method");
+        let mut operation = D::operation();
+        operation
+            .tags
+            .get_or_insert_with(Vec::new)
+            .extend(D::tags().into_iter().map(str::to_owned));
+        let mut schemas = Vec::new();
+        D::schemas(&mut schemas);
+        Self {
+            path: D::path(),
+            methods,
+            handler: on(filter, handler),
+            operation,
+            schemas,
+        }
+    }
+
+    /// Limits the size of the request bodies accepted by the route.
+    fn body_limit(mut self, limit: usize) -> Self {
+        self.handler = self.handler.layer(DefaultBodyLimit::max(limit));
+        self
+    }
+
+    /// Adds the description of the route to `spec`.
+    pub(crate) fn document(&self, spec: &mut utoipa::openapi::OpenApi) {
+        spec.paths
+            .add_path_operation(&self.path, self.methods.clone(), self.operation.clone());
+        spec.components
+            .get_or_insert_with(Default::default)
+            .schemas
+            .extend(self.schemas.iter().cloned());
+    }
+
+    /// Returns the path of the route in the syntax of [`Router::route`].
+    fn router_path(&self) -> String {
+        self.path
+            .split('/')
+            .map(|segment| {
+                segment
+                    .strip_prefix('{')
+                    .and_then(|s| s.strip_suffix('}'))
+                    .map_or_else(|| segment.to_owned(), |param| format!(":{param}"))
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Returns every route determined by crate features, with the device
+/// management routes only if `with_devices` is set.
+pub(crate) fn routes(with_devices: bool) -> Vec<Route> {
+    let mut routes = vec![
+        Route::new(pages::__path_index_page, index_page),
+        Route::new(__path_health_check, health_check),
+        Route::new(__path_metrics, metrics),
+        Route::new(calendar::__path_server_calendar, server_calendar),
+        Route::new(pages::__path_device_page, device_page),
+        Route::new(calendar::__path_device_calendar, device_calendar),
+        Route::new(feeds::__path_atom_feed, atom_feed),
+        Route::new(feeds::__path_json_feed, json_feed),
+        Route::new(pages::__path_privacy_page, privacy_page),
+        Route::new(pages::__path_stats_page, stats_page),
+        Route::new(api::__path_handle_beat_req, handle_beat_req).body_limit(MAX_METADATA_SIZE),
+        Route::new(api::__path_handle_key_beat_req, handle_key_beat_req).body_limit(MAX_METADATA_SIZE),
+        Route::new(api::__path_presence_ws, presence_ws),
+        Route::new(api::__path_handle_batch_beat_req, handle_batch_beat_req),
+        Route::new(openapi::__path_openapi_json, openapi_json),
+        Route::new(api::__path_get_stats_, get_stats_),
+        Route::new(api::__path_realtime_stats, realtime_stats),
+    ];
+    if with_devices {
+        routes.extend([
+            Route::new(api::__path_post_device, post_device),
+            Route::new(api::__path_get_device, get_device),
+            Route::new(api::__path_regenerate_device_token, regenerate_device_token),
+            Route::new(api::__path_put_probe, put_probe),
+            Route::new(api::__path_delete_probe, delete_probe),
+            Route::new(api::__path_delete_signing_secret, delete_signing_secret),
+            Route::new(api::__path_regenerate_signing_secret, regenerate_signing_secret),
+        ]);
+    }
+    #[cfg(feature = "openapi-viewer")]
+    routes.push(Route::new(openapi::__path_docs_page, openapi::docs_page));
+    #[cfg(feature = "badges")]
+    routes.extend([
+        Route::new(badge_routes::__path_last_seen, last_seen),
+        Route::new(badge_routes::__path_total_beats, total_beats),
+    ]);
+    routes
+}
+
 /// Creates and returns a [`Router`] with only the routes determined by
 /// crate features and the provided [`Config`].
 pub fn router(config: &Config) -> Router<AppState> {
@@ -41,23 +233,14 @@ pub fn router(config: &Config) -> Router<AppState> {
 }
 
 fn __router(config: &Config) -> Router<AppState> {
-    let mut router = Router::new()
-        .route("/", get(index_page))
-        .route("/.well-known/health", get(health_check))
-        .route("/api/beat", post(handle_beat_req))
-        .route("/api/stats", get(get_stats_))
-        .route("/api/stats/ws", get(realtime_stats))
-        .route("/privacy", get(privacy_page))
-        .route("/stats", get(stats_page));
-    if !config.secret_key.is_empty() {
-        router = router
-            .route("/api/devices", post(post_device))
-            .route("/api/devices/:device_id/token/generate", post(regenerate_device_token));
+    let mut router = Router::new();
+    for route in routes(!config.secret_key.is_empty()) {
+        let path = route.router_path();
+        // the unversioned routes are kept as aliases of the first version
+        if let Some(path) = path.strip_prefix(API_PREFIX) {
+            router = router.route(&format!("/api{path}"), route.handler.clone());
+        }
+        router = router.route(&path, route.handler);
     }
-
-    #[cfg(not(feature = "badges"))]
-    return router;
     router
-        .route("/badge/last-seen", get(last_seen))
-        .route("/badge/total-beats", get(total_beats))
 }
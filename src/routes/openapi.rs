@@ -0,0 +1,162 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{config::Config, AppState, DEVICE_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use axum::{extract::State, Json};
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        OpenApi as Spec, Server,
+    },
+    Modify, OpenApi,
+};
+
+/// The parts of the spec that aren't described by the routes.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Heartbeat", description = "A service to keep a live heartbeat on multiple devices."),
+    modifiers(&SecuritySchemes),
+)]
+struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut Spec) {
+        let header = |description: String| {
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization".to_owned(),
+                description,
+            )))
+        };
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            header(format!(
+                "The device's token. If the device has a signing secret, the `{TIMESTAMP_HEADER}`, `{NONCE_HEADER}` \
+                 and `{SIGNATURE_HEADER}` headers are also required."
+            )),
+        );
+        components.add_security_scheme(
+            "key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                SIGNATURE_HEADER.to_owned(),
+                format!(
+                    "An Ed25519 signature made with the device's private key, along with the `{DEVICE_HEADER}`, \
+                     `{TIMESTAMP_HEADER}` and `{NONCE_HEADER}` headers."
+                ),
+            ))),
+        );
+        components.add_security_scheme("secret_key", header("The server's secret key.".into()));
+    }
+}
+
+/// Returns the `OpenAPI` description of the routes registered for `config`.
+pub fn spec(config: &Config) -> Spec {
+    let mut spec = document(!config.secret_key.is_empty());
+    spec.servers = Some(vec![Server::new(&config.live_url)]);
+    spec
+}
+
+fn document(with_devices: bool) -> Spec {
+    let mut spec = ApiDoc::openapi();
+    spec.info.version = env!("CARGO_PKG_VERSION").into();
+    for route in super::routes(with_devices) {
+        route.document(&mut spec);
+    }
+    spec
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/openapi.json",
+    tag = "meta",
+    responses((status = 200, description = "This document.", content_type = "application/json")),
+)]
+#[axum::debug_handler]
+pub async fn openapi_json(State(state): State<AppState>) -> Json<Spec> {
+    Json(spec(state.config))
+}
+
+#[cfg(feature = "openapi-viewer")]
+#[utoipa::path(
+    get,
+    path = "/api/v1/docs",
+    tag = "meta",
+    responses((status = 200, description = "This document, rendered as a page.", content_type = "text/html")),
+)]
+#[axum::debug_handler]
+pub async fn docs_page(State(state): State<AppState>) -> html::Markup {
+    let spec = serde_json::to_value(spec(state.config)).unwrap_or_default();
+    crate::templates::api_docs(&spec, state.config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_covers_router() {
+        for with_devices in [false, true] {
+            let spec = document(with_devices);
+            let routes = super::super::routes(with_devices);
+            let mut operations = 0;
+            for route in &routes {
+                for method in &route.methods {
+                    assert!(
+                        spec.paths.get_path_operation(&route.path, method.clone()).is_some(),
+                        "{} ({}) is missing from the OpenAPI spec",
+                        route.path,
+                        route.operation.operation_id.as_deref().unwrap_or_default()
+                    );
+                }
+                operations += route.methods.len();
+            }
+            let documented: usize = spec
+                .paths
+                .paths
+                .values()
+                .map(|item| {
+                    [&item.get, &item.post, &item.put, &item.delete, &item.patch]
+                        .into_iter()
+                        .filter(|operation| operation.is_some())
+                        .count()
+                })
+                .sum();
+            assert_eq!(
+                documented, operations,
+                "the OpenAPI spec describes routes that aren't registered"
+            );
+        }
+    }
+
+    #[test]
+    fn test_router_paths() {
+        let routes = super::super::routes(true);
+        let path = |path: &str| {
+            routes
+                .iter()
+                .find(|route| route.path == path)
+                .map(super::super::Route::router_path)
+        };
+        assert_eq!(path("/api/v1/beat").as_deref(), Some("/api/v1/beat"));
+        assert_eq!(
+            path("/api/v1/devices/{device_id}/probe").as_deref(),
+            Some("/api/v1/devices/:device_id/probe")
+        );
+    }
+
+    #[test]
+    fn test_spec_is_3_1() {
+        let spec = serde_json::to_value(document(false)).expect("spec to serialize");
+        assert_eq!(spec["openapi"], "3.1.0");
+        assert!(spec["paths"].get("/api/v1/beat").is_some());
+        assert!(spec["paths"].get("/api/v1/devices").is_none());
+        assert!(spec["components"]["schemas"].get("Metadata").is_some());
+        assert!(spec["components"]["schemas"].get("ProblemDetails").is_some());
+        assert_eq!(spec["paths"]["/api/v1/beat"]["post"]["tags"][0], "beats");
+    }
+}
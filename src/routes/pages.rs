@@ -4,56 +4,251 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::{api::get_stats, StatsResp};
 use crate::{
-    templates::{index, privacy, stats as stats_template},
-    AppState, PoolExt,
+    error::Error,
+    templates::{device as device_template, index, index_text, privacy, stats as stats_template, stats_text},
+    AppState,
+};
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, VARY},
+        request::Parts,
+        HeaderValue, Method, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    Json,
 };
-use axum::{extract::State, http::StatusCode};
 use html::Markup;
+use std::convert::Infallible;
+
+/// Whether a flag is set in the query string, either on its own or with a
+/// value other than `0`, `false`, `no` or `never`.
+pub fn query_flag(uri: &Uri, names: &[&str]) -> bool {
+    uri.query().is_some_and(|query| {
+        query.split('&').any(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            names.contains(&key) && !matches!(value, "0" | "false" | "no" | "never")
+        })
+    })
+}
+
+/// How a page is rendered, negotiated from the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    /// A compact summary, with ANSI colours if the `colour` query parameter
+    /// is set.
+    Text {
+        colour: bool,
+    },
+    /// The same body as `GET /api/v1/stats`.
+    Json,
+}
+
+impl Format {
+    /// Picks the format with the highest quality in an `Accept` header. Ties
+    /// are broken in favour of HTML, then JSON, and HTML is used if none of
+    /// them are acceptable.
+    fn negotiate(accept: &str, colour: bool) -> Self {
+        let ranges = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media_type = params.next()?.trim().to_ascii_lowercase();
+                let (kind, subtype) = media_type.split_once('/')?;
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((kind.to_owned(), subtype.to_owned(), quality))
+            })
+            .collect::<Vec<_>>();
+        // the quality of the most specific range matching a media type
+        let quality = |kind: &str, subtype: &str| {
+            ranges
+                .iter()
+                .filter_map(|(k, s, q)| match (k.as_str(), s.as_str()) {
+                    (k, s) if k == kind && s == subtype => Some((2, *q)),
+                    (k, "*") if k == kind => Some((1, *q)),
+                    ("*", "*") => Some((0, *q)),
+                    _ => None,
+                })
+                .max_by_key(|&(specificity, _)| specificity)
+                .map_or(0.0, |(_, q)| q)
+        };
+        [
+            (Self::Html, quality("text", "html")),
+            (Self::Json, quality("application", "json")),
+            (Self::Text { colour }, quality("text", "plain")),
+        ]
+        .into_iter()
+        .filter(|&(_, q)| q > 0.0)
+        .reduce(|best, next| if next.1 > best.1 { next } else { best })
+        .map_or(Self::Html, |(format, _)| format)
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = Infallible;
 
+    async fn from_request_parts(req: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let Some(accept) = req.headers.get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return Ok(Self::Html);
+        };
+        Ok(Self::negotiate(accept, query_flag(&req.uri, &["colour", "color"])))
+    }
+}
+
+/// Renders a page in the negotiated format.
+fn render(
+    format: Format,
+    html: impl FnOnce() -> Markup,
+    text: impl FnOnce(bool) -> String,
+    json: impl FnOnce() -> StatsResp,
+) -> Response {
+    let mut resp = match format {
+        Format::Html => html().into_response(),
+        Format::Text { colour } => {
+            let mut resp = text(colour).into_response();
+            resp.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+            resp
+        }
+        Format::Json => Json(json()).into_response(),
+    };
+    resp.headers_mut().insert(VARY, HeaderValue::from_static("accept"));
+    resp
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "pages",
+    params(("colour" = Option<bool>, Query, description = "Colours the plain text summary with ANSI escape codes.")),
+    responses((
+        status = 200,
+        description = "The home page, or a summary of it in plain text or JSON, depending on the `Accept` header.",
+        content((String = "text/html"), (String = "text/plain"), (StatsResp = "application/json")),
+    )),
+)]
 #[axum::debug_handler]
-pub async fn index_page(
-    State(AppState {
-        stats,
-        git_revision,
-        config,
-        pool,
-        ..
-    }): State<AppState>,
-) -> (StatusCode, Markup) {
+pub async fn index_page(State(app_state): State<AppState>, format: Format) -> Response {
     let stats = {
-        let mut guard = stats.lock();
+        let mut guard = app_state.stats.lock();
         guard.num_visits += 1;
         guard.clone()
     };
-    tokio::spawn(async move {
-        let _ = pool.incr_visits().await;
-    });
-    (StatusCode::OK, index(&stats, git_revision, config))
+    app_state.writer.visit();
+    render(
+        format,
+        || index(&stats, app_state.git_revision, app_state.config),
+        |colour| index_text(&stats, app_state.config, colour),
+        || get_stats(&app_state),
+    )
 }
 
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "pages",
+    params(("colour" = Option<bool>, Query, description = "Colours the plain text summary with ANSI escape codes.")),
+    responses((
+        status = 200,
+        description = "Statistics about all devices, in plain text or JSON depending on the `Accept` header.",
+        content((String = "text/html"), (String = "text/plain"), (StatsResp = "application/json")),
+    )),
+)]
 #[axum::debug_handler]
-pub async fn stats_page(
-    State(AppState {
-        stats,
-        server_start_time,
-        config,
-        pool,
-        ..
-    }): State<AppState>,
-) -> (StatusCode, Markup) {
+pub async fn stats_page(State(app_state): State<AppState>, format: Format) -> Response {
     let stats = {
-        let mut guard = stats.lock();
+        let mut guard = app_state.stats.lock();
         guard.num_visits += 1;
         guard.clone()
     };
-    tokio::spawn(async move {
-        let _ = pool.incr_visits().await;
-    });
-    (StatusCode::OK, stats_template(&stats, config, server_start_time))
+    app_state.writer.visit();
+    let public = || {
+        let mut stats = stats.clone();
+        for device in &mut stats.devices {
+            *device = device.public(&app_state.config.public_metadata);
+        }
+        stats
+    };
+    render(
+        format,
+        || stats_template(&stats, app_state.config, app_state.server_start_time),
+        |colour| stats_text(&public(), app_state.config, app_state.server_start_time, colour),
+        || get_stats(&app_state),
+    )
 }
 
+#[utoipa::path(
+    get,
+    path = "/privacy",
+    tag = "pages",
+    responses((status = 200, description = "The privacy policy.", content_type = "text/html")),
+)]
 #[axum::debug_handler]
 pub async fn privacy_page(State(AppState { config, .. }): State<AppState>) -> Markup {
     privacy(config)
 }
+
+#[utoipa::path(
+    get,
+    path = "/devices/{device_id}",
+    tag = "pages",
+    params(("device_id" = i64, Path, description = "The ID of the device.")),
+    responses(
+        (status = 200, description = "Statistics about a single device.", content_type = "text/html"),
+        (status = 404, description = "The device doesn't exist.", content_type = "text/html"),
+    ),
+)]
+#[axum::debug_handler]
+pub async fn device_page(
+    State(AppState {
+        stats, config, writer, ..
+    }): State<AppState>,
+    Path(device_id): Path<i64>,
+    method: Method,
+    uri: Uri,
+) -> Result<Markup, Error> {
+    let device = {
+        let mut guard = stats.lock();
+        let device = guard.devices.iter().find(|d| d.id == device_id).cloned();
+        if device.is_some() {
+            guard.num_visits += 1;
+        }
+        device
+    };
+    let Some(device) = device else {
+        return Err(Error::new(
+            uri.path(),
+            &method,
+            StatusCode::NOT_FOUND,
+            &config.server_name,
+        ));
+    };
+    writer.visit();
+    Ok(device_template(&device, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |accept| Format::negotiate(accept, false);
+        assert_eq!(negotiate("*/*"), Format::Html);
+        assert_eq!(negotiate("text/html,application/xhtml+xml,*/*;q=0.8"), Format::Html);
+        assert_eq!(negotiate("application/json"), Format::Json);
+        assert_eq!(negotiate("text/plain"), Format::Text { colour: false });
+        assert_eq!(negotiate("text/*;q=0.5, text/plain"), Format::Text { colour: false });
+        assert_eq!(negotiate("text/html;q=0.1, application/json;q=0.9"), Format::Json);
+        assert_eq!(negotiate("text/html;q=0, */*"), Format::Json);
+        assert_eq!(negotiate("image/png"), Format::Html);
+        assert_eq!(Format::negotiate("text/plain", true), Format::Text { colour: true });
+    }
+}
@@ -1,3 +1,4 @@
+use crate::AppState;
 use axum::{extract::connect_info::IntoMakeServiceWithConnectInfo, Router};
 use hyper_util::rt::TokioIo;
 use std::{io, net::SocketAddr};
@@ -7,7 +8,8 @@ use tracing::{debug, warn};
 
 /// Serve the given router on the given TCP listener.
 ///
-/// This supports graceful shutdown via SIGINT and SIGTERM.
+/// This supports graceful shutdown via SIGINT and SIGTERM, after which the
+/// visits and beats buffered in `state` are written.
 ///
 /// # Errors
 ///
@@ -15,6 +17,7 @@ use tracing::{debug, warn};
 pub async fn serve(
     tcp_listener: TcpListener,
     mut make_service: IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+    state: AppState,
 ) -> io::Result<()> {
     let (tx, rx) = watch::channel(());
     loop {
@@ -60,10 +63,12 @@ pub async fn serve(
     drop(tcp_listener);
     debug!("waiting for {} tasks to finish", tx.receiver_count());
     tx.closed().await;
+    debug!("writing buffered visits and beats");
+    state.writer.flush().await;
     Ok(())
 }
 
-async fn shutdown() {
+pub async fn shutdown() {
     let ctrlc = async { signal::ctrl_c().await.expect("failed to install ^C handler") };
     #[cfg(unix)]
     let terminate = async {
@@ -4,9 +4,19 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::devices::Device;
-use chrono::Utc;
-use sqlx::PgPool;
+use crate::{
+    cluster::Change,
+    devices::Device,
+    server::shutdown,
+    storage::{Snapshot, Storage},
+    AppState,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{collections::HashMap, time::Duration};
+use tracing::{debug, error, warn};
+
+/// How often the stats in memory are compared with those in the database.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(300);
 
 #[derive(Debug, Clone)]
 pub struct Stats {
@@ -15,53 +25,283 @@ pub struct Stats {
     pub longest_absence: chrono::Duration,
     pub num_visits: i64,
     pub total_beats: i64,
+    /// How many times the stats have been changed, to tell whether they
+    /// changed while fresh ones were being fetched.
+    pub changes: u64,
 }
 
 impl Stats {
-    pub async fn fetch(pool: &PgPool) -> Self {
-        let devs = sqlx::query!(
-            r"
-    WITH b AS (
-        SELECT device, time_stamp FROM heartbeat.beats ORDER BY time_stamp DESC LIMIT 1
-    )
-    SELECT
-        d.num_beats,
-        MAX(b.time_stamp) AS last_beat,
-        d.id,
-        d.name
-    FROM b, heartbeat.beats beats JOIN heartbeat.devices d ON beats.device = d.id
-    GROUP BY d.id
-    "
-        )
-        .fetch_all(pool)
-        .await
-        .unwrap_or_default();
-        // can't find a way to not make this a second query
-        let (visits, longest_absence) = sqlx::query!(
-            "SELECT EXTRACT(epoch FROM longest_absence)::BIGINT as longest_absence, total_visits FROM heartbeat.stats;"
-        )
-        .fetch_optional(pool)
-        .await
-        .unwrap_or_default()
-        .map_or((0, Some(0)), |v| (v.total_visits, v.longest_absence));
-        let longest = chrono::Duration::seconds(longest_absence.unwrap_or_default());
-        let mut devices = vec![];
-        for dev in devs {
-            devices.push(Device {
-                id: dev.id,
-                name: dev.name,
-                last_beat: dev.last_beat,
-                num_beats: dev.num_beats,
-            });
-        }
-        let last_beat = devices.iter().max_by_key(|d| d.last_beat).and_then(|d| d.last_beat);
+    pub async fn fetch(storage: &dyn Storage) -> Self {
+        Self::new(storage.snapshot().await.unwrap_or_default())
+    }
+
+    /// Like [`Stats::fetch`], but fails instead of starting out empty.
+    pub async fn try_fetch(storage: &dyn Storage) -> sqlx::Result<Self> {
+        Ok(Self::new(storage.snapshot().await?))
+    }
+
+    fn new(Snapshot { devices, totals }: Snapshot) -> Self {
+        let last_beat = totals.last_seen;
         let total_beats = devices.iter().map(|d| d.num_beats).sum();
         Self {
             last_seen: last_beat,
             devices,
-            longest_absence: longest.max(Utc::now() - last_beat.unwrap_or_else(Utc::now)),
-            num_visits: visits as _,
+            longest_absence: totals
+                .longest_absence
+                .max(Utc::now() - last_beat.unwrap_or_else(Utc::now)),
+            num_visits: totals.num_visits,
             total_beats,
+            changes: 0,
+        }
+    }
+
+    /// Applies a change made by this or another instance, returning whether
+    /// the longest absence grew.
+    pub fn apply(&mut self, change: &Change) -> bool {
+        self.changes += 1;
+        match change {
+            &Change::Beat {
+                device,
+                credential,
+                time,
+                previous,
+            } => {
+                let absence = previous.map(|previous| time - previous);
+                let grew = absence.is_some_and(|absence| absence > self.longest_absence);
+                if let Some(absence) = absence.filter(|_| grew) {
+                    self.longest_absence = absence;
+                }
+                self.last_seen = self.last_seen.max(Some(time));
+                if let Some(x) = self.device(device) {
+                    if Some(time) >= x.last_beat {
+                        x.last_beat = Some(time);
+                        x.last_credential = Some(credential);
+                    }
+                    x.num_beats += 1;
+                }
+                self.total_beats += 1;
+                return grew;
+            }
+            &Change::Batch {
+                device,
+                credential,
+                inserted,
+                latest,
+                longest_absence,
+                time,
+            } => {
+                self.total_beats += inserted;
+                self.last_seen = self.last_seen.max(latest);
+                self.longest_absence = TimeDelta::seconds(longest_absence).max(time - self.last_seen.unwrap_or(time));
+                if let Some(x) = self.device(device) {
+                    x.num_beats += inserted;
+                    if latest > x.last_beat {
+                        x.last_beat = latest;
+                        x.last_credential = Some(credential);
+                    }
+                }
+            }
+            Change::Device(device) => {
+                if self.device(device.id).is_none() {
+                    self.devices.push(device.clone());
+                }
+            }
+            Change::Probe { device, probe } => {
+                if let Some(x) = self.device(*device) {
+                    x.probe.clone_from(probe);
+                }
+            }
+            Change::Metadata { device, metadata } => {
+                if let Some(x) = self.device(*device) {
+                    x.metadata = Some(metadata.clone());
+                }
+            }
+            Change::Visits { count } => self.num_visits += count,
+        }
+        false
+    }
+
+    fn device(&mut self, id: i64) -> Option<&mut Device> {
+        self.devices.iter_mut().find(|x| x.id == id)
+    }
+
+    /// Replaces these stats with `fresh` ones from the database.
+    pub fn replace(&mut self, fresh: Self) {
+        *self = Self {
+            changes: self.changes + 1,
+            ..fresh
+        };
+    }
+
+    /// Replaces these stats with `fresh` ones from the database, returning how
+    /// far they had drifted from them.
+    ///
+    /// `changes` is what [`Stats::changes`] was before `fresh` was fetched. If
+    /// the stats changed since, `fresh` may be missing those changes, so it is
+    /// rejected and [`None`] is returned.
+    pub fn reconcile(&mut self, fresh: Self, changes: u64, at: DateTime<Utc>) -> Option<Drift> {
+        if self.changes != changes {
+            return None;
+        }
+        let mut beats = self
+            .devices
+            .iter()
+            .map(|d| (d.id, d.num_beats))
+            .collect::<HashMap<_, _>>();
+        let mut drift = Drift {
+            at,
+            total_beats: self.total_beats - fresh.total_beats,
+            num_visits: self.num_visits - fresh.num_visits,
+            device_beats: 0,
+            devices: 0,
+        };
+        for device in &fresh.devices {
+            match beats.remove(&device.id) {
+                Some(num_beats) => drift.device_beats += (num_beats - device.num_beats).abs(),
+                None => drift.devices += 1,
+            }
         }
+        drift.devices += i64::try_from(beats.len()).unwrap_or(i64::MAX);
+        self.replace(fresh);
+        Some(drift)
+    }
+}
+
+/// How far the stats in memory were from those in the database when they
+/// were last reconciled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Drift {
+    /// When the stats were reconciled.
+    pub at: DateTime<Utc>,
+    /// The total number of beats in memory less that in the database.
+    pub total_beats: i64,
+    /// The number of visits in memory less that in the database.
+    pub num_visits: i64,
+    /// How far the number of beats of each device was off, either way, added
+    /// up.
+    pub device_beats: i64,
+    /// The number of devices that were only in memory or only in the
+    /// database.
+    pub devices: i64,
+}
+
+impl Drift {
+    const fn is_zero(&self) -> bool {
+        self.total_beats == 0 && self.num_visits == 0 && self.device_beats == 0 && self.devices == 0
+    }
+}
+
+/// Replaces the stats in memory with those in the database every few minutes
+/// until the server shuts down, recording how far they had drifted.
+pub async fn serve_reconciliation(state: AppState) {
+    let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+    // the stats were fetched when the server started
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown() => {
+                debug!("shutdown signal received, no longer reconciling stats");
+                break;
+            }
+        }
+        // buffered visits would count as drift
+        state.writer.flush().await;
+        let changes = state.stats.lock().changes;
+        let fresh = match Stats::try_fetch(&*state.storage).await {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                error!("Failed to fetch stats to reconcile: {e:?}");
+                continue;
+            }
+        };
+        let Some(drift) = state.stats.lock().reconcile(fresh, changes, Utc::now()) else {
+            debug!("stats changed while fetching them, reconciling them next time");
+            continue;
+        };
+        if drift.is_zero() {
+            debug!("stats in memory match the database");
+        } else {
+            warn!(?drift, "Stats in memory had drifted from the database");
+        }
+        *state.drift.lock() = Some(drift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Credential;
+
+    fn device(id: i64, num_beats: i64) -> Device {
+        Device {
+            id,
+            name: None,
+            last_beat: None,
+            num_beats,
+            last_credential: None,
+            metadata: None,
+            probe: None,
+        }
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let stats = |devices: Vec<Device>, num_visits| Stats {
+            last_seen: None,
+            total_beats: devices.iter().map(|d| d.num_beats).sum(),
+            devices,
+            longest_absence: TimeDelta::zero(),
+            num_visits,
+            changes: 0,
+        };
+        let mut memory = stats(vec![device(1, 5), device(2, 3)], 10);
+        let database = stats(vec![device(1, 4), device(3, 0)], 8);
+        let now = Utc::now();
+        let drift = memory.reconcile(database, memory.changes, now);
+        assert_eq!(
+            drift,
+            Some(Drift {
+                at: now,
+                total_beats: 4,
+                num_visits: 2,
+                device_beats: 1,
+                devices: 2,
+            })
+        );
+        assert_eq!(memory.total_beats, 4);
+        assert!(memory
+            .reconcile(memory.clone(), memory.changes, now)
+            .is_some_and(|d| d.is_zero()));
+    }
+
+    #[test]
+    fn test_reconcile_after_change() {
+        let mut memory = Stats {
+            last_seen: None,
+            total_beats: 5,
+            devices: vec![device(1, 5)],
+            longest_absence: TimeDelta::zero(),
+            num_visits: 0,
+            changes: 0,
+        };
+        let changes = memory.changes;
+        // fetched before the beat was written
+        let database = memory.clone();
+        let now = Utc::now();
+        memory.apply(&Change::Beat {
+            device: 1,
+            credential: Credential::Current,
+            time: now,
+            previous: None,
+        });
+        assert_eq!(memory.reconcile(database, changes, now), None);
+        assert_eq!(memory.total_beats, 6);
+        assert_eq!(memory.devices[0].num_beats, 6);
+        assert_eq!(memory.last_seen, Some(now));
+        let database = memory.clone();
+        assert!(memory
+            .reconcile(database, memory.changes, now)
+            .is_some_and(|d| d.is_zero()));
     }
 }
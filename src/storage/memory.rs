@@ -0,0 +1,827 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{
+    AppliedMigration, Batch, Compacted, Named, NewBeat, NewDevice, ProbeTarget, Snapshot, Span, Storage, StoredEvent,
+    Totals,
+};
+use crate::{
+    auth::Device as DeviceAuth,
+    devices::{Credential, Device, Metadata, Probe},
+};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug)]
+struct StoredDevice {
+    name: Option<String>,
+    token: String,
+    num_beats: i64,
+    signing_secret: Option<String>,
+    public_key: Option<Vec<u8>>,
+    udp_counter: i64,
+    probe: Option<Probe>,
+    metadata: Option<Metadata>,
+    last_beat_at: Option<DateTime<Utc>>,
+    last_credential: Option<Credential>,
+}
+
+impl StoredDevice {
+    fn named(&self, id: i64) -> Named {
+        Named {
+            id,
+            name: self.name.clone(),
+        }
+    }
+
+    fn beat(&mut self, time: DateTime<Utc>, credential: Credential) {
+        if self.last_beat_at.is_none_or(|last_beat_at| time >= last_beat_at) {
+            self.last_beat_at = Some(time);
+            self.last_credential = Some(credential);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Superseded {
+    device: i64,
+    expires_at: DateTime<Utc>,
+}
+
+/// Consecutive beats of a device: a single raw beat, or a rollup of them.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    num_beats: i64,
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+    last_credential: Credential,
+}
+
+impl Run {
+    const fn beat(time: DateTime<Utc>, credential: Credential) -> Self {
+        Self {
+            num_beats: 1,
+            first: time,
+            last: time,
+            last_credential: credential,
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.num_beats += other.num_beats;
+        self.first = self.first.min(other.first);
+        if other.last > self.last {
+            self.last = other.last;
+            self.last_credential = other.last_credential;
+        }
+    }
+}
+
+/// Rolls `runs` up into `rollups`, by device and the start of the `bucket`
+/// they are in.
+fn roll_up(
+    rollups: &mut BTreeMap<(i64, DateTime<Utc>), Run>,
+    runs: impl IntoIterator<Item = (i64, Run)>,
+    bucket: TimeDelta,
+) -> sqlx::Result<()> {
+    for (device, run) in runs {
+        let start = run
+            .first
+            .duration_trunc(bucket)
+            .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        rollups
+            .entry((device, start))
+            .and_modify(|rollup| rollup.merge(run))
+            .or_insert(run);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Inner {
+    server_start_time: DateTime<Utc>,
+    total_visits: i64,
+    longest_absence: TimeDelta,
+    last_seen: Option<DateTime<Utc>>,
+    devices: BTreeMap<i64, StoredDevice>,
+    /// Beats by time stamp and device, so they are in order.
+    beats: BTreeMap<(DateTime<Utc>, i64), Credential>,
+    superseded_tokens: HashMap<String, Superseded>,
+    nonces: HashMap<(i64, String), DateTime<Utc>>,
+    events: BTreeMap<i64, StoredEvent>,
+    /// Compacted beats by device and the start of their hour or day.
+    hourly: BTreeMap<(i64, DateTime<Utc>), Run>,
+    daily: BTreeMap<(i64, DateTime<Utc>), Run>,
+    compacted_absence: TimeDelta,
+}
+
+impl Inner {
+    const fn totals(&self) -> Totals {
+        Totals {
+            num_visits: self.total_visits,
+            longest_absence: self.longest_absence,
+            last_seen: self.last_seen,
+        }
+    }
+
+    fn devices(&self) -> Vec<Device> {
+        self.devices
+            .iter()
+            .map(|(id, device)| Device {
+                id: *id,
+                name: device.name.clone(),
+                last_beat: device.last_beat_at,
+                num_beats: device.num_beats,
+                last_credential: device.last_credential,
+                metadata: device.metadata.clone(),
+                probe: device.probe.clone(),
+            })
+            .collect()
+    }
+
+    fn device(&mut self, id: i64) -> sqlx::Result<&mut StoredDevice> {
+        self.devices.get_mut(&id).ok_or(sqlx::Error::RowNotFound)
+    }
+
+    /// Returns the runs of beats of `device`, or of all devices, that end
+    /// after `since`, ordered by their first beat.
+    fn runs(&self, device: Option<i64>, since: DateTime<Utc>) -> Vec<(i64, Run)> {
+        let mut runs = self
+            .beats
+            .range((since, i64::MIN)..)
+            .map(|((time, id), credential)| (*id, Run::beat(*time, *credential)))
+            .chain(self.hourly.iter().chain(&self.daily).map(|((id, _), run)| (*id, *run)))
+            .filter(|(id, run)| run.last >= since && device.is_none_or(|device| device == *id))
+            .collect::<Vec<_>>();
+        runs.sort_by_key(|(_, run)| run.first);
+        runs
+    }
+
+    /// Returns the last compacted beat of any device.
+    fn last_compacted(&self) -> Option<DateTime<Utc>> {
+        self.hourly
+            .values()
+            .chain(self.daily.values())
+            .map(|run| run.last)
+            .max()
+    }
+
+    /// Returns the beats, raw or the last compacted one, right before `earliest`
+    /// and right after `latest`, or those themselves if there are none.
+    fn nearby(&self, earliest: DateTime<Utc>, latest: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let last_compacted = self.last_compacted();
+        let previous = self
+            .beats
+            .range(..(earliest, i64::MIN))
+            .next_back()
+            .map(|((time, _), _)| *time)
+            .max(last_compacted.filter(|time| *time < earliest));
+        let next = self
+            .beats
+            .range((latest, i64::MAX)..)
+            .map(|((time, _), _)| *time)
+            .find(|time| *time > latest)
+            .into_iter()
+            .chain(last_compacted.filter(|time| *time > latest))
+            .min();
+        (previous.unwrap_or(earliest), next.unwrap_or(latest))
+    }
+
+    /// Returns the longest gap between the raw beats, and the last compacted
+    /// beat, from `from` to `to`, if there are any.
+    fn longest_gap_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<TimeDelta> {
+        let mut time_stamps = self
+            .beats
+            .range((from, i64::MIN)..=(to, i64::MAX))
+            .map(|((time, _), _)| *time)
+            .chain(self.last_compacted().filter(|time| (from..=to).contains(time)))
+            .collect::<Vec<_>>();
+        time_stamps.sort_unstable();
+        let longest = time_stamps.windows(2).map(|pair| pair[1] - pair[0]).max()?;
+        Some(TimeDelta::seconds(longest.num_seconds()))
+    }
+
+    /// Returns the longest gap between the raw beats before `before`, and
+    /// between the first of them and the last compacted beat.
+    fn longest_gap(&self, before: Option<DateTime<Utc>>) -> TimeDelta {
+        let time_stamps = self
+            .last_compacted()
+            .into_iter()
+            .chain(
+                self.beats
+                    .keys()
+                    .map(|(time, _)| *time)
+                    .take_while(|time| before.is_none_or(|before| *time < before)),
+            )
+            .collect::<Vec<_>>();
+        let longest = time_stamps
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .max()
+            .unwrap_or_default();
+        // stored in whole seconds, like the other backends
+        TimeDelta::seconds(longest.num_seconds())
+    }
+}
+
+/// Returns the runs, ordered by their first beat, with the latest last beat
+/// of those before each.
+fn with_previous(runs: Vec<(i64, Run)>) -> impl Iterator<Item = (Option<DateTime<Utc>>, Run)> {
+    runs.into_iter()
+        .scan(None, |latest: &mut Option<DateTime<Utc>>, (_, run)| {
+            let previous = *latest;
+            *latest = previous.max(Some(run.last));
+            Some((previous, run))
+        })
+}
+
+/// Keeps everything in memory, for tests and demos. Nothing is persisted, and
+/// every instance starts out empty.
+#[derive(Debug)]
+pub struct Memory {
+    inner: Mutex<Inner>,
+}
+
+impl Memory {
+    /// Creates an empty store, as if the server was first deployed at
+    /// `server_start_time`.
+    #[must_use]
+    pub fn new(server_start_time: DateTime<Utc>) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                server_start_time,
+                total_visits: 0,
+                longest_absence: TimeDelta::zero(),
+                last_seen: None,
+                devices: BTreeMap::new(),
+                beats: BTreeMap::new(),
+                superseded_tokens: HashMap::new(),
+                nonces: HashMap::new(),
+                events: BTreeMap::new(),
+                hourly: BTreeMap::new(),
+                daily: BTreeMap::new(),
+                compacted_absence: TimeDelta::zero(),
+            }),
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+#[axum::async_trait]
+impl Storage for Memory {
+    #[cfg(feature = "migrate")]
+    async fn migrate(&self) -> Result<(), sqlx::migrate::MigrateError> {
+        Ok(())
+    }
+
+    #[cfg(feature = "migrate")]
+    async fn undo_migrations(&self, _target: i64) -> Result<(), sqlx::migrate::MigrateError> {
+        Ok(())
+    }
+
+    #[cfg(feature = "migrate")]
+    fn migrator(&self) -> Option<&sqlx::migrate::Migrator> {
+        None
+    }
+
+    fn expected_schema_version(&self) -> Option<i64> {
+        None
+    }
+
+    async fn applied_migrations(&self) -> sqlx::Result<Option<Vec<AppliedMigration>>> {
+        Ok(None)
+    }
+
+    async fn server_start_time(&self) -> DateTime<Utc> {
+        self.inner.lock().server_start_time
+    }
+
+    async fn add_visits(&self, count: i64) -> sqlx::Result<()> {
+        self.inner.lock().total_visits += count;
+        Ok(())
+    }
+
+    async fn totals(&self) -> sqlx::Result<Totals> {
+        Ok(self.inner.lock().totals())
+    }
+
+    async fn set_longest_absence(&self, absence: TimeDelta) -> sqlx::Result<()> {
+        let longest = &mut self.inner.lock().longest_absence;
+        *longest = (*longest).max(absence);
+        Ok(())
+    }
+
+    async fn last_seen(&self) -> sqlx::Result<Option<DateTime<Utc>>> {
+        Ok(self.inner.lock().last_seen)
+    }
+
+    async fn total_beats(&self) -> sqlx::Result<i64> {
+        Ok(self.inner.lock().devices.values().map(|d| d.num_beats).sum())
+    }
+
+    async fn devices(&self) -> sqlx::Result<Vec<Device>> {
+        Ok(self.inner.lock().devices())
+    }
+
+    async fn snapshot(&self) -> sqlx::Result<Snapshot> {
+        let inner = self.inner.lock();
+        Ok(Snapshot {
+            devices: inner.devices(),
+            totals: inner.totals(),
+        })
+    }
+
+    async fn recent_devices(&self, limit: i64) -> sqlx::Result<Vec<Named>> {
+        let inner = self.inner.lock();
+        Ok(inner
+            .devices
+            .iter()
+            .rev()
+            .take(usize::try_from(limit).unwrap_or_default())
+            .map(|(id, device)| device.named(*id))
+            .collect())
+    }
+
+    async fn add_device(&self, device: NewDevice<'_>) -> sqlx::Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.devices.contains_key(&device.id) {
+            return Err(sqlx::Error::Protocol(format!("device {} already exists", device.id)));
+        }
+        inner.devices.insert(
+            device.id,
+            StoredDevice {
+                name: Some(device.name.to_owned()),
+                token: device.token.to_owned(),
+                num_beats: 0,
+                signing_secret: device.signing_secret.map(ToOwned::to_owned),
+                public_key: device.public_key.map(ToOwned::to_owned),
+                udp_counter: 0,
+                probe: device.probe.cloned(),
+                metadata: None,
+                last_beat_at: None,
+                last_credential: None,
+            },
+        );
+        drop(inner);
+        Ok(())
+    }
+
+    async fn rotate_token(
+        &self,
+        device: i64,
+        token: &str,
+        now: DateTime<Utc>,
+        previous_expires_at: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Option<Named>> {
+        let mut inner = self.inner.lock();
+        let Some(stored) = inner.devices.get_mut(&device) else {
+            return Ok(None);
+        };
+        let previous_token = std::mem::replace(&mut stored.token, token.to_owned());
+        let named = stored.named(device);
+        inner
+            .superseded_tokens
+            .retain(|_, s| s.device != device || s.expires_at > now);
+        if let Some(expires_at) = previous_expires_at {
+            inner
+                .superseded_tokens
+                .entry(previous_token)
+                .or_insert(Superseded { device, expires_at });
+        }
+        drop(inner);
+        Ok(Some(named))
+    }
+
+    async fn set_signing_secret(&self, device: i64, secret: Option<&str>) -> sqlx::Result<Option<Named>> {
+        let mut inner = self.inner.lock();
+        Ok(inner.devices.get_mut(&device).map(|stored| {
+            stored.signing_secret = secret.map(ToOwned::to_owned);
+            stored.named(device)
+        }))
+    }
+
+    async fn set_probe(&self, device: i64, probe: Option<&Probe>) -> sqlx::Result<Option<Named>> {
+        let mut inner = self.inner.lock();
+        Ok(inner.devices.get_mut(&device).map(|stored| {
+            stored.probe = probe.cloned();
+            stored.named(device)
+        }))
+    }
+
+    async fn probe_targets(&self) -> sqlx::Result<Vec<ProbeTarget>> {
+        Ok(self
+            .inner
+            .lock()
+            .devices
+            .iter()
+            .filter_map(|(id, device)| {
+                Some(ProbeTarget {
+                    id: *id,
+                    name: device.name.clone(),
+                    probe: device.probe.clone()?,
+                })
+            })
+            .collect())
+    }
+
+    async fn store_metadata(&self, device: i64, _time: DateTime<Utc>, metadata: &Metadata) -> sqlx::Result<()> {
+        self.inner.lock().device(device)?.metadata = Some(metadata.clone());
+        Ok(())
+    }
+
+    async fn lookup_token(&self, token: &str) -> sqlx::Result<Option<(DeviceAuth, Option<String>)>> {
+        let inner = self.inner.lock();
+        let auth = |id: i64, device: &StoredDevice, credential| {
+            (
+                DeviceAuth {
+                    id,
+                    name: device.name.clone(),
+                    credential,
+                },
+                device.signing_secret.clone(),
+            )
+        };
+        if let Some((id, device)) = inner.devices.iter().find(|(_, d)| d.token == token) {
+            return Ok(Some(auth(*id, device, Credential::Current)));
+        }
+        // superseded tokens are only accepted until their grace period runs out
+        let now = Utc::now();
+        Ok(inner
+            .superseded_tokens
+            .get(token)
+            .filter(|s| s.expires_at > now)
+            .and_then(|s| Some(auth(s.device, inner.devices.get(&s.device)?, Credential::Superseded))))
+    }
+
+    async fn public_key(&self, device: i64) -> sqlx::Result<Option<(Named, Vec<u8>)>> {
+        let inner = self.inner.lock();
+        Ok(inner
+            .devices
+            .get(&device)
+            .and_then(|stored| Some((stored.named(device), stored.public_key.clone()?))))
+    }
+
+    async fn signing_secret(&self, device: i64) -> sqlx::Result<Option<(Named, String)>> {
+        let inner = self.inner.lock();
+        Ok(inner
+            .devices
+            .get(&device)
+            .and_then(|stored| Some((stored.named(device), stored.signing_secret.clone()?))))
+    }
+
+    async fn use_nonce(
+        &self,
+        device: i64,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<bool> {
+        let mut inner = self.inner.lock();
+        let previous = inner.nonces.get(&(device, nonce.to_owned())).copied();
+        let fresh = previous.is_none_or(|previous| previous <= now);
+        if fresh {
+            inner.nonces.insert((device, nonce.to_owned()), expires_at);
+        }
+        inner.nonces.retain(|_, expires_at| *expires_at > now);
+        drop(inner);
+        Ok(fresh)
+    }
+
+    async fn advance_udp_counter(&self, device: i64, counter: i64) -> sqlx::Result<bool> {
+        let mut inner = self.inner.lock();
+        Ok(inner
+            .devices
+            .get_mut(&device)
+            .filter(|stored| stored.udp_counter < counter)
+            .map(|stored| stored.udp_counter = counter)
+            .is_some())
+    }
+
+    async fn insert_live_beats(&self, beats: &[NewBeat]) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let mut inner = self.inner.lock();
+        let previous = inner.last_seen;
+        for beat in beats {
+            if inner.beats.contains_key(&(beat.time, beat.device)) {
+                continue;
+            }
+            let Some(stored) = inner.devices.get_mut(&beat.device) else {
+                continue;
+            };
+            stored.num_beats += 1;
+            stored.beat(beat.time, beat.credential);
+            inner.last_seen = inner.last_seen.max(Some(beat.time));
+            inner.beats.insert((beat.time, beat.device), beat.credential);
+        }
+        drop(inner);
+        Ok(previous)
+    }
+
+    async fn insert_beats(
+        &self,
+        device: i64,
+        credential: Credential,
+        time_stamps: &[DateTime<Utc>],
+    ) -> sqlx::Result<Batch> {
+        let mut inner = self.inner.lock();
+        inner.device(device)?;
+        let nearby = time_stamps
+            .iter()
+            .min()
+            .zip(time_stamps.iter().max())
+            .map(|(earliest, latest)| inner.nearby(*earliest, *latest));
+        let old_gap = nearby.and_then(|(from, to)| inner.longest_gap_between(from, to));
+        let mut inserted = 0;
+        let mut latest = None;
+        for time in time_stamps {
+            if let std::collections::btree_map::Entry::Vacant(entry) = inner.beats.entry((*time, device)) {
+                entry.insert(credential);
+                inserted += 1;
+                latest = latest.max(Some(*time));
+            }
+        }
+        let stored = inner.device(device)?;
+        stored.num_beats += inserted;
+        if let Some(latest) = latest {
+            stored.beat(latest, credential);
+        }
+        inner.last_seen = inner.last_seen.max(latest);
+        // new beats can only split the gaps between their neighbours, so unless
+        // the longest of those was the longest absence, the rest of the beats
+        // needn't be looked at
+        let longest_absence = match old_gap {
+            Some(old_gap) if old_gap >= inner.longest_absence => inner.compacted_absence.max(inner.longest_gap(None)),
+            _ => {
+                let new_gap = nearby.and_then(|(from, to)| inner.longest_gap_between(from, to));
+                inner.longest_absence.max(new_gap.unwrap_or_default())
+            }
+        };
+        inner.longest_absence = longest_absence;
+        drop(inner);
+        Ok(Batch {
+            inserted,
+            latest,
+            longest_absence,
+        })
+    }
+
+    async fn absences(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>> {
+        let runs = self.inner.lock().runs(device, since);
+        Ok(with_previous(runs)
+            .filter_map(|(previous, run)| Some((previous?, run.first)))
+            .filter(|(start, end)| *end - *start >= threshold)
+            .collect())
+    }
+
+    async fn active_spans(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>> {
+        let runs = self.inner.lock().runs(device, since);
+        let mut spans: Vec<Span> = vec![];
+        for (previous, run) in with_previous(runs) {
+            match spans.last_mut() {
+                Some((_, end)) if previous.is_some_and(|previous| run.first - previous < threshold) => {
+                    *end = (*end).max(run.last);
+                }
+                _ => spans.push((run.first, run.last)),
+            }
+        }
+        spans.retain(|(start, end)| end > start);
+        Ok(spans)
+    }
+
+    async fn compact(
+        &self,
+        raw_before: DateTime<Utc>,
+        hourly_before: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Compacted> {
+        let mut inner = self.inner.lock();
+        let absence = inner.longest_gap(Some(raw_before));
+        inner.compacted_absence = inner.compacted_absence.max(absence);
+        let kept = inner.beats.split_off(&(raw_before, i64::MIN));
+        let old = std::mem::replace(&mut inner.beats, kept);
+        let beats = old.len() as u64;
+        let runs = old
+            .into_iter()
+            .map(|((time, device), credential)| (device, Run::beat(time, credential)));
+        roll_up(&mut inner.hourly, runs, TimeDelta::hours(1))?;
+        let mut hours = 0;
+        if let Some(hourly_before) = hourly_before {
+            let (old, kept) = std::mem::take(&mut inner.hourly)
+                .into_iter()
+                .partition::<BTreeMap<_, _>, _>(|((_, hour), _)| *hour < hourly_before);
+            inner.hourly = kept;
+            hours = old.len() as u64;
+            roll_up(
+                &mut inner.daily,
+                old.into_iter().map(|((device, _), run)| (device, run)),
+                TimeDelta::days(1),
+            )?;
+        }
+        drop(inner);
+        Ok(Compacted { beats, hours })
+    }
+
+    async fn insert_event(&self, event: StoredEvent) -> sqlx::Result<()> {
+        self.inner.lock().events.entry(event.id).or_insert(event);
+        Ok(())
+    }
+
+    async fn recent_events(&self, limit: i64) -> sqlx::Result<Vec<StoredEvent>> {
+        let mut events = self.inner.lock().events.values().copied().collect::<Vec<_>>();
+        events.sort_by_key(|event| std::cmp::Reverse(event.time));
+        events.truncate(usize::try_from(limit).unwrap_or_default());
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        s.parse().expect("valid timestamp")
+    }
+
+    #[tokio::test]
+    async fn test_spans() {
+        let storage = Memory::default();
+        storage
+            .add_device(NewDevice {
+                id: 1,
+                name: "test",
+                token: "token",
+                signing_secret: None,
+                public_key: None,
+                probe: None,
+            })
+            .await
+            .expect("device to be added");
+        let beats = [
+            time("2024-03-01T09:00:00Z"),
+            time("2024-03-01T09:30:00Z"),
+            time("2024-03-01T12:00:00Z"),
+            time("2024-03-01T12:00:00Z"),
+        ];
+        let batch = storage
+            .insert_beats(1, Credential::Current, &beats)
+            .await
+            .expect("beats to be inserted");
+        assert_eq!(batch.inserted, 3);
+        assert_eq!(batch.longest_absence, TimeDelta::minutes(150));
+        let since = time("2024-03-01T00:00:00Z");
+        let threshold = TimeDelta::hours(1);
+        assert_eq!(
+            storage
+                .absences(Some(1), since, threshold)
+                .await
+                .expect("absences to be read"),
+            [(beats[1], beats[2])]
+        );
+        assert_eq!(
+            storage
+                .active_spans(None, since, threshold)
+                .await
+                .expect("spans to be read"),
+            [(beats[0], beats[1])]
+        );
+        assert!(storage
+            .absences(Some(2), since, threshold)
+            .await
+            .expect("absences to be read")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_longest_absence() {
+        let storage = Memory::default();
+        storage
+            .add_device(NewDevice {
+                id: 1,
+                name: "test",
+                token: "token",
+                signing_secret: None,
+                public_key: None,
+                probe: None,
+            })
+            .await
+            .expect("device to be added");
+        let insert = |beats: &[&str]| {
+            let beats = beats.iter().map(|beat| time(beat)).collect::<Vec<_>>();
+            let storage = &storage;
+            async move {
+                storage
+                    .insert_beats(1, Credential::Current, &beats)
+                    .await
+                    .expect("beats to be inserted")
+            }
+        };
+        let batch = insert(&["2024-03-01T09:00:00Z", "2024-03-01T12:00:00Z", "2024-03-01T13:00:00Z"]).await;
+        assert_eq!(batch.longest_absence, TimeDelta::hours(3));
+        // a shorter gap is split
+        let batch = insert(&["2024-03-01T12:30:00Z"]).await;
+        assert_eq!(batch.longest_absence, TimeDelta::hours(3));
+        // new gaps at either end
+        let batch = insert(&["2024-03-01T17:00:00Z"]).await;
+        assert_eq!(batch.longest_absence, TimeDelta::hours(4));
+        let batch = insert(&["2024-03-01T04:00:00Z"]).await;
+        assert_eq!(batch.longest_absence, TimeDelta::hours(5));
+        // the longest gap is split, so the others have to be looked at
+        let batch = insert(&["2024-03-01T06:00:00Z", "2024-03-01T07:00:00Z"]).await;
+        assert_eq!(batch.longest_absence, TimeDelta::hours(4));
+        let batch = insert(&["2024-03-01T07:00:00Z"]).await;
+        assert_eq!((batch.inserted, batch.longest_absence), (0, TimeDelta::hours(4)));
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let storage = Memory::default();
+        storage
+            .add_device(NewDevice {
+                id: 1,
+                name: "test",
+                token: "token",
+                signing_secret: None,
+                public_key: None,
+                probe: None,
+            })
+            .await
+            .expect("device to be added");
+        let beats = [
+            time("2024-03-01T09:00:00Z"),
+            time("2024-03-01T09:30:00Z"),
+            time("2024-03-01T12:00:00Z"),
+            time("2024-03-02T09:00:00Z"),
+            time("2024-03-03T09:00:00Z"),
+        ];
+        storage
+            .insert_beats(1, Credential::Current, &beats[..4])
+            .await
+            .expect("beats to be inserted");
+        let since = time("2024-03-01T00:00:00Z");
+        let threshold = TimeDelta::hours(1);
+        let absences = storage
+            .absences(None, since, threshold)
+            .await
+            .expect("absences to be read");
+        let compacted = storage
+            .compact(time("2024-03-02T10:00:00Z"), None)
+            .await
+            .expect("beats to be compacted");
+        assert_eq!((compacted.beats, compacted.hours), (4, 0));
+        assert_eq!(
+            storage
+                .absences(None, since, threshold)
+                .await
+                .expect("absences to be read"),
+            absences
+        );
+        // gaps within a day are lost once its hours are rolled up
+        let compacted = storage
+            .compact(time("2024-03-02T10:00:00Z"), Some(time("2024-03-02T00:00:00Z")))
+            .await
+            .expect("beats to be compacted");
+        assert_eq!((compacted.beats, compacted.hours), (0, 2));
+        assert_eq!(
+            storage
+                .absences(None, since, threshold)
+                .await
+                .expect("absences to be read"),
+            absences[1..]
+        );
+        assert_eq!(storage.last_seen().await.expect("last beat to be read"), Some(beats[3]));
+        // the longest gap was compacted, and the one after the last compacted
+        // beat still counts
+        let batch = storage
+            .insert_beats(1, Credential::Key, &beats[4..])
+            .await
+            .expect("beats to be inserted");
+        assert_eq!(batch.longest_absence, TimeDelta::days(1));
+        assert_eq!(
+            storage
+                .active_spans(Some(1), since, threshold)
+                .await
+                .expect("spans to be read"),
+            [(beats[0], beats[2])]
+        );
+        let devices = storage.devices().await.expect("devices to be read");
+        assert_eq!(devices[0].num_beats, 5);
+        assert_eq!(devices[0].last_credential, Some(Credential::Key));
+    }
+}
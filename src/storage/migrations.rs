@@ -0,0 +1,320 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks the database schema against the migrations this build was made with,
+//! and applies or reverts them.
+
+use super::Storage;
+use std::fmt;
+#[cfg(feature = "migrate")]
+use {
+    sqlx::migrate::{MigrateError, Migrator},
+    std::collections::BTreeMap,
+};
+
+/// A migration as it is recorded in the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedMigration {
+    /// The version, which is when the migration was written.
+    pub version: i64,
+    /// The SHA-384 checksum of the migration as it was applied.
+    pub checksum: Vec<u8>,
+    /// Whether the migration finished.
+    pub success: bool,
+}
+
+/// What state a migration is in.
+#[cfg(feature = "migrate")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// The migration hasn't been applied yet.
+    Pending,
+    /// The migration has been applied.
+    Applied,
+    /// The migration was applied, but has since been changed.
+    Modified,
+    /// The migration was applied, but didn't finish.
+    Failed,
+    /// The migration was applied by a newer build.
+    Unknown,
+}
+
+#[cfg(feature = "migrate")]
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Pending => "pending",
+            Self::Applied => "applied",
+            Self::Modified => "modified",
+            Self::Failed => "failed",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// A migration, as this build has it and as it was applied to the database.
+#[cfg(feature = "migrate")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// The version, which is when the migration was written.
+    pub version: i64,
+    /// What the migration does, or `None` if this build doesn't have it.
+    pub description: Option<String>,
+    /// What state the migration is in.
+    pub state: State,
+    /// Whether the migration can be reverted.
+    pub reversible: bool,
+}
+
+/// Why the database schema doesn't match this build, or migrations couldn't
+/// be applied or reverted.
+#[derive(Debug)]
+pub enum Error {
+    /// The database couldn't be read.
+    Database(sqlx::Error),
+    /// A migration couldn't be applied or reverted.
+    #[cfg(feature = "migrate")]
+    Migrate(MigrateError),
+    /// A migration didn't finish.
+    Failed(i64),
+    /// A migration was changed after it was applied.
+    Modified(i64),
+    /// The database is missing migrations this build expects.
+    Older {
+        /// The latest migration in the database, if there is one.
+        latest: Option<i64>,
+        /// The latest migration of this build.
+        expected: i64,
+    },
+    /// The database has migrations that this build doesn't know about.
+    Newer {
+        /// The latest migration in the database.
+        latest: i64,
+        /// The latest migration of this build.
+        expected: i64,
+    },
+    /// A migration can't be reverted.
+    Irreversible(i64),
+    /// There are no migrations to revert.
+    NothingToRevert,
+    /// `database.auto_migrate` is set, but this build can't apply migrations.
+    #[cfg(not(feature = "migrate"))]
+    AutoMigrateUnsupported,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Database(error) => write!(f, "database error: {error}"),
+            #[cfg(feature = "migrate")]
+            Self::Migrate(error) => write!(f, "migration error: {error}"),
+            Self::Failed(version) => write!(
+                f,
+                "migration {version} didn't finish. Fix the database by hand, then delete its row from \
+                 _sqlx_migrations"
+            ),
+            Self::Modified(version) => write!(f, "migration {version} was changed after it was applied"),
+            Self::Older { latest, expected } => write!(
+                f,
+                "the database schema is older than this build expects: its latest migration is {}, but this build's \
+                 is {expected}. Run `heartbeat migrate up`, or set `database.auto_migrate = true`",
+                latest.map_or_else(|| "none".into(), |latest| latest.to_string()),
+            ),
+            Self::Newer { latest, expected } => write!(
+                f,
+                "the database schema is newer than this build expects: its latest migration is {latest}, but this \
+                 build's is {expected}. Upgrade heartbeat, or run `heartbeat migrate down` with the newer build"
+            ),
+            Self::Irreversible(version) => write!(f, "migration {version} can't be reverted"),
+            Self::NothingToRevert => f.write_str("no migrations have been applied"),
+            #[cfg(not(feature = "migrate"))]
+            Self::AutoMigrateUnsupported => f.write_str("`database.auto_migrate` requires the `migrate` feature"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        Self::Database(error)
+    }
+}
+
+#[cfg(feature = "migrate")]
+impl From<MigrateError> for Error {
+    fn from(error: MigrateError) -> Self {
+        Self::Migrate(error)
+    }
+}
+
+/// Parses the version of the latest migration of a backend, as it is set by
+/// the build script.
+pub(super) fn expected(latest: &str) -> Option<i64> {
+    latest.parse().ok().filter(|&version| version > 0)
+}
+
+/// Fails if the database schema is older or newer than this build expects, or
+/// a migration didn't finish.
+///
+/// Databases that were set up without migrations can't be checked, and are
+/// assumed to match.
+///
+/// # Errors
+///
+/// Returns an error if the schema doesn't match, or it couldn't be read.
+pub async fn check(storage: &dyn Storage) -> Result<(), Error> {
+    let Some(expected) = storage.expected_schema_version() else {
+        return Ok(());
+    };
+    let Some(applied) = storage.applied_migrations().await? else {
+        tracing::warn!("The database wasn't set up with `heartbeat migrate`, so its schema can't be checked");
+        return Ok(());
+    };
+    if let Some(failed) = applied.iter().find(|m| !m.success) {
+        return Err(Error::Failed(failed.version));
+    }
+    match applied.iter().map(|m| m.version).max() {
+        Some(latest) if latest > expected => Err(Error::Newer { latest, expected }),
+        Some(latest) if latest == expected => Ok(()),
+        latest => Err(Error::Older { latest, expected }),
+    }
+}
+
+/// Returns every migration this build has or that was applied to the
+/// database, oldest first.
+#[cfg(feature = "migrate")]
+async fn status_of(storage: &dyn Storage, migrator: &Migrator) -> Result<Vec<Status>, Error> {
+    let mut applied = storage
+        .applied_migrations()
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| (m.version, m))
+        .collect::<BTreeMap<_, _>>();
+    let reversible = migrator
+        .iter()
+        .filter(|m| m.migration_type.is_down_migration())
+        .map(|m| m.version)
+        .collect::<Vec<_>>();
+    let mut status = migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| Status {
+            version: m.version,
+            description: Some(m.description.to_string()),
+            state: match applied.remove(&m.version) {
+                None => State::Pending,
+                Some(applied) if !applied.success => State::Failed,
+                Some(applied) if *applied.checksum != *m.checksum => State::Modified,
+                Some(_) => State::Applied,
+            },
+            reversible: reversible.contains(&m.version),
+        })
+        .collect::<Vec<_>>();
+    status.extend(applied.into_values().map(|m| Status {
+        version: m.version,
+        description: None,
+        state: if m.success { State::Unknown } else { State::Failed },
+        reversible: false,
+    }));
+    status.sort_unstable_by_key(|s| s.version);
+    Ok(status)
+}
+
+/// Returns every migration this build has or that was applied to the
+/// database, oldest first.
+///
+/// # Errors
+///
+/// Returns an error if the applied migrations couldn't be read.
+#[cfg(feature = "migrate")]
+pub async fn status(storage: &dyn Storage) -> Result<Vec<Status>, Error> {
+    match storage.migrator() {
+        Some(migrator) => status_of(storage, migrator).await,
+        None => Ok(vec![]),
+    }
+}
+
+/// Checks that every migration this build has was applied as it is, and no
+/// others were.
+///
+/// # Errors
+///
+/// Returns the first problem that was found, if any.
+#[cfg(feature = "migrate")]
+pub async fn verify(storage: &dyn Storage) -> Result<(), Error> {
+    let status = status(storage).await?;
+    if let Some(modified) = status.iter().find(|s| s.state == State::Modified) {
+        return Err(Error::Modified(modified.version));
+    }
+    check(storage).await
+}
+
+/// Reverts the migrations after `to`, or only the latest one if it isn't set,
+/// and returns those that were reverted.
+///
+/// # Errors
+///
+/// Returns an error if any of them can't be reverted, in which case none are.
+#[cfg(feature = "migrate")]
+pub async fn down(storage: &dyn Storage, to: Option<i64>) -> Result<Vec<Status>, Error> {
+    let status = status(storage).await?;
+    let mut applied = status
+        .into_iter()
+        .filter(|s| s.state != State::Pending)
+        .collect::<Vec<_>>();
+    let to = to.unwrap_or_else(|| applied.iter().rev().nth(1).map_or(0, |s| s.version));
+    applied.retain(|s| s.version > to);
+    if applied.is_empty() {
+        return Err(Error::NothingToRevert);
+    }
+    if let Some(irreversible) = applied.iter().find(|s| !s.reversible) {
+        return Err(Error::Irreversible(irreversible.version));
+    }
+    storage.undo_migrations(to).await?;
+    Ok(applied)
+}
+
+#[cfg(all(test, feature = "migrate", feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::storage::{ConnectOptions, Sqlite};
+
+    #[tokio::test]
+    async fn test_down_then_up() {
+        let scratch = std::env::temp_dir().join(format!("heartbeat-migrations-{}.db", std::process::id()));
+        let dsn = format!("sqlite://{}", scratch.display());
+        let storage = Sqlite::connect(&dsn, &ConnectOptions::default()).expect("scratch database to connect");
+        let expected = storage
+            .expected_schema_version()
+            .expect("expected schema version to be known");
+        // a database that wasn't set up with migrations isn't checked
+        check(&storage).await.expect("unmigrated database to pass the check");
+        storage.migrate().await.expect("migrations to be applied");
+        verify(&storage).await.expect("applied migrations to verify");
+        assert!(status(&storage)
+            .await
+            .expect("migration status to be read")
+            .iter()
+            .all(|s| s.state == State::Applied));
+
+        let reverted = down(&storage, None).await.expect("last migration to be reverted");
+        assert_eq!(reverted.iter().map(|s| s.version).collect::<Vec<_>>(), [expected]);
+        assert!(matches!(check(&storage).await, Err(Error::Older { expected: e, .. }) if e == expected));
+        let status = status(&storage).await.expect("migration status to be read");
+        assert_eq!(status.last().expect("a migration to exist").state, State::Pending);
+        // the earlier migrations can't be reverted
+        assert!(matches!(down(&storage, Some(0)).await, Err(Error::Irreversible(_))));
+
+        storage.migrate().await.expect("migrations to be applied again");
+        check(&storage).await.expect("migrated database to pass the check");
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", scratch.display()));
+        }
+    }
+}
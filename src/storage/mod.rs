@@ -0,0 +1,443 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Where devices, beats, stats and visits are stored.
+//!
+//! The backend is chosen by the scheme of the database DSN, and each backend
+//! has its own set of migrations under `migrations/<backend>`.
+//!
+//! Old beats may be compacted into hourly and then daily rollups, which keep
+//! the number of beats and the first and last of them. Reads that span beats
+//! treat each rollup as a run of beats from its first to its last, so gaps
+//! within a daily rollup are lost but those between rollups are not.
+//!
+//! Several instances may share a `PostgreSQL` database. They tell each other
+//! about changes with `NOTIFY`, and hold an advisory lock to pick the one
+//! leader that runs background jobs. Reads that may lag behind can be spread
+//! across read replicas with [`Replicas`].
+
+use crate::{
+    auth::Device as DeviceAuth,
+    devices::{Credential, Device, Metadata, Probe},
+    events::StoredKind,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+pub use migrations::AppliedMigration;
+#[cfg(feature = "migrate")]
+use sqlx::migrate::{MigrateError, Migrator};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+mod memory;
+pub mod migrations;
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::Memory;
+pub use postgres::Postgres;
+#[cfg(feature = "sqlite")]
+pub use sqlite::Sqlite;
+
+/// A span of time, from the first to the second.
+pub type Span = (DateTime<Utc>, DateTime<Utc>);
+
+/// The ID and name of a device.
+#[derive(Debug, Clone)]
+pub struct Named {
+    /// The device's Snowflake ID.
+    pub id: i64,
+    /// The device's human-readable name.
+    pub name: Option<String>,
+}
+
+/// A device that the server polls.
+#[derive(Debug, Clone)]
+pub struct ProbeTarget {
+    /// The device's Snowflake ID.
+    pub id: i64,
+    /// The device's human-readable name.
+    pub name: Option<String>,
+    /// How and how often to poll the device.
+    pub probe: Probe,
+}
+
+/// A device that is being added.
+#[derive(Debug, Clone, Copy)]
+pub struct NewDevice<'a> {
+    /// The device's Snowflake ID.
+    pub id: i64,
+    /// The device's human-readable name.
+    pub name: &'a str,
+    /// The token the device authenticates with.
+    pub token: &'a str,
+    /// The secret beats from the device must be signed with, if any.
+    pub signing_secret: Option<&'a str>,
+    /// The device's raw Ed25519 public key, if any.
+    pub public_key: Option<&'a [u8]>,
+    /// How the server checks on the device, if it does.
+    pub probe: Option<&'a Probe>,
+}
+
+/// A beat that is being recorded as it happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewBeat {
+    /// The device the beat is from.
+    pub device: i64,
+    /// How the device authenticated.
+    pub credential: Credential,
+    /// When the beat was received.
+    pub time: DateTime<Utc>,
+}
+
+/// The outcome of inserting a batch of beats.
+#[derive(Debug, Clone, Copy)]
+pub struct Batch {
+    /// The number of beats that weren't already stored.
+    pub inserted: i64,
+    /// The latest of the beats that were inserted.
+    pub latest: Option<DateTime<Utc>>,
+    /// The longest absence, recomputed over all beats.
+    pub longest_absence: TimeDelta,
+}
+
+/// The totals that aren't derived from devices.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Totals {
+    /// The number of times the index page was visited.
+    pub num_visits: i64,
+    /// The longest absence that has ended.
+    pub longest_absence: TimeDelta,
+    /// The time of the last beat from any device.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Every device and the totals, read together.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    /// Every device, with its latest metadata.
+    pub devices: Vec<Device>,
+    /// The totals that aren't derived from devices.
+    pub totals: Totals,
+}
+
+/// The outcome of compacting old beats.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compacted {
+    /// The number of beats that were rolled up into hours.
+    pub beats: u64,
+    /// The number of hourly rollups that were rolled up into days.
+    pub hours: u64,
+}
+
+/// An event, as it is stored.
+#[derive(Debug, Clone, Copy)]
+pub struct StoredEvent {
+    /// The snowflake ID of the event.
+    pub id: i64,
+    /// What happened.
+    pub kind: StoredKind,
+    /// When it happened.
+    pub time: DateTime<Utc>,
+    /// When the absence started, for absences and records.
+    pub started_at: Option<DateTime<Utc>>,
+    /// The device whose beat ended the absence.
+    pub device: Option<i64>,
+}
+
+/// A storage backend.
+///
+/// Methods that only read aggregates, such as [`Storage::last_seen`], may be
+/// called often and should be cheap.
+#[axum::async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Applies the backend's migrations.
+    #[cfg(feature = "migrate")]
+    async fn migrate(&self) -> Result<(), MigrateError>;
+    /// Reverts the applied migrations after `target`, skipping those that
+    /// can't be reverted.
+    #[cfg(feature = "migrate")]
+    async fn undo_migrations(&self, target: i64) -> Result<(), MigrateError>;
+    /// Returns the backend's migrations, or `None` if it has none.
+    #[cfg(feature = "migrate")]
+    fn migrator(&self) -> Option<&Migrator>;
+    /// Returns the version of the backend's latest migration, or `None` if it
+    /// has none.
+    fn expected_schema_version(&self) -> Option<i64>;
+    /// Returns the migrations that were applied to the database, oldest first,
+    /// or `None` if it wasn't set up with migrations.
+    async fn applied_migrations(&self) -> sqlx::Result<Option<Vec<AppliedMigration>>>;
+
+    /// Get the server epoch (time of first ever deployment)
+    async fn server_start_time(&self) -> DateTime<Utc>;
+    /// Adds `count` to the number of visits to the site.
+    async fn add_visits(&self, count: i64) -> sqlx::Result<()>;
+    /// Returns the number of visits and the longest absence.
+    async fn totals(&self) -> sqlx::Result<Totals>;
+    /// Stores a new longest absence, unless a longer one is already stored.
+    async fn set_longest_absence(&self, absence: TimeDelta) -> sqlx::Result<()>;
+    /// Returns the time of the last beat from any device.
+    async fn last_seen(&self) -> sqlx::Result<Option<DateTime<Utc>>>;
+    /// Returns the total number of beats from all devices.
+    async fn total_beats(&self) -> sqlx::Result<i64>;
+
+    /// Returns every device, including those that haven't sent a beat yet,
+    /// with its latest metadata.
+    async fn devices(&self) -> sqlx::Result<Vec<Device>>;
+    /// Returns every device and the totals as they were at a single point in
+    /// time.
+    async fn snapshot(&self) -> sqlx::Result<Snapshot>;
+    /// Returns up to `limit` of the most recently added devices, newest first.
+    async fn recent_devices(&self, limit: i64) -> sqlx::Result<Vec<Named>>;
+    /// Adds a device.
+    async fn add_device(&self, device: NewDevice<'_>) -> sqlx::Result<()>;
+    /// Replaces a device's token, keeping the previous one until
+    /// `previous_expires_at` if it is set. Returns `None` if the device
+    /// doesn't exist.
+    async fn rotate_token(
+        &self,
+        device: i64,
+        token: &str,
+        now: DateTime<Utc>,
+        previous_expires_at: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Option<Named>>;
+    /// Sets or removes a device's signing secret. Returns `None` if the device
+    /// doesn't exist.
+    async fn set_signing_secret(&self, device: i64, secret: Option<&str>) -> sqlx::Result<Option<Named>>;
+    /// Sets or removes a device's probe. Returns `None` if the device doesn't
+    /// exist.
+    async fn set_probe(&self, device: i64, probe: Option<&Probe>) -> sqlx::Result<Option<Named>>;
+    /// Returns every device that has a probe configured.
+    async fn probe_targets(&self) -> sqlx::Result<Vec<ProbeTarget>>;
+    /// Stores the latest metadata of a device.
+    async fn store_metadata(&self, device: i64, time: DateTime<Utc>, metadata: &Metadata) -> sqlx::Result<()>;
+
+    /// Looks up the device a current or superseded token belongs to, along
+    /// with its signing secret.
+    async fn lookup_token(&self, token: &str) -> sqlx::Result<Option<(DeviceAuth, Option<String>)>>;
+    /// Returns a device with its Ed25519 public key, if it has one.
+    async fn public_key(&self, device: i64) -> sqlx::Result<Option<(Named, Vec<u8>)>>;
+    /// Returns a device with its signing secret, if it has one.
+    async fn signing_secret(&self, device: i64) -> sqlx::Result<Option<(Named, String)>>;
+    /// Records the nonce of a signed beat until `expires_at`, and forgets
+    /// those that have expired. Returns `false` if the nonce was already
+    /// recorded and hasn't expired.
+    async fn use_nonce(
+        &self,
+        device: i64,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<bool>;
+    /// Advances the UDP counter of a device. Returns `false` if the counter
+    /// isn't greater than the stored one.
+    async fn advance_udp_counter(&self, device: i64, counter: i64) -> sqlx::Result<bool>;
+
+    /// Records beats as they happen, skipping any that already exist, and
+    /// returns the time of the last beat from any device before them.
+    async fn insert_live_beats(&self, beats: &[NewBeat]) -> sqlx::Result<Option<DateTime<Utc>>>;
+    /// Inserts a batch of beats, skipping any that already exist, and
+    /// recomputes the longest absence since backfilled beats may have filled
+    /// in old gaps.
+    async fn insert_beats(
+        &self,
+        device: i64,
+        credential: Credential,
+        time_stamps: &[DateTime<Utc>],
+    ) -> sqlx::Result<Batch>;
+    /// Returns the gaps of at least `threshold` between the beats of `device`,
+    /// or of all devices, since `since`.
+    async fn absences(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>>;
+    /// Returns the spans of beats of `device`, or of all devices, since
+    /// `since` with no gaps of at least `threshold` in them.
+    async fn active_spans(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>>;
+
+    /// Rolls up the beats before `raw_before` into hours, then the hourly
+    /// rollups before `hourly_before`, if set, into days. `raw_before` must be
+    /// on the hour and `hourly_before` at midnight, UTC.
+    async fn compact(&self, raw_before: DateTime<Utc>, hourly_before: Option<DateTime<Utc>>)
+        -> sqlx::Result<Compacted>;
+
+    /// Stores an event, unless one with the same ID already exists.
+    async fn insert_event(&self, event: StoredEvent) -> sqlx::Result<()>;
+    /// Returns up to `limit` of the most recent events, newest first.
+    async fn recent_events(&self, limit: i64) -> sqlx::Result<Vec<StoredEvent>>;
+
+    /// Tells the other instances sharing the database about a change.
+    /// Backends that can't be shared do nothing.
+    async fn notify(&self, _change: &str) -> sqlx::Result<()> {
+        Ok(())
+    }
+    /// Starts listening for changes from the other instances sharing the
+    /// database, or returns `None` if the backend can't be shared.
+    async fn listen(&self) -> sqlx::Result<Option<Box<dyn Listener>>> {
+        Ok(None)
+    }
+    /// Becomes or stays the leader that runs background jobs, returning
+    /// whether this instance is the leader. Backends that can't be shared
+    /// always lead.
+    async fn lead(&self) -> sqlx::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Receives the changes made by the other instances sharing a database.
+#[axum::async_trait]
+pub trait Listener: Debug + Send {
+    /// Waits for the next change, returning `None` if the connection was lost
+    /// and changes may have been missed in the meantime.
+    async fn recv(&mut self) -> sqlx::Result<Option<String>>;
+}
+
+/// How connections to the database are opened and pooled.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// The `PostgreSQL` schema everything is stored in, which is set as the
+    /// `search_path` of every connection.
+    pub schema: String,
+    /// The most connections the pool opens.
+    pub max_connections: u32,
+    /// The fewest connections the pool keeps open.
+    pub min_connections: u32,
+    /// How long to wait for a connection before giving up.
+    pub acquire_timeout: Duration,
+    /// How long a connection may sit idle before it is closed, if it is at
+    /// all.
+    pub idle_timeout: Option<Duration>,
+    /// How long a `PostgreSQL` statement may run before it is cancelled, if
+    /// there is a limit.
+    pub statement_timeout: Option<Duration>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            schema: String::from("heartbeat"),
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+            statement_timeout: None,
+        }
+    }
+}
+
+/// Connects to the backend named by the scheme of `dsn`: `postgres://` (or
+/// `postgresql://`), `sqlite:`, or `memory:` for an empty [`Memory`] store.
+///
+/// Connections are only opened once they are first needed.
+///
+/// # Errors
+///
+/// Returns an error if the scheme isn't supported, or the DSN is invalid.
+pub fn connect(dsn: &str, options: &ConnectOptions) -> sqlx::Result<Arc<dyn Storage>> {
+    match dsn.split_once(':').map(|(scheme, _)| scheme) {
+        Some("postgres" | "postgresql") => Ok(Arc::new(Postgres::connect(dsn, options)?)),
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") => Ok(Arc::new(Sqlite::connect(dsn, options)?)),
+        Some("memory") => Ok(Arc::new(Memory::default())),
+        scheme => Err(sqlx::Error::Configuration(
+            format!(
+                "unsupported database scheme `{}`. Supported schemes are `postgres`{} and `memory`",
+                scheme.unwrap_or_default(),
+                if cfg!(feature = "sqlite") { ", `sqlite`" } else { "" }
+            )
+            .into(),
+        )),
+    }
+}
+
+/// Returns an error if `dsn` names the in-memory store, which loses
+/// everything when the server stops and is only meant for tests and `--demo`.
+///
+/// # Errors
+///
+/// Returns an error if the scheme of `dsn` is `memory`.
+pub fn ensure_persistent(dsn: &str) -> sqlx::Result<()> {
+    if dsn.split_once(':').is_some_and(|(scheme, _)| scheme == "memory") {
+        return Err(sqlx::Error::Configuration(
+            "`memory:` doesn't persist anything, use `--demo` to run without a database".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Read replicas of the database, which take turns serving reads that may lag
+/// a little behind.
+#[derive(Debug, Default)]
+pub struct Replicas {
+    replicas: Vec<Arc<dyn Storage>>,
+    next: AtomicUsize,
+}
+
+impl Replicas {
+    /// Connects to the replicas at `dsns`, without opening any connections
+    /// yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the DSNs is invalid.
+    pub fn connect(dsns: &[String], options: &ConnectOptions) -> sqlx::Result<Self> {
+        Ok(Self {
+            replicas: dsns
+                .iter()
+                .map(|dsn| connect(dsn, options))
+                .collect::<sqlx::Result<_>>()?,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the next replica to read from, or `None` if there are none.
+    pub fn next(&self) -> Option<&Arc<dyn Storage>> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.replicas.get(next.checked_rem(self.replicas.len())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_scheme() {
+        assert!(connect("mysql://localhost/heartbeat", &ConnectOptions::default()).is_err());
+        assert!(connect("heartbeat.db", &ConnectOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_replicas_take_turns() {
+        assert!(Replicas::default().next().is_none());
+        let dsns = ["memory:".to_string(), "memory:".to_string()];
+        let replicas = Replicas::connect(&dsns, &ConnectOptions::default()).expect("replicas to connect");
+        let first = replicas.next().expect("a first replica");
+        let second = replicas.next().expect("a second replica");
+        assert!(!Arc::ptr_eq(first, second));
+        assert!(Arc::ptr_eq(first, replicas.next().expect("the first replica again")));
+    }
+
+    #[test]
+    fn test_ensure_persistent() {
+        assert!(ensure_persistent("memory:").is_err());
+        assert!(ensure_persistent("postgres://localhost/heartbeat").is_ok());
+        assert!(ensure_persistent("sqlite:heartbeat.db").is_ok());
+    }
+}
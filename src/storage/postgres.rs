@@ -0,0 +1,1096 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::migrations::{self, AppliedMigration};
+use super::{
+    Batch, Compacted, ConnectOptions, Listener, Named, NewBeat, NewDevice, ProbeTarget, Snapshot, Span, Storage,
+    StoredEvent, Totals,
+};
+use crate::{
+    auth::Device as DeviceAuth,
+    devices::{Credential, Device, Metadata, Probe},
+    events::StoredKind,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "migrate")]
+use sqlx::migrate::{MigrateError, Migration, Migrator};
+use sqlx::{
+    postgres::{types::PgInterval, PgConnectOptions, PgListener, PgPoolOptions},
+    types::Json,
+    Connection, PgConnection, PgExecutor, PgPool,
+};
+use std::{str::FromStr, sync::Arc};
+use tokio::sync::Mutex;
+
+/// The channel instances using the default schema send changes on, which
+/// those using another one derive theirs from.
+const CHANNEL: &str = "heartbeat_changes";
+/// The key of the advisory lock held by the leader of the instances using the
+/// default schema, which those using another one derive theirs from.
+const LEADER_LOCK: i64 = 0x6862_6c65_6164_6572;
+
+/// Returns the channel instances storing everything in `schema` send changes
+/// on, and the key of the advisory lock held by their leader, so instances
+/// using different schemas of one database leave each other alone.
+fn coordination(schema: &str) -> (String, i64) {
+    // instances from before the schema was configurable use the constants
+    if schema == ConnectOptions::default().schema {
+        return (CHANNEL.to_owned(), LEADER_LOCK);
+    }
+    let digest = Sha256::digest(schema.as_bytes());
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&digest[..8]);
+    let hash = i64::from_be_bytes(hash);
+    // channels are identifiers, so the schema's name may not fit in one
+    (format!("{CHANNEL}_{hash:016x}"), LEADER_LOCK ^ hash)
+}
+
+/// Stores everything in `PostgreSQL`, in the `heartbeat` schema unless
+/// another one is configured.
+#[derive(Debug, Clone)]
+pub struct Postgres {
+    pool: PgPool,
+    /// The connection holding the leader's advisory lock, which is released
+    /// when the connection is closed.
+    leader: Arc<Mutex<Option<PgConnection>>>,
+    /// How to connect for migrations, which keep track of themselves in the
+    /// configured schema.
+    unpooled: PgConnectOptions,
+    /// The schema everything is stored in.
+    #[cfg(feature = "migrate")]
+    schema: String,
+    /// The channel changes are sent on.
+    channel: String,
+    /// The key of the advisory lock held by the leader.
+    leader_lock: i64,
+    /// The migrations, rewritten for the configured schema.
+    #[cfg(feature = "migrate")]
+    migrator: Arc<Migrator>,
+}
+
+impl Postgres {
+    /// Creates a pool for the database at `dsn`, without connecting to it yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the DSN is invalid.
+    pub fn connect(dsn: &str, options: &ConnectOptions) -> sqlx::Result<Self> {
+        // options are separated by spaces, so those in the schema's name are
+        // escaped
+        let search_path = quote(&options.schema).replace('\\', "\\\\").replace(' ', "\\ ");
+        let unpooled = PgConnectOptions::from_str(dsn)?.options([("search_path", search_path)]);
+        let mut pooled = unpooled.clone();
+        if let Some(timeout) = options.statement_timeout {
+            pooled = pooled.options([("statement_timeout", format!("{}ms", timeout.as_millis()))]);
+        }
+        let pool = PgPoolOptions::default()
+            .max_connections(options.max_connections)
+            .min_connections(options.min_connections)
+            .acquire_timeout(options.acquire_timeout)
+            .idle_timeout(options.idle_timeout)
+            .connect_lazy_with(pooled);
+        let (channel, leader_lock) = coordination(&options.schema);
+        Ok(Self {
+            pool,
+            leader: Arc::default(),
+            unpooled,
+            #[cfg(feature = "migrate")]
+            schema: options.schema.clone(),
+            channel,
+            leader_lock,
+            #[cfg(feature = "migrate")]
+            migrator: Arc::new(migrator(&options.schema)),
+        })
+    }
+
+    /// Opens a single connection for migrations, outside of the pool.
+    async fn unpooled(&self) -> sqlx::Result<PgPool> {
+        PgPoolOptions::default()
+            .max_connections(1)
+            .connect_with(self.unpooled.clone())
+            .await
+    }
+
+    /// Like [`Postgres::unpooled`], but also creates the schema, so the table
+    /// migrations are recorded in can be created in it, and moves that table
+    /// there from where it was kept before.
+    #[cfg(feature = "migrate")]
+    async fn unpooled_for_migrations(&self) -> sqlx::Result<PgPool> {
+        let pool = self.unpooled().await?;
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", quote(&self.schema)))
+            .execute(&pool)
+            .await?;
+        if migrations_table(&pool).await? == Some(LEGACY_MIGRATIONS_TABLE) {
+            sqlx::query(&format!(
+                "ALTER TABLE {LEGACY_MIGRATIONS_TABLE} SET SCHEMA {}",
+                quote(&self.schema)
+            ))
+            .execute(&pool)
+            .await?;
+        }
+        Ok(pool)
+    }
+}
+
+/// Where the table migrations are recorded in was created before it was kept
+/// in the configured schema.
+const LEGACY_MIGRATIONS_TABLE: &str = "public._sqlx_migrations";
+
+/// Returns the table the migrations applied to the configured schema are
+/// recorded in, if there is one.
+///
+/// If the schema doesn't have one, but has tables, its migrations are still
+/// recorded in [`LEGACY_MIGRATIONS_TABLE`].
+async fn migrations_table(executor: impl PgExecutor<'_>) -> sqlx::Result<Option<&'static str>> {
+    // the table is only created by the first migration, so these can't be
+    // checked at compile time
+    let (current, legacy) = sqlx::query_as::<_, (bool, bool)>(&format!(
+        "SELECT to_regclass('_sqlx_migrations') IS NOT NULL, to_regclass('{LEGACY_MIGRATIONS_TABLE}') IS NOT NULL AND \
+         to_regclass('beats') IS NOT NULL"
+    ))
+    .fetch_one(executor)
+    .await?;
+    Ok(if current {
+        Some("_sqlx_migrations")
+    } else if legacy {
+        Some(LEGACY_MIGRATIONS_TABLE)
+    } else {
+        None
+    })
+}
+
+/// Quotes `identifier` so it is used as it is, whatever characters are in it.
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Rewrites `sql`, which names the `heartbeat` schema, to name `schema`
+/// instead.
+#[cfg(feature = "migrate")]
+fn in_schema(sql: &str, schema: &str) -> String {
+    const NAME: &str = "heartbeat";
+    let schema = quote(schema);
+    let mut rewritten = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(start) = rest.find(NAME) {
+        let (before, after) = (&rest[..start], &rest[start + NAME.len()..]);
+        let named =
+            !before.ends_with(|c: char| c.is_alphanumeric() || "_./\"".contains(c)) && after.starts_with(['.', ';']);
+        rewritten.push_str(before);
+        rewritten.push_str(if named { &schema } else { NAME });
+        rest = after;
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// Returns the migrations under `migrations/postgres`, rewritten for
+/// `schema`. They keep the checksums of the files, so databases using any
+/// schema are checked against the same ones.
+#[cfg(feature = "migrate")]
+fn migrator(schema: &str) -> Migrator {
+    // the first migrations name their tables without a schema, so they are
+    // created in `schema`, the `search_path` of the connection, rather than
+    // moved there later
+    let migrations = MIGRATOR
+        .iter()
+        .map(|migration| Migration {
+            sql: in_schema(&migration.sql, schema).into(),
+            ..migration.clone()
+        })
+        .collect::<Vec<_>>();
+    Migrator {
+        migrations: migrations.into(),
+        ..Migrator::DEFAULT
+    }
+}
+
+fn interval(delta: TimeDelta) -> sqlx::Result<PgInterval> {
+    PgInterval::try_from(TimeDelta::microseconds(
+        delta.num_microseconds().unwrap_or(i64::MAX - 1),
+    ))
+    .map_err(sqlx::Error::Encode)
+}
+
+async fn totals(executor: impl PgExecutor<'_>) -> sqlx::Result<Totals> {
+    let record = sqlx::query!(
+        r"
+        SELECT EXTRACT(epoch FROM longest_absence)::BIGINT as longest_absence, total_visits, last_seen
+        FROM stats;
+        "
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(record.map_or_else(Totals::default, |record| Totals {
+        num_visits: record.total_visits,
+        longest_absence: TimeDelta::seconds(record.longest_absence.unwrap_or_default()),
+        last_seen: record.last_seen,
+    }))
+}
+
+async fn devices(executor: impl PgExecutor<'_>) -> sqlx::Result<Vec<Device>> {
+    let records = sqlx::query!(
+        r#"
+        SELECT
+            d.num_beats,
+            d.last_beat_at,
+            d.id,
+            d.name,
+            d.last_credential AS "last_credential: Credential",
+            s.metadata AS "metadata?: Json<Metadata>",
+            d.probe AS "probe: Json<Probe>"
+        FROM devices d
+        LEFT JOIN device_state s ON s.device = d.id
+        ORDER BY d.id;
+        "#
+    )
+    .fetch_all(executor)
+    .await?;
+    Ok(records
+        .into_iter()
+        .map(|record| Device {
+            id: record.id,
+            name: record.name,
+            last_beat: record.last_beat_at,
+            num_beats: record.num_beats,
+            last_credential: record.last_credential,
+            metadata: record.metadata.map(|m| m.0),
+            probe: record.probe.map(|p| p.0),
+        })
+        .collect())
+}
+
+/// The migrations under `migrations/postgres`.
+#[cfg(feature = "migrate")]
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+#[axum::async_trait]
+impl Storage for Postgres {
+    #[cfg(feature = "migrate")]
+    async fn migrate(&self) -> Result<(), MigrateError> {
+        self.migrator.run(&self.unpooled_for_migrations().await?).await
+    }
+
+    #[cfg(feature = "migrate")]
+    async fn undo_migrations(&self, target: i64) -> Result<(), MigrateError> {
+        self.migrator.undo(&self.unpooled_for_migrations().await?, target).await
+    }
+
+    #[cfg(feature = "migrate")]
+    fn migrator(&self) -> Option<&Migrator> {
+        Some(&self.migrator)
+    }
+
+    fn expected_schema_version(&self) -> Option<i64> {
+        migrations::expected(env!("HB_POSTGRES_SCHEMA"))
+    }
+
+    async fn applied_migrations(&self) -> sqlx::Result<Option<Vec<AppliedMigration>>> {
+        let pool = self.unpooled().await?;
+        let Some(table) = migrations_table(&pool).await? else {
+            return Ok(None);
+        };
+        let records = sqlx::query_as::<_, (i64, Vec<u8>, bool)>(&format!(
+            "SELECT version, checksum, success FROM {table} ORDER BY version"
+        ))
+        .fetch_all(&pool)
+        .await?;
+        Ok(Some(
+            records
+                .into_iter()
+                .map(|(version, checksum, success)| AppliedMigration {
+                    version,
+                    checksum,
+                    success,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn server_start_time(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        sqlx::query_scalar!(
+            r"
+            WITH dummy AS (
+                INSERT INTO stats (_id)
+                VALUES (0)
+                ON CONFLICT (_id) DO NOTHING
+            )
+            SELECT server_start_time
+            FROM stats
+            WHERE _id = 0;
+            "
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or_default()
+        .unwrap_or(now)
+    }
+
+    async fn add_visits(&self, count: i64) -> sqlx::Result<()> {
+        sqlx::query!(
+            r"
+            UPDATE stats
+            SET total_visits = total_visits + $1
+            RETURNING total_visits;
+            ",
+            count
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn totals(&self) -> sqlx::Result<Totals> {
+        totals(&self.pool).await
+    }
+
+    async fn set_longest_absence(&self, absence: TimeDelta) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE stats SET longest_absence = GREATEST(longest_absence, $1) WHERE _id = 0;",
+            interval(absence)?
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn last_seen(&self) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let last_seen = sqlx::query_scalar!("SELECT last_seen FROM stats;")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(last_seen.flatten())
+    }
+
+    async fn total_beats(&self) -> sqlx::Result<i64> {
+        let total = sqlx::query_scalar!("SELECT SUM(num_beats)::BIGINT AS total_beats FROM devices;")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(total.unwrap_or_default())
+    }
+
+    async fn devices(&self) -> sqlx::Result<Vec<Device>> {
+        devices(&self.pool).await
+    }
+    async fn snapshot(&self) -> sqlx::Result<Snapshot> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+        let devices = devices(&mut *tx).await?;
+        let totals = totals(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(Snapshot { devices, totals })
+    }
+
+    async fn recent_devices(&self, limit: i64) -> sqlx::Result<Vec<Named>> {
+        sqlx::query_as!(Named, "SELECT id, name FROM devices ORDER BY id DESC LIMIT $1;", limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn add_device(&self, device: NewDevice<'_>) -> sqlx::Result<()> {
+        sqlx::query!(
+            r"
+            INSERT INTO devices (id, name, token, signing_secret, public_key, probe)
+            VALUES ($1, $2, $3, $4, $5, $6);
+            ",
+            device.id,
+            device.name,
+            device.token,
+            device.signing_secret,
+            device.public_key,
+            device.probe.map(Json) as _,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn rotate_token(
+        &self,
+        device: i64,
+        token: &str,
+        now: DateTime<Utc>,
+        previous_expires_at: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Option<Named>> {
+        let mut tx = self.pool.begin().await?;
+        let Some(previous_token) = sqlx::query_scalar!("SELECT token FROM devices WHERE id = $1 FOR UPDATE;", device)
+            .fetch_optional(&mut *tx)
+            .await?
+        else {
+            return Ok(None);
+        };
+        sqlx::query!(
+            "DELETE FROM superseded_tokens WHERE device = $1 AND expires_at <= $2;",
+            device,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        if let Some(expires_at) = previous_expires_at {
+            sqlx::query!(
+                r"
+                INSERT INTO superseded_tokens (token, device, superseded_at, expires_at)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (token) DO NOTHING;
+                ",
+                previous_token,
+                device,
+                now,
+                expires_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let named = sqlx::query_as!(
+            Named,
+            "UPDATE devices SET token = $1 WHERE id = $2 RETURNING id, name;",
+            token,
+            device
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(named))
+    }
+
+    async fn set_signing_secret(&self, device: i64, secret: Option<&str>) -> sqlx::Result<Option<Named>> {
+        sqlx::query_as!(
+            Named,
+            "UPDATE devices SET signing_secret = $1 WHERE id = $2 RETURNING id, name;",
+            secret,
+            device
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn set_probe(&self, device: i64, probe: Option<&Probe>) -> sqlx::Result<Option<Named>> {
+        sqlx::query_as!(
+            Named,
+            "UPDATE devices SET probe = $1 WHERE id = $2 RETURNING id, name;",
+            probe.map(Json) as _,
+            device
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn probe_targets(&self) -> sqlx::Result<Vec<ProbeTarget>> {
+        let records = sqlx::query!(
+            r#"SELECT id, name, probe AS "probe!: Json<Probe>" FROM devices WHERE probe IS NOT NULL ORDER BY id;"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|record| ProbeTarget {
+                id: record.id,
+                name: record.name,
+                probe: record.probe.0,
+            })
+            .collect())
+    }
+
+    async fn store_metadata(&self, device: i64, time: DateTime<Utc>, metadata: &Metadata) -> sqlx::Result<()> {
+        sqlx::query!(
+            r"
+            INSERT INTO device_state (device, metadata, updated_at) VALUES ($1, $2, $3)
+            ON CONFLICT (device) DO UPDATE SET metadata = EXCLUDED.metadata, updated_at = EXCLUDED.updated_at;
+            ",
+            device,
+            Json(metadata) as _,
+            time
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn lookup_token(&self, token: &str) -> sqlx::Result<Option<(DeviceAuth, Option<String>)>> {
+        // superseded tokens are only accepted until their grace period runs out
+        let record = sqlx::query!(
+            r#"
+            SELECT id AS "id!", name, signing_secret, 'current'::credential AS "credential!: Credential"
+            FROM devices
+            WHERE token = $1
+            UNION ALL
+            SELECT d.id, d.name, d.signing_secret, 'superseded'::credential
+            FROM superseded_tokens s JOIN devices d ON s.device = d.id
+            WHERE s.token = $1 AND s.expires_at > NOW()
+            LIMIT 1;
+            "#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|record| {
+            (
+                DeviceAuth {
+                    id: record.id,
+                    name: record.name,
+                    credential: record.credential,
+                },
+                record.signing_secret,
+            )
+        }))
+    }
+
+    async fn public_key(&self, device: i64) -> sqlx::Result<Option<(Named, Vec<u8>)>> {
+        let record = sqlx::query!(
+            r#"
+            SELECT id, name, public_key AS "public_key!"
+            FROM devices
+            WHERE id = $1 AND public_key IS NOT NULL;
+            "#,
+            device
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|record| {
+            (
+                Named {
+                    id: record.id,
+                    name: record.name,
+                },
+                record.public_key,
+            )
+        }))
+    }
+
+    async fn signing_secret(&self, device: i64) -> sqlx::Result<Option<(Named, String)>> {
+        let record = sqlx::query!(
+            r#"
+            SELECT id, name, signing_secret AS "signing_secret!"
+            FROM devices
+            WHERE id = $1 AND signing_secret IS NOT NULL;
+            "#,
+            device
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|record| {
+            (
+                Named {
+                    id: record.id,
+                    name: record.name,
+                },
+                record.signing_secret,
+            )
+        }))
+    }
+
+    async fn use_nonce(
+        &self,
+        device: i64,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<bool> {
+        let fresh = sqlx::query!(
+            r"
+            INSERT INTO beat_nonces (device, nonce, expires_at) VALUES ($1, $2, $3)
+            ON CONFLICT (device, nonce) DO UPDATE SET expires_at = EXCLUDED.expires_at
+            WHERE beat_nonces.expires_at <= $4;
+            ",
+            device,
+            nonce,
+            expires_at,
+            now
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            == 1;
+        sqlx::query!("DELETE FROM beat_nonces WHERE expires_at <= $1;", now)
+            .execute(&self.pool)
+            .await?;
+        Ok(fresh)
+    }
+
+    async fn advance_udp_counter(&self, device: i64, counter: i64) -> sqlx::Result<bool> {
+        let advanced = sqlx::query!(
+            "UPDATE devices SET udp_counter = $2 WHERE id = $1 AND udp_counter < $2;",
+            device,
+            counter
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            == 1;
+        Ok(advanced)
+    }
+
+    async fn insert_live_beats(&self, beats: &[NewBeat]) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let time_stamps = beats.iter().map(|beat| beat.time).collect::<Vec<_>>();
+        let devices = beats.iter().map(|beat| beat.device).collect::<Vec<_>>();
+        let credentials = beats.iter().map(|beat| beat.credential).collect::<Vec<_>>();
+        // locking the stats row makes concurrent beats see each other, and
+        // the update returns the last beat from before these
+        let previous = sqlx::query_scalar!(
+            r"
+        WITH inserted AS (
+            INSERT INTO beats (time_stamp, device, credential)
+            SELECT n.time_stamp, n.device, n.credential
+            FROM UNNEST($1::TIMESTAMPTZ[], $2::BIGINT[], $3::credential[]) AS n(time_stamp, device, credential)
+            JOIN devices d ON d.id = n.device
+            ON CONFLICT DO NOTHING
+            RETURNING time_stamp, device, credential
+        ),
+        per_device AS (
+            SELECT
+                device,
+                COUNT(*) AS num_beats,
+                MAX(time_stamp) AS last_beat,
+                (ARRAY_AGG(credential ORDER BY time_stamp DESC))[1] AS last_credential
+            FROM inserted
+            GROUP BY device
+        ),
+        discard AS (
+            UPDATE devices d SET
+                num_beats = d.num_beats + p.num_beats,
+                last_credential = CASE WHEN d.last_beat_at > p.last_beat THEN d.last_credential ELSE p.last_credential END,
+                last_beat_at = GREATEST(d.last_beat_at, p.last_beat)
+            FROM per_device p
+            WHERE d.id = p.device
+        )
+        UPDATE stats s SET last_seen = GREATEST(s.last_seen, (SELECT MAX(time_stamp) FROM inserted))
+        FROM (SELECT last_seen FROM stats FOR UPDATE) previous
+        RETURNING previous.last_seen;
+        ",
+            &time_stamps,
+            &devices,
+            &credentials as &[Credential]
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(previous.flatten())
+    }
+
+    async fn insert_beats(
+        &self,
+        device: i64,
+        credential: Credential,
+        time_stamps: &[DateTime<Utc>],
+    ) -> sqlx::Result<Batch> {
+        let mut tx = self.pool.begin().await?;
+        let inserted = sqlx::query!(
+            r#"
+            WITH inserted AS (
+                INSERT INTO beats (time_stamp, device, credential)
+                SELECT time_stamp, $2, $3 FROM UNNEST($1::TIMESTAMPTZ[]) AS t(time_stamp)
+                ON CONFLICT DO NOTHING
+                RETURNING time_stamp
+            )
+            SELECT
+                COUNT(*) AS "count!",
+                MIN(time_stamp) AS earliest,
+                MAX(time_stamp) AS latest,
+                COALESCE(ARRAY_AGG(time_stamp), '{}') AS "time_stamps!"
+            FROM inserted;
+            "#,
+            time_stamps,
+            device,
+            credential as Credential
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r"
+            UPDATE devices SET
+                num_beats = num_beats + $1,
+                last_credential = CASE
+                    WHEN $3::TIMESTAMPTZ IS NULL OR last_beat_at > $3 THEN last_credential
+                    ELSE $4
+                END,
+                last_beat_at = GREATEST(last_beat_at, $3)
+            WHERE id = $2;
+            ",
+            inserted.count,
+            device,
+            inserted.latest,
+            credential as Credential
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("UPDATE stats SET last_seen = GREATEST(last_seen, $1);", inserted.latest)
+            .execute(&mut *tx)
+            .await?;
+        // new beats can only split the gaps between their neighbours, so unless
+        // the longest of those was the longest absence, the rest of the beats
+        // needn't be looked at
+        let longest_absence = sqlx::query_scalar!(
+            r#"
+            WITH timeline AS NOT MATERIALIZED (
+                SELECT device, time_stamp FROM beats
+                UNION ALL
+                SELECT NULL, MAX(last_beat) FROM (
+                    SELECT last_beat FROM beats_hourly UNION ALL SELECT last_beat FROM beats_daily
+                ) rollups
+            ),
+            nearby AS (
+                SELECT device, time_stamp FROM timeline WHERE time_stamp BETWEEN
+                    COALESCE((SELECT MAX(time_stamp) FROM timeline WHERE time_stamp < $2), $2)
+                    AND COALESCE((SELECT MIN(time_stamp) FROM timeline WHERE time_stamp > $3), $3)
+            ),
+            gaps AS (
+                SELECT
+                    (SELECT MAX(gap) FROM (
+                        SELECT time_stamp - LAG(time_stamp) OVER (ORDER BY time_stamp) AS gap FROM nearby
+                        WHERE device IS DISTINCT FROM $1 OR time_stamp <> ALL($4)
+                    ) old_gaps) AS old_gap,
+                    (SELECT MAX(gap) FROM (
+                        SELECT time_stamp - LAG(time_stamp) OVER (ORDER BY time_stamp) AS gap FROM nearby
+                    ) new_gaps) AS new_gap
+            )
+            UPDATE stats SET longest_absence = CASE
+                WHEN old_gap IS NULL OR old_gap < longest_absence THEN GREATEST(longest_absence, new_gap)
+                ELSE GREATEST(compacted_absence, COALESCE((
+                    SELECT MAX(gap) FROM (
+                        SELECT time_stamp - LAG(time_stamp) OVER (ORDER BY time_stamp) AS gap FROM timeline
+                    ) gaps
+                ), '0 seconds'))
+            END
+            FROM gaps
+            RETURNING EXTRACT(epoch FROM longest_absence)::BIGINT AS "longest_absence!";
+            "#,
+            device,
+            inserted.earliest,
+            inserted.latest,
+            &inserted.time_stamps
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Batch {
+            inserted: inserted.count,
+            latest: inserted.latest,
+            longest_absence: TimeDelta::seconds(longest_absence),
+        })
+    }
+
+    async fn absences(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>> {
+        let records = sqlx::query!(
+            r#"
+            SELECT previous AS "start!", first_beat AS "end!"
+            FROM (
+                SELECT
+                    first_beat,
+                    MAX(last_beat) OVER (ORDER BY first_beat ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING)
+                        AS previous
+                FROM beat_runs
+                WHERE ($1::BIGINT IS NULL OR device = $1) AND last_beat >= $2
+            ) gaps
+            WHERE first_beat - previous >= $3
+            ORDER BY previous;
+            "#,
+            device,
+            since,
+            interval(threshold)?
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records.into_iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    async fn active_spans(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>> {
+        let records = sqlx::query!(
+            r#"
+            SELECT MIN(first_beat) AS "start!", MAX(last_beat) AS "end!"
+            FROM (
+                SELECT
+                    first_beat,
+                    last_beat,
+                    COUNT(*) FILTER (WHERE first_beat - previous >= $3) OVER (ORDER BY first_beat) AS span
+                FROM (
+                    SELECT
+                        first_beat,
+                        last_beat,
+                        MAX(last_beat) OVER (ORDER BY first_beat ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING)
+                            AS previous
+                    FROM beat_runs
+                    WHERE ($1::BIGINT IS NULL OR device = $1) AND last_beat >= $2
+                ) gaps
+            ) spans
+            GROUP BY span
+            HAVING MAX(last_beat) > MIN(first_beat)
+            ORDER BY 1;
+            "#,
+            device,
+            since,
+            interval(threshold)?
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records.into_iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    async fn compact(
+        &self,
+        raw_before: DateTime<Utc>,
+        hourly_before: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Compacted> {
+        let mut tx = self.pool.begin().await?;
+        // the gaps between the beats that are about to be compacted, and the
+        // one after the last beat that already was
+        sqlx::query!(
+            r"
+            UPDATE stats SET compacted_absence = GREATEST(compacted_absence, COALESCE((
+                SELECT MAX(gap) FROM (
+                    SELECT time_stamp - LAG(time_stamp) OVER (ORDER BY time_stamp) AS gap FROM (
+                        SELECT time_stamp FROM beats WHERE time_stamp < $1
+                        UNION ALL
+                        SELECT MAX(last_beat) FROM (
+                            SELECT last_beat FROM beats_hourly
+                            UNION ALL
+                            SELECT last_beat FROM beats_daily
+                        ) rollups
+                    ) beats
+                ) gaps
+            ), '0 seconds'));
+            ",
+            raw_before
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r"
+            INSERT INTO beats_hourly (device, hour, num_beats, first_beat, last_beat, last_credential)
+            SELECT
+                device,
+                DATE_TRUNC('hour', time_stamp AT TIME ZONE 'UTC') AT TIME ZONE 'UTC',
+                COUNT(*),
+                MIN(time_stamp),
+                MAX(time_stamp),
+                (ARRAY_AGG(credential ORDER BY time_stamp DESC))[1]
+            FROM beats
+            WHERE time_stamp < $1
+            GROUP BY 1, 2
+            ON CONFLICT (device, hour) DO UPDATE SET
+                num_beats = beats_hourly.num_beats + EXCLUDED.num_beats,
+                first_beat = LEAST(beats_hourly.first_beat, EXCLUDED.first_beat),
+                last_beat = GREATEST(beats_hourly.last_beat, EXCLUDED.last_beat),
+                last_credential = CASE
+                    WHEN EXCLUDED.last_beat > beats_hourly.last_beat THEN EXCLUDED.last_credential
+                    ELSE beats_hourly.last_credential
+                END;
+            ",
+            raw_before
+        )
+        .execute(&mut *tx)
+        .await?;
+        let beats = sqlx::query!("DELETE FROM beats WHERE time_stamp < $1;", raw_before)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        let hours = if let Some(hourly_before) = hourly_before {
+            sqlx::query!(
+                r"
+                INSERT INTO beats_daily (device, day, num_beats, first_beat, last_beat, last_credential)
+                SELECT
+                    device,
+                    DATE_TRUNC('day', hour AT TIME ZONE 'UTC') AT TIME ZONE 'UTC',
+                    SUM(num_beats)::BIGINT,
+                    MIN(first_beat),
+                    MAX(last_beat),
+                    (ARRAY_AGG(last_credential ORDER BY last_beat DESC))[1]
+                FROM beats_hourly
+                WHERE hour < $1
+                GROUP BY 1, 2
+                ON CONFLICT (device, day) DO UPDATE SET
+                    num_beats = beats_daily.num_beats + EXCLUDED.num_beats,
+                    first_beat = LEAST(beats_daily.first_beat, EXCLUDED.first_beat),
+                    last_beat = GREATEST(beats_daily.last_beat, EXCLUDED.last_beat),
+                    last_credential = CASE
+                        WHEN EXCLUDED.last_beat > beats_daily.last_beat THEN EXCLUDED.last_credential
+                        ELSE beats_daily.last_credential
+                    END;
+                ",
+                hourly_before
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!("DELETE FROM beats_hourly WHERE hour < $1;", hourly_before)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+        } else {
+            0
+        };
+        tx.commit().await?;
+        Ok(Compacted { beats, hours })
+    }
+
+    async fn insert_event(&self, event: StoredEvent) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO events (id, kind, time_stamp, started_at, device)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (id) DO NOTHING;
+            "#,
+            event.id,
+            event.kind as StoredKind,
+            event.time,
+            event.started_at,
+            event.device
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn recent_events(&self, limit: i64) -> sqlx::Result<Vec<StoredEvent>> {
+        sqlx::query_as!(
+            StoredEvent,
+            r#"
+            SELECT id, kind AS "kind: StoredKind", time_stamp AS time, started_at, device
+            FROM events
+            ORDER BY time_stamp DESC
+            LIMIT $1;
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn notify(&self, change: &str) -> sqlx::Result<()> {
+        sqlx::query!("SELECT pg_notify($1, $2)", self.channel, change)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn listen(&self) -> sqlx::Result<Option<Box<dyn Listener>>> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(&self.channel).await?;
+        Ok(Some(Box::new(listener)))
+    }
+
+    async fn lead(&self) -> sqlx::Result<bool> {
+        let mut leader = self.leader.lock().await;
+        if let Some(conn) = leader.as_mut() {
+            if let Err(e) = conn.ping().await {
+                // closing the connection releases the lock if the server still has it
+                *leader = None;
+                return Err(e);
+            }
+            return Ok(true);
+        }
+        // a connection of its own, so the lock isn't left behind in the pool
+        let mut conn = self.pool.acquire().await?.detach();
+        let locked = sqlx::query_scalar!("SELECT pg_try_advisory_lock($1)", self.leader_lock)
+            .fetch_one(&mut conn)
+            .await?
+            .unwrap_or_default();
+        if locked {
+            *leader = Some(conn);
+        }
+        drop(leader);
+        Ok(locked)
+    }
+}
+
+#[axum::async_trait]
+impl Listener for PgListener {
+    async fn recv(&mut self) -> sqlx::Result<Option<String>> {
+        Ok(self.try_recv().await?.map(|n| n.payload().to_owned()))
+    }
+}
+
+#[cfg(all(test, feature = "migrate"))]
+mod tests {
+    use super::*;
+
+    /// Migrates two schemas of the database in `HEARTBEAT_TEST_POSTGRES_DSN`,
+    /// which is skipped if it isn't set.
+    #[tokio::test]
+    async fn test_migrate_two_schemas() {
+        let Ok(dsn) = std::env::var("HEARTBEAT_TEST_POSTGRES_DSN") else {
+            return;
+        };
+        let connect = |schema: &str| {
+            let options = ConnectOptions {
+                schema: schema.into(),
+                ..ConnectOptions::default()
+            };
+            Postgres::connect(&dsn, &options).expect("valid DSN")
+        };
+        let (first, second) = (connect("hb_test_first"), connect("hb_test_second"));
+        for storage in [&first, &second] {
+            sqlx::query(&format!("DROP SCHEMA IF EXISTS {} CASCADE", quote(&storage.schema)))
+                .execute(&storage.unpooled().await.expect("connection"))
+                .await
+                .expect("schema to be dropped");
+        }
+        first.migrate().await.expect("first schema to be migrated");
+        second.migrate().await.expect("second schema to be migrated");
+        for storage in [&first, &second] {
+            let applied = storage
+                .applied_migrations()
+                .await
+                .expect("migrations to be read")
+                .expect("migrations to be recorded");
+            assert_eq!(
+                applied.len(),
+                MIGRATOR
+                    .iter()
+                    .filter(|m| !m.migration_type.is_down_migration())
+                    .count()
+            );
+            let recorded_in = sqlx::query_scalar::<_, String>(
+                "SELECT schemaname::TEXT FROM pg_tables WHERE tablename = '_sqlx_migrations' AND schemaname = \
+                 current_schema()",
+            )
+            .fetch_one(&storage.pool)
+            .await
+            .expect("migrations table to be in the schema");
+            assert_eq!(recorded_in, storage.schema);
+            storage.server_start_time().await;
+            assert!(storage.snapshot().await.is_ok());
+        }
+        assert_ne!(first.channel, second.channel);
+        assert_ne!(first.leader_lock, second.leader_lock);
+        assert!(first.lead().await.expect("lock to be taken"));
+        assert!(second.lead().await.expect("lock to be taken"));
+    }
+
+    #[test]
+    fn test_coordination() {
+        assert_eq!(coordination("heartbeat"), (CHANNEL.to_owned(), LEADER_LOCK));
+        let (channel, lock) = coordination(&"x".repeat(63));
+        assert!(channel.len() <= 63);
+        assert_ne!(lock, LEADER_LOCK);
+        assert_ne!(coordination("a"), coordination("b"));
+    }
+
+    #[test]
+    fn test_in_schema() {
+        let sql = "CREATE SCHEMA IF NOT EXISTS heartbeat;\n\
+                   -- see https://github.com/lmaotrigine/heartbeat.\n\
+                   ALTER TABLE heartbeat.beats ADD COLUMN credential heartbeat.credential;\n\
+                   SELECT 'x'::heartbeat.credential FROM my_heartbeat.beats;";
+        assert_eq!(
+            in_schema(sql, "hb\"2"),
+            "CREATE SCHEMA IF NOT EXISTS \"hb\"\"2\";\n\
+             -- see https://github.com/lmaotrigine/heartbeat.\n\
+             ALTER TABLE \"hb\"\"2\".beats ADD COLUMN credential \"hb\"\"2\".credential;\n\
+             SELECT 'x'::\"hb\"\"2\".credential FROM my_heartbeat.beats;"
+        );
+    }
+}
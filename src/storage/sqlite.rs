@@ -0,0 +1,832 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// The query macros are only checked against PostgreSQL, so the queries here
+// are checked at runtime instead. Timestamps are stored as RFC 3339 text,
+// which sorts correctly and is understood by SQLite's date functions.
+
+use super::migrations::{self, AppliedMigration};
+use super::{
+    Batch, Compacted, ConnectOptions, Named, NewBeat, NewDevice, ProbeTarget, Snapshot, Span, Storage, StoredEvent,
+    Totals,
+};
+use crate::{
+    auth::Device as DeviceAuth,
+    devices::{Credential, Device, Metadata, Probe},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+#[cfg(feature = "migrate")]
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    types::Json,
+    QueryBuilder, SqliteExecutor, SqlitePool,
+};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// Stores everything in an `SQLite` database, which is created if it doesn't
+/// exist.
+#[derive(Debug, Clone)]
+pub struct Sqlite {
+    pool: SqlitePool,
+}
+
+impl Sqlite {
+    /// Creates a pool for the database at `dsn`, without opening it yet. The
+    /// schema and statement timeout of `options` don't apply to `SQLite`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the DSN is invalid.
+    pub fn connect(dsn: &str, options: &ConnectOptions) -> sqlx::Result<Self> {
+        let connect = SqliteConnectOptions::from_str(dsn)?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::default()
+            .max_connections(options.max_connections)
+            .min_connections(options.min_connections)
+            .acquire_timeout(options.acquire_timeout)
+            .idle_timeout(options.idle_timeout)
+            .connect_lazy_with(connect);
+        Ok(Self { pool })
+    }
+}
+
+/// The number of seconds in a [`TimeDelta`], as it is compared with the
+/// difference of two `julianday`s.
+#[allow(clippy::cast_precision_loss)]
+fn seconds(delta: TimeDelta) -> f64 {
+    delta.num_milliseconds() as f64 / 1000.0
+}
+
+/// The difference in seconds between two timestamp columns.
+macro_rules! gap {
+    ($to:literal, $from:literal) => {
+        concat!("(julianday(", $to, ") - julianday(", $from, ")) * 86400.0")
+    };
+}
+
+/// The longest gap between the beats matching `$filter`, and between the last
+/// of them and the last compacted beat, in whole seconds.
+macro_rules! longest_gap {
+    ($filter:literal) => {
+        concat!(
+            "SELECT CAST(MAX(gap) AS INTEGER) FROM (SELECT ",
+            gap!("time_stamp", "LAG(time_stamp) OVER (ORDER BY time_stamp)"),
+            " AS gap FROM (SELECT time_stamp FROM beats WHERE ",
+            $filter,
+            " UNION ALL SELECT MAX(last_beat) FROM (",
+            "SELECT last_beat FROM beats_hourly UNION ALL SELECT last_beat FROM beats_daily)))"
+        )
+    };
+}
+
+async fn totals(executor: impl SqliteExecutor<'_>) -> sqlx::Result<Totals> {
+    let record = sqlx::query_as::<_, (i64, i64, Option<DateTime<Utc>>)>(
+        "SELECT total_visits, longest_absence, last_seen FROM stats;",
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(
+        record.map_or_else(Totals::default, |(num_visits, longest_absence, last_seen)| Totals {
+            num_visits,
+            longest_absence: TimeDelta::seconds(longest_absence),
+            last_seen,
+        }),
+    )
+}
+
+async fn devices(executor: impl SqliteExecutor<'_>) -> sqlx::Result<Vec<Device>> {
+    type Row = (
+        i64,
+        Option<String>,
+        i64,
+        Option<DateTime<Utc>>,
+        Option<Credential>,
+        Option<Json<Metadata>>,
+        Option<Json<Probe>>,
+    );
+    let records = sqlx::query_as::<_, Row>(
+        r"
+        SELECT
+            d.id,
+            d.name,
+            d.num_beats,
+            d.last_beat_at,
+            d.last_credential,
+            s.metadata,
+            d.probe
+        FROM devices d
+        LEFT JOIN device_state s ON s.device = d.id
+        ORDER BY d.id;
+        ",
+    )
+    .fetch_all(executor)
+    .await?;
+    Ok(records
+        .into_iter()
+        .map(
+            |(id, name, num_beats, last_beat, last_credential, metadata, probe)| Device {
+                id,
+                name,
+                last_beat,
+                num_beats,
+                last_credential,
+                metadata: metadata.map(|m| m.0),
+                probe: probe.map(|p| p.0),
+            },
+        )
+        .collect())
+}
+
+/// The migrations under `migrations/sqlite`.
+#[cfg(feature = "migrate")]
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+#[axum::async_trait]
+impl Storage for Sqlite {
+    #[cfg(feature = "migrate")]
+    async fn migrate(&self) -> Result<(), MigrateError> {
+        MIGRATOR.run(&self.pool).await
+    }
+
+    #[cfg(feature = "migrate")]
+    async fn undo_migrations(&self, target: i64) -> Result<(), MigrateError> {
+        MIGRATOR.undo(&self.pool, target).await
+    }
+
+    #[cfg(feature = "migrate")]
+    fn migrator(&self) -> Option<&Migrator> {
+        Some(&MIGRATOR)
+    }
+
+    fn expected_schema_version(&self) -> Option<i64> {
+        migrations::expected(env!("HB_SQLITE_SCHEMA"))
+    }
+
+    async fn applied_migrations(&self) -> sqlx::Result<Option<Vec<AppliedMigration>>> {
+        // the table is only created by the first migration, so these can't be
+        // checked at compile time
+        let exists = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !exists {
+            return Ok(None);
+        }
+        let records = sqlx::query_as::<_, (i64, Vec<u8>, bool)>(
+            "SELECT version, checksum, success FROM _sqlx_migrations ORDER BY version",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Some(
+            records
+                .into_iter()
+                .map(|(version, checksum, success)| AppliedMigration {
+                    version,
+                    checksum,
+                    success,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn server_start_time(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        let _ = sqlx::query("INSERT INTO stats (_id, server_start_time) VALUES (0, ?) ON CONFLICT (_id) DO NOTHING;")
+            .bind(now)
+            .execute(&self.pool)
+            .await;
+        sqlx::query_scalar("SELECT server_start_time FROM stats WHERE _id = 0;")
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or_default()
+            .unwrap_or(now)
+    }
+
+    async fn add_visits(&self, count: i64) -> sqlx::Result<()> {
+        sqlx::query("UPDATE stats SET total_visits = total_visits + ?;")
+            .bind(count)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn totals(&self) -> sqlx::Result<Totals> {
+        totals(&self.pool).await
+    }
+
+    async fn set_longest_absence(&self, absence: TimeDelta) -> sqlx::Result<()> {
+        sqlx::query("UPDATE stats SET longest_absence = MAX(longest_absence, ?) WHERE _id = 0;")
+            .bind(absence.num_seconds())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn last_seen(&self) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let last_seen = sqlx::query_scalar("SELECT last_seen FROM stats;")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(last_seen.flatten())
+    }
+
+    async fn total_beats(&self) -> sqlx::Result<i64> {
+        sqlx::query_scalar("SELECT COALESCE(SUM(num_beats), 0) FROM devices;")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn devices(&self) -> sqlx::Result<Vec<Device>> {
+        devices(&self.pool).await
+    }
+    async fn snapshot(&self) -> sqlx::Result<Snapshot> {
+        // reads within a transaction see the same snapshot of the database
+        let mut tx = self.pool.begin().await?;
+        let devices = devices(&mut *tx).await?;
+        let totals = totals(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(Snapshot { devices, totals })
+    }
+
+    async fn recent_devices(&self, limit: i64) -> sqlx::Result<Vec<Named>> {
+        let records =
+            sqlx::query_as::<_, (i64, Option<String>)>("SELECT id, name FROM devices ORDER BY id DESC LIMIT ?;")
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(records.into_iter().map(|(id, name)| Named { id, name }).collect())
+    }
+
+    async fn add_device(&self, device: NewDevice<'_>) -> sqlx::Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO devices (id, name, token, signing_secret, public_key, probe)
+            VALUES (?, ?, ?, ?, ?, ?);
+            ",
+        )
+        .bind(device.id)
+        .bind(device.name)
+        .bind(device.token)
+        .bind(device.signing_secret)
+        .bind(device.public_key)
+        .bind(device.probe.map(Json))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn rotate_token(
+        &self,
+        device: i64,
+        token: &str,
+        now: DateTime<Utc>,
+        previous_expires_at: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Option<Named>> {
+        let mut tx = self.pool.begin().await?;
+        // writing first takes the database's write lock, which stands in for
+        // `SELECT ... FOR UPDATE`
+        sqlx::query("DELETE FROM superseded_tokens WHERE device = ? AND expires_at <= ?;")
+            .bind(device)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        let Some(previous_token) = sqlx::query_scalar::<_, String>("SELECT token FROM devices WHERE id = ?;")
+            .bind(device)
+            .fetch_optional(&mut *tx)
+            .await?
+        else {
+            return Ok(None);
+        };
+        if let Some(expires_at) = previous_expires_at {
+            sqlx::query(
+                r"
+                INSERT INTO superseded_tokens (token, device, superseded_at, expires_at)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT (token) DO NOTHING;
+                ",
+            )
+            .bind(previous_token)
+            .bind(device)
+            .bind(now)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let (id, name) =
+            sqlx::query_as::<_, (i64, Option<String>)>("UPDATE devices SET token = ? WHERE id = ? RETURNING id, name;")
+                .bind(token)
+                .bind(device)
+                .fetch_one(&mut *tx)
+                .await?;
+        tx.commit().await?;
+        Ok(Some(Named { id, name }))
+    }
+
+    async fn set_signing_secret(&self, device: i64, secret: Option<&str>) -> sqlx::Result<Option<Named>> {
+        let record = sqlx::query_as::<_, (i64, Option<String>)>(
+            "UPDATE devices SET signing_secret = ? WHERE id = ? RETURNING id, name;",
+        )
+        .bind(secret)
+        .bind(device)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|(id, name)| Named { id, name }))
+    }
+
+    async fn set_probe(&self, device: i64, probe: Option<&Probe>) -> sqlx::Result<Option<Named>> {
+        let record =
+            sqlx::query_as::<_, (i64, Option<String>)>("UPDATE devices SET probe = ? WHERE id = ? RETURNING id, name;")
+                .bind(probe.map(Json))
+                .bind(device)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(record.map(|(id, name)| Named { id, name }))
+    }
+
+    async fn probe_targets(&self) -> sqlx::Result<Vec<ProbeTarget>> {
+        let records = sqlx::query_as::<_, (i64, Option<String>, Json<Probe>)>(
+            "SELECT id, name, probe FROM devices WHERE probe IS NOT NULL ORDER BY id;",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|(id, name, probe)| ProbeTarget {
+                id,
+                name,
+                probe: probe.0,
+            })
+            .collect())
+    }
+
+    async fn store_metadata(&self, device: i64, time: DateTime<Utc>, metadata: &Metadata) -> sqlx::Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO device_state (device, metadata, updated_at) VALUES (?, ?, ?)
+            ON CONFLICT (device) DO UPDATE SET metadata = excluded.metadata, updated_at = excluded.updated_at;
+            ",
+        )
+        .bind(device)
+        .bind(Json(metadata))
+        .bind(time)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn lookup_token(&self, token: &str) -> sqlx::Result<Option<(DeviceAuth, Option<String>)>> {
+        // superseded tokens are only accepted until their grace period runs out
+        let record = sqlx::query_as::<_, (i64, Option<String>, Option<String>, Credential)>(
+            r"
+            SELECT id, name, signing_secret, 'current'
+            FROM devices
+            WHERE token = ?1
+            UNION ALL
+            SELECT d.id, d.name, d.signing_secret, 'superseded'
+            FROM superseded_tokens s JOIN devices d ON s.device = d.id
+            WHERE s.token = ?1 AND s.expires_at > ?2
+            LIMIT 1;
+            ",
+        )
+        .bind(token)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|(id, name, signing_secret, credential)| (DeviceAuth { id, name, credential }, signing_secret)))
+    }
+
+    async fn public_key(&self, device: i64) -> sqlx::Result<Option<(Named, Vec<u8>)>> {
+        let record = sqlx::query_as::<_, (i64, Option<String>, Vec<u8>)>(
+            "SELECT id, name, public_key FROM devices WHERE id = ? AND public_key IS NOT NULL;",
+        )
+        .bind(device)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|(id, name, public_key)| (Named { id, name }, public_key)))
+    }
+
+    async fn signing_secret(&self, device: i64) -> sqlx::Result<Option<(Named, String)>> {
+        let record = sqlx::query_as::<_, (i64, Option<String>, String)>(
+            "SELECT id, name, signing_secret FROM devices WHERE id = ? AND signing_secret IS NOT NULL;",
+        )
+        .bind(device)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|(id, name, signing_secret)| (Named { id, name }, signing_secret)))
+    }
+
+    async fn use_nonce(
+        &self,
+        device: i64,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> sqlx::Result<bool> {
+        let fresh = sqlx::query(
+            r"
+            INSERT INTO beat_nonces (device, nonce, expires_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (device, nonce) DO UPDATE SET expires_at = excluded.expires_at
+            WHERE beat_nonces.expires_at <= ?4;
+            ",
+        )
+        .bind(device)
+        .bind(nonce)
+        .bind(expires_at)
+        .bind(now)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            == 1;
+        sqlx::query("DELETE FROM beat_nonces WHERE expires_at <= ?;")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(fresh)
+    }
+
+    async fn advance_udp_counter(&self, device: i64, counter: i64) -> sqlx::Result<bool> {
+        let advanced = sqlx::query("UPDATE devices SET udp_counter = ?2 WHERE id = ?1 AND udp_counter < ?2;")
+            .bind(device)
+            .bind(counter)
+            .execute(&self.pool)
+            .await?
+            .rows_affected()
+            == 1;
+        Ok(advanced)
+    }
+
+    async fn insert_live_beats(&self, beats: &[NewBeat]) -> sqlx::Result<Option<DateTime<Utc>>> {
+        if beats.is_empty() {
+            return self.last_seen().await;
+        }
+        let mut tx = self.pool.begin().await?;
+        // writing first takes the database's write lock, so concurrent beats
+        // see each other
+        let mut query = QueryBuilder::new("WITH new (time_stamp, device, credential) AS (");
+        query.push_values(beats, |mut row, beat| {
+            row.push_bind(beat.time)
+                .push_bind(beat.device)
+                .push_bind(beat.credential);
+        });
+        query.push(
+            r"
+            )
+            INSERT INTO beats (time_stamp, device, credential)
+            SELECT time_stamp, device, credential FROM new WHERE device IN (SELECT id FROM devices)
+            ON CONFLICT DO NOTHING
+            RETURNING time_stamp, device, credential;
+            ",
+        );
+        let inserted = query
+            .build_query_as::<(DateTime<Utc>, i64, Credential)>()
+            .fetch_all(&mut *tx)
+            .await?;
+        let mut per_device = BTreeMap::<i64, (i64, DateTime<Utc>, Credential)>::new();
+        for (time, device, credential) in inserted {
+            let (num_beats, last_beat, last_credential) = per_device.entry(device).or_insert((0, time, credential));
+            *num_beats += 1;
+            if time > *last_beat {
+                (*last_beat, *last_credential) = (time, credential);
+            }
+        }
+        for (device, (num_beats, last_beat, last_credential)) in &per_device {
+            sqlx::query(
+                r"
+                UPDATE devices SET
+                    num_beats = num_beats + ?1,
+                    last_credential = CASE WHEN last_beat_at > ?3 THEN last_credential ELSE ?4 END,
+                    last_beat_at = MAX(COALESCE(last_beat_at, ?3), ?3)
+                WHERE id = ?2;
+                ",
+            )
+            .bind(num_beats)
+            .bind(device)
+            .bind(last_beat)
+            .bind(last_credential)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let previous = sqlx::query_scalar::<_, Option<DateTime<Utc>>>("SELECT last_seen FROM stats;")
+            .fetch_optional(&mut *tx)
+            .await?
+            .flatten();
+        if let Some(latest) = per_device.values().map(|(_, last_beat, _)| *last_beat).max() {
+            sqlx::query("UPDATE stats SET last_seen = MAX(COALESCE(last_seen, ?1), ?1);")
+                .bind(latest)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(previous)
+    }
+
+    async fn insert_beats(
+        &self,
+        device: i64,
+        credential: Credential,
+        time_stamps: &[DateTime<Utc>],
+    ) -> sqlx::Result<Batch> {
+        let mut tx = self.pool.begin().await?;
+        let (mut inserted, mut earliest, mut latest, mut rows) = (0, None, None, Vec::new());
+        for &time_stamp in time_stamps {
+            let row = sqlx::query_scalar::<_, i64>(
+                "INSERT INTO beats (time_stamp, device, credential) VALUES (?, ?, ?) ON CONFLICT DO NOTHING RETURNING rowid;",
+            )
+            .bind(time_stamp)
+            .bind(device)
+            .bind(credential)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(row) = row {
+                inserted += 1;
+                earliest = Some(earliest.map_or(time_stamp, |earliest: DateTime<Utc>| earliest.min(time_stamp)));
+                latest = latest.max(Some(time_stamp));
+                rows.push(row);
+            }
+        }
+        sqlx::query(
+            r"
+            UPDATE devices SET
+                num_beats = num_beats + ?1,
+                last_credential = CASE
+                    WHEN ?3 IS NULL OR last_beat_at > ?3 THEN last_credential
+                    ELSE ?4
+                END,
+                last_beat_at = MAX(COALESCE(last_beat_at, ?3), COALESCE(?3, last_beat_at))
+            WHERE id = ?2;
+            ",
+        )
+        .bind(inserted)
+        .bind(device)
+        .bind(latest)
+        .bind(credential)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE stats SET last_seen = MAX(COALESCE(last_seen, ?1), COALESCE(?1, last_seen));")
+            .bind(latest)
+            .execute(&mut *tx)
+            .await?;
+        // new beats can only split the gaps between their neighbours, so unless
+        // the longest of those was the longest absence, the rest of the beats
+        // needn't be looked at
+        let longest_absence = sqlx::query_scalar::<_, i64>(concat!(
+            r"
+            WITH timeline AS (
+                SELECT rowid AS id, time_stamp FROM beats
+                UNION ALL
+                SELECT NULL, MAX(last_beat) FROM (
+                    SELECT last_beat FROM beats_hourly UNION ALL SELECT last_beat FROM beats_daily
+                )
+            ),
+            nearby AS (
+                SELECT id, time_stamp FROM timeline WHERE time_stamp BETWEEN
+                    COALESCE((SELECT MAX(time_stamp) FROM timeline WHERE time_stamp < ?1), ?1)
+                    AND COALESCE((SELECT MIN(time_stamp) FROM timeline WHERE time_stamp > ?2), ?2)
+            ),
+            gaps AS (
+                SELECT
+                    (SELECT CAST(MAX(gap) AS INTEGER) FROM (SELECT ",
+            gap!("time_stamp", "LAG(time_stamp) OVER (ORDER BY time_stamp)"),
+            r" AS gap FROM nearby
+                        WHERE id IS NULL OR id NOT IN (SELECT value FROM json_each(?3))
+                    )) AS old_gap,
+                    (SELECT CAST(MAX(gap) AS INTEGER) FROM (SELECT ",
+            gap!("time_stamp", "LAG(time_stamp) OVER (ORDER BY time_stamp)"),
+            r" AS gap FROM nearby)) AS new_gap
+            )
+            UPDATE stats SET longest_absence = CASE
+                WHEN (SELECT old_gap FROM gaps) IS NULL OR (SELECT old_gap FROM gaps) < longest_absence
+                    THEN MAX(longest_absence, COALESCE((SELECT new_gap FROM gaps), 0))
+                ELSE MAX(compacted_absence, COALESCE((",
+            longest_gap!("TRUE"),
+            "), 0))
+            END
+            RETURNING longest_absence;"
+        ))
+        .bind(earliest)
+        .bind(latest)
+        .bind(serde_json::to_string(&rows).map_err(|error| sqlx::Error::Encode(error.into()))?)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Batch {
+            inserted,
+            latest,
+            longest_absence: TimeDelta::seconds(longest_absence),
+        })
+    }
+
+    async fn absences(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>> {
+        sqlx::query_as(concat!(
+            r"
+            SELECT previous, first_beat
+            FROM (
+                SELECT
+                    first_beat,
+                    MAX(last_beat) OVER (ORDER BY first_beat ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING)
+                        AS previous
+                FROM beat_runs
+                WHERE (?1 IS NULL OR device = ?1) AND last_beat >= ?2
+            )
+            WHERE previous IS NOT NULL AND ",
+            gap!("first_beat", "previous"),
+            " >= ?3 ORDER BY previous;"
+        ))
+        .bind(device)
+        .bind(since)
+        .bind(seconds(threshold))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn active_spans(
+        &self,
+        device: Option<i64>,
+        since: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> sqlx::Result<Vec<Span>> {
+        sqlx::query_as(concat!(
+            r"
+            SELECT MIN(first_beat), MAX(last_beat)
+            FROM (
+                SELECT first_beat, last_beat, SUM(CASE WHEN ",
+            gap!("first_beat", "previous"),
+            r" >= ?3 THEN 1 ELSE 0 END) OVER (ORDER BY first_beat) AS span
+                FROM (
+                    SELECT
+                        first_beat,
+                        last_beat,
+                        MAX(last_beat) OVER (ORDER BY first_beat ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING)
+                            AS previous
+                    FROM beat_runs
+                    WHERE (?1 IS NULL OR device = ?1) AND last_beat >= ?2
+                )
+            )
+            GROUP BY span
+            HAVING MAX(last_beat) > MIN(first_beat)
+            ORDER BY 1;
+            "
+        ))
+        .bind(device)
+        .bind(since)
+        .bind(seconds(threshold))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn compact(
+        &self,
+        raw_before: DateTime<Utc>,
+        hourly_before: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<Compacted> {
+        let mut tx = self.pool.begin().await?;
+        // the gaps between the beats that are about to be compacted, and the
+        // one after the last beat that already was
+        sqlx::query(concat!(
+            "UPDATE stats SET compacted_absence = MAX(compacted_absence, COALESCE((",
+            longest_gap!("time_stamp < ?1"),
+            "), 0));"
+        ))
+        .bind(raw_before)
+        .execute(&mut *tx)
+        .await?;
+        // the timestamps are all in UTC, so truncating them is a matter of
+        // formatting
+        sqlx::query(
+            r"
+            WITH runs AS (
+                SELECT
+                    device,
+                    strftime('%Y-%m-%dT%H:00:00+00:00', time_stamp) AS hour,
+                    COUNT(*) AS num_beats,
+                    MIN(time_stamp) AS first_beat,
+                    MAX(time_stamp) AS last_beat
+                FROM beats
+                WHERE time_stamp < ?1
+                GROUP BY 1, 2
+            )
+            INSERT INTO beats_hourly (device, hour, num_beats, first_beat, last_beat, last_credential)
+            SELECT
+                device,
+                hour,
+                num_beats,
+                first_beat,
+                last_beat,
+                (SELECT credential FROM beats WHERE device = runs.device AND time_stamp = runs.last_beat LIMIT 1)
+            FROM runs
+            WHERE TRUE
+            ON CONFLICT (device, hour) DO UPDATE SET
+                num_beats = beats_hourly.num_beats + excluded.num_beats,
+                first_beat = MIN(beats_hourly.first_beat, excluded.first_beat),
+                last_beat = MAX(beats_hourly.last_beat, excluded.last_beat),
+                last_credential = CASE
+                    WHEN excluded.last_beat > beats_hourly.last_beat THEN excluded.last_credential
+                    ELSE beats_hourly.last_credential
+                END;
+            ",
+        )
+        .bind(raw_before)
+        .execute(&mut *tx)
+        .await?;
+        let beats = sqlx::query("DELETE FROM beats WHERE time_stamp < ?;")
+            .bind(raw_before)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        let hours = if let Some(hourly_before) = hourly_before {
+            sqlx::query(
+                r"
+                WITH runs AS (
+                    SELECT
+                        device,
+                        strftime('%Y-%m-%dT00:00:00+00:00', hour) AS day,
+                        SUM(num_beats) AS num_beats,
+                        MIN(first_beat) AS first_beat,
+                        MAX(last_beat) AS last_beat
+                    FROM beats_hourly
+                    WHERE hour < ?1
+                    GROUP BY 1, 2
+                )
+                INSERT INTO beats_daily (device, day, num_beats, first_beat, last_beat, last_credential)
+                SELECT
+                    device,
+                    day,
+                    num_beats,
+                    first_beat,
+                    last_beat,
+                    (SELECT last_credential FROM beats_hourly WHERE device = runs.device AND last_beat = runs.last_beat)
+                FROM runs
+                WHERE TRUE
+                ON CONFLICT (device, day) DO UPDATE SET
+                    num_beats = beats_daily.num_beats + excluded.num_beats,
+                    first_beat = MIN(beats_daily.first_beat, excluded.first_beat),
+                    last_beat = MAX(beats_daily.last_beat, excluded.last_beat),
+                    last_credential = CASE
+                        WHEN excluded.last_beat > beats_daily.last_beat THEN excluded.last_credential
+                        ELSE beats_daily.last_credential
+                    END;
+                ",
+            )
+            .bind(hourly_before)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("DELETE FROM beats_hourly WHERE hour < ?;")
+                .bind(hourly_before)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+        } else {
+            0
+        };
+        tx.commit().await?;
+        Ok(Compacted { beats, hours })
+    }
+
+    async fn insert_event(&self, event: StoredEvent) -> sqlx::Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO events (id, kind, time_stamp, started_at, device)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (id) DO NOTHING;
+            ",
+        )
+        .bind(event.id)
+        .bind(event.kind)
+        .bind(event.time)
+        .bind(event.started_at)
+        .bind(event.device)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn recent_events(&self, limit: i64) -> sqlx::Result<Vec<StoredEvent>> {
+        let records = sqlx::query_as(
+            "SELECT id, kind, time_stamp, started_at, device FROM events ORDER BY time_stamp DESC LIMIT ?;",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records
+            .into_iter()
+            .map(|(id, kind, time, started_at, device)| StoredEvent {
+                id,
+                kind,
+                time,
+                started_at,
+                device,
+            })
+            .collect())
+    }
+}
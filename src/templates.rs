@@ -6,6 +6,7 @@
 
 use crate::{
     config::Config,
+    devices::{Device, Metadata},
     stats::Stats,
     util::formats::{format_relative, FormatNum},
     VERSION,
@@ -106,6 +107,8 @@ pub fn index(stats: &Stats, revision: &str, config: &Config) -> Markup {
 This embed was generated at {now_fmt}.
 Due to caching, you will have to check the website if the embed generation time is old."));
         meta name="theme-color" content="#6495ed";
+        link rel="alternate" type="application/atom+xml" title=(config.server_name) href="/feed.atom";
+        link rel="alternate" type="application/feed+json" title=(config.server_name) href="/feed.json";
         script type="module" src="/script.mjs" {}
     });
     let href = format!("{}/tree/{}", config.repo, revision);
@@ -325,3 +328,290 @@ pub fn stats(stats: &Stats, config: &Config, server_start_time: DateTime<Utc>) -
     };
     base(format!("Stats - {}", config.server_name), true, Some(head), &body)
 }
+
+pub fn device(device: &Device, config: &Config) -> Markup {
+    let name = device.name.as_deref().unwrap_or("Unnamed device");
+    let title = format!("{name} - {}", config.server_name);
+    let head = html! {
+        meta property="og:site_name" content=(title);
+        meta property="og:description" content=(format!("{name} on {}", config.server_name));
+        meta name="theme-color" content="#6495ed";
+    };
+    let last_beat = device.last_beat.map_or_else(
+        || "never".to_string(),
+        |last_beat| format!("{} ago", format_relative(Utc::now() - last_beat)),
+    );
+    let metadata = device.metadata.as_ref().map(|m| m.filtered(&config.public_metadata));
+    let body = html! {
+        body {
+            div.spacer {}
+            div.preamble {
+                div.grid-cell {}
+                div {
+                    p.centre {
+                        (name) " on " (config.server_name)
+                    }
+                }
+                div.grid-cell {}
+            }
+            div.times {
+                div.grid-cell {}
+                div.grid-cell {
+                    p.centre {
+                        "Last beat:"
+                        br;
+                        span #last-beat {
+                            (last_beat)
+                        }
+                    }
+                }
+                div.grid-cell {
+                    p.centre {
+                        "Total beats received:"
+                        br;
+                        span #total-beats {
+                            (device.num_beats.format())
+                        }
+                    }
+                }
+                @if let Some(metadata) = metadata {
+                    (metadata_cells(&metadata))
+                }
+                div.grid-cell {}
+            }
+            div.spacer {}
+            div.links {
+                div.grid-cell {}
+                div {
+                    p.centre {
+                        a href="/" {
+                            "Main Page"
+                        }
+                        " - "
+                        a href="/stats" {
+                            "Stats"
+                        }
+                    }
+                }
+                div.grid-cell {}
+            }
+        }
+    };
+    base(title, true, Some(head), &body)
+}
+
+/// Formats the label and value of a line of a plain text page, in bold and
+/// cyan respectively if `colour` is set.
+fn text_line(out: &mut String, label: &str, value: impl std::fmt::Display, colour: bool) {
+    use std::fmt::Write;
+
+    let _ = if colour {
+        writeln!(out, "\x1b[1m{label:<26}\x1b[0m \x1b[36m{value}\x1b[0m")
+    } else {
+        writeln!(out, "{label:<26} {value}")
+    };
+}
+
+pub fn index_text(stats: &Stats, config: &Config, colour: bool) -> String {
+    let now = Utc::now();
+    let last_seen = stats.last_seen.unwrap_or_else(|| std::time::UNIX_EPOCH.into());
+    let mut out = format!("{}\n\n", config.server_name);
+    text_line(
+        &mut out,
+        "Last response time:",
+        last_seen.format("%d %B %Y %H:%M:%S UTC"),
+        colour,
+    );
+    text_line(
+        &mut out,
+        "Time since last response:",
+        format_relative(now - last_seen),
+        colour,
+    );
+    text_line(
+        &mut out,
+        "Longest absence:",
+        format_relative(stats.longest_absence),
+        colour,
+    );
+    text_line(&mut out, "Total beats received:", stats.total_beats.format(), colour);
+    out
+}
+
+pub fn stats_text(stats: &Stats, config: &Config, server_start_time: DateTime<Utc>, colour: bool) -> String {
+    let now = Utc::now();
+    let mut out = format!("Statistics for {}\n\n", config.server_name);
+    text_line(&mut out, "Total visits:", stats.num_visits.format(), colour);
+    text_line(&mut out, "Total devices:", stats.devices.len().format(), colour);
+    text_line(&mut out, "Total beats received:", stats.total_beats.format(), colour);
+    text_line(&mut out, "Uptime:", format_relative(now - server_start_time), colour);
+    if !stats.devices.is_empty() {
+        out.push('\n');
+    }
+    for device in &stats.devices {
+        let last_beat = match device.last_beat.map(|last_beat| format_relative(now - last_beat)) {
+            None => "never".to_string(),
+            Some(relative) if relative == "just now" => relative,
+            Some(relative) => format!("{relative} ago"),
+        };
+        let beats = if device.num_beats == 1 { "beat" } else { "beats" };
+        text_line(
+            &mut out,
+            &format!("{}:", device.name.as_deref().unwrap_or("Unnamed device")),
+            format!("last beat {last_beat}, {} {beats}", device.num_beats.format()),
+            colour,
+        );
+    }
+    out
+}
+
+fn metadata_cells(metadata: &Metadata) -> Markup {
+    let uptime = metadata.uptime.map(|uptime| {
+        i64::try_from(uptime)
+            .ok()
+            .and_then(chrono::TimeDelta::try_seconds)
+            .map_or_else(|| "unknown".into(), format_relative)
+    });
+    let cells = [
+        ("Battery:", metadata.battery.map(|battery| format!("{battery}%"))),
+        ("Hostname:", metadata.hostname.clone()),
+        ("OS:", metadata.os.clone()),
+        ("Uptime:", uptime),
+        ("IP address:", metadata.ip.map(|ip| ip.to_string())),
+        ("Status:", metadata.status.clone()),
+    ];
+    html! {
+        @for (label, value) in cells {
+            @if let Some(value) = value {
+                div.grid-cell {
+                    p.centre {
+                        (label)
+                        br;
+                        span { (value) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Renders the `OpenAPI` description of the routes as a page, listing every
+/// operation and the schemas they use.
+#[cfg(feature = "openapi-viewer")]
+pub fn api_docs(spec: &serde_json::Value, config: &Config) -> Markup {
+    const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+    let empty = serde_json::Map::new();
+    let paths = spec["paths"].as_object().unwrap_or(&empty);
+    let schemas = spec["components"]["schemas"].as_object().unwrap_or(&empty);
+    let body = html! {
+        body {
+            div.spacer {}
+            div.api-docs {
+                p.centre {
+                    (config.server_name) " API"
+                    br;
+                    "This page is generated from "
+                    a href="/api/v1/openapi.json" { "the OpenAPI description" }
+                    "."
+                }
+                @for (path, item) in paths {
+                    @for method in METHODS {
+                        @if let Some(operation) = item.get(method) {
+                            (api_operation(method, path, operation))
+                        }
+                    }
+                }
+                h3 { "Schemas" }
+                @for (name, schema) in schemas {
+                    h4 #(format!("schema-{name}")) { (name) }
+                    pre { (serde_json::to_string_pretty(schema).unwrap_or_default()) }
+                }
+            }
+            div.spacer {}
+            div.links {
+                div.grid-cell {}
+                div {
+                    p.centre {
+                        a href="/" {
+                            "Main Page"
+                        }
+                        " - "
+                        a href="/stats" {
+                            "Stats"
+                        }
+                    }
+                }
+                div.grid-cell {}
+            }
+        }
+    };
+    base(format!("API - {}", config.server_name), true, None, &body)
+}
+
+#[cfg(feature = "openapi-viewer")]
+fn api_operation(method: &str, path: &str, operation: &serde_json::Value) -> Markup {
+    let security = operation["security"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(serde_json::Value::as_object)
+        .flat_map(serde_json::Map::keys)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let empty = serde_json::Map::new();
+    html! {
+        h3 { code { (method.to_uppercase()) " " (path) } }
+        @if let Some(description) = operation["description"].as_str().or_else(|| operation["summary"].as_str()) {
+            p { (description) }
+        }
+        @if !security.is_empty() {
+            p { "Authentication: " (security.join(" or ")) }
+        }
+        @if let Some(params) = operation["parameters"].as_array() {
+            p { "Parameters:" }
+            ul {
+                @for param in params {
+                    li {
+                        code { (param["name"].as_str().unwrap_or_default()) }
+                        " (" (param["in"].as_str().unwrap_or_default()) ")"
+                        @if let Some(description) = param["description"].as_str() {
+                            ": " (description)
+                        }
+                    }
+                }
+            }
+        }
+        @if let Some(content) = operation["requestBody"]["content"].as_object() {
+            p { "Request body:" }
+            ul {
+                @for (content_type, media) in content {
+                    li { code { (content_type) } (api_schema(&media["schema"])) }
+                }
+            }
+        }
+        p { "Responses:" }
+        ul {
+            @for (status, response) in operation["responses"].as_object().unwrap_or(&empty) {
+                li {
+                    b { (status) } " " (response["description"].as_str().unwrap_or_default())
+                    @for (content_type, media) in response["content"].as_object().unwrap_or(&empty) {
+                        br;
+                        code { (content_type) } (api_schema(&media["schema"]))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Links to a referenced schema, or shows an inline one.
+#[cfg(feature = "openapi-viewer")]
+fn api_schema(schema: &serde_json::Value) -> Markup {
+    html! {
+        @if let Some(name) = schema["$ref"].as_str().and_then(|r| r.strip_prefix("#/components/schemas/")) {
+            ": " a href=(format!("#schema-{name}")) { (name) }
+        } @else if !schema.is_null() {
+            ": " code { (schema) }
+        }
+    }
+}
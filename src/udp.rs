@@ -0,0 +1,240 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// A UDP datagram is laid out as follows, with all integers big-endian:
+//
+// | offset | length | field                                        |
+// |--------|--------|----------------------------------------------|
+// | 0      | 1      | version, currently 1                         |
+// | 1      | 8      | device ID                                    |
+// | 9      | 8      | counter, which must increase with every beat |
+// | 17     | 16     | HMAC-SHA256 of the above, truncated          |
+//
+// The HMAC is keyed by the device's signing secret. Successful beats are
+// acknowledged by echoing the counter back to the sender.
+use crate::{
+    auth::Device as DeviceAuth, devices::Credential, routes::record_beat, server::shutdown, signature, AppState,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, sync::Semaphore};
+use tracing::{debug, error};
+
+const VERSION: u8 = 1;
+const MAC_LEN: usize = 16;
+const SIGNED_LEN: usize = 17;
+const PACKET_LEN: usize = SIGNED_LEN + MAC_LEN;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// The maximum number of datagrams that are authenticated at once. Source
+/// addresses can be spoofed, so this bounds the load on the database
+/// regardless of where datagrams claim to come from.
+const MAX_PENDING: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Packet {
+    device: i64,
+    counter: i64,
+}
+
+impl Packet {
+    /// Parses a datagram, returning the packet along with the signed bytes
+    /// and the MAC.
+    fn parse(buf: &[u8]) -> Option<(Self, &[u8], &[u8])> {
+        if buf.len() != PACKET_LEN || buf[0] != VERSION {
+            return None;
+        }
+        let (signed, mac) = buf.split_at(SIGNED_LEN);
+        let device = i64::from_be_bytes(signed[1..9].try_into().ok()?);
+        let counter = i64::from_be_bytes(signed[9..17].try_into().ok()?);
+        if counter <= 0 {
+            return None;
+        }
+        Some((Self { device, counter }, signed, mac))
+    }
+}
+
+/// Limits the number of datagrams accepted from each address in a fixed
+/// window. A limit of 0 disables rate limiting.
+struct RateLimiter {
+    limit: u32,
+    window_start: Instant,
+    counts: HashMap<IpAddr, u32>,
+}
+
+impl RateLimiter {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            counts: HashMap::new(),
+        }
+    }
+
+    fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        if now.duration_since(self.window_start) >= RATE_LIMIT_WINDOW {
+            self.counts.clear();
+            self.window_start = now;
+        }
+        let count = self.counts.entry(ip).or_default();
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+/// Receive beats on the given UDP socket until the server shuts down.
+///
+/// Beats are recorded the same way as those sent to `POST /api/v1/beat`.
+///
+/// # Errors
+///
+/// This function currently never returns an error. Failing to receive a
+/// datagram is logged and the listener carries on.
+pub async fn serve_udp(socket: UdpSocket, state: AppState) -> io::Result<()> {
+    let socket = Arc::new(socket);
+    let mut limiter = RateLimiter::new(state.config.udp.rate_limit);
+    let pending = Arc::new(Semaphore::new(MAX_PENDING));
+    let mut buf = [0u8; PACKET_LEN + 1];
+    loop {
+        let (len, addr) = tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(res) => res,
+                Err(e) => {
+                    debug!("failed to receive datagram: {e}");
+                    continue;
+                }
+            },
+            () = shutdown() => {
+                debug!("shutdown signal received, closing UDP listener");
+                break;
+            }
+        };
+        if !limiter.check(addr.ip(), Instant::now()) {
+            debug!("rate limited datagram from {addr}");
+            continue;
+        }
+        let Some((packet, signed, mac)) = Packet::parse(&buf[..len]) else {
+            debug!("malformed datagram from {addr}");
+            continue;
+        };
+        let Ok(permit) = Arc::clone(&pending).try_acquire_owned() else {
+            debug!("too many pending datagrams, dropping datagram from {addr}");
+            continue;
+        };
+        let (signed, mac) = (signed.to_vec(), mac.to_vec());
+        let (state, socket) = (state.clone(), Arc::clone(&socket));
+        tokio::spawn(async move {
+            handle_packet(state, &socket, addr, packet, &signed, &mac).await;
+            drop(permit);
+        });
+    }
+    Ok(())
+}
+
+async fn handle_packet(
+    state: AppState,
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    packet: Packet,
+    signed: &[u8],
+    mac: &[u8],
+) {
+    let device = match authenticate(&state, &packet, signed, mac).await {
+        Ok(Some(device)) => device,
+        Ok(None) => {
+            debug!(id = %packet.device, "rejected datagram from {addr}");
+            return;
+        }
+        Err(e) => {
+            error!("Failed to authenticate UDP beat: {e:?}");
+            return;
+        }
+    };
+    record_beat(state, device, None).await;
+    if let Err(e) = socket.send_to(&packet.counter.to_be_bytes(), addr).await {
+        debug!("failed to acknowledge beat from {addr}: {e}");
+    }
+}
+
+/// Checks the MAC and counter of a packet, returning the device if both are
+/// valid.
+async fn authenticate(
+    state: &AppState,
+    packet: &Packet,
+    signed: &[u8],
+    mac: &[u8],
+) -> Result<Option<DeviceAuth>, sqlx::Error> {
+    let Some((device, secret)) = state.storage.signing_secret(packet.device).await? else {
+        return Ok(None);
+    };
+    if !signature::verify_truncated(&secret, signed, mac) {
+        return Ok(None);
+    }
+    let advanced = state.storage.advance_udp_counter(device.id, packet.counter).await?;
+    Ok(advanced.then_some(DeviceAuth {
+        id: device.id,
+        name: device.name,
+        credential: Credential::Udp,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(version: u8, device: i64, counter: i64) -> Vec<u8> {
+        let mut buf = vec![version];
+        buf.extend_from_slice(&device.to_be_bytes());
+        buf.extend_from_slice(&counter.to_be_bytes());
+        buf.extend_from_slice(&[0xab; MAC_LEN]);
+        buf
+    }
+
+    #[test]
+    fn test_parse_packet() {
+        let buf = packet(VERSION, 42, 1_698_915_036);
+        let (packet, signed, mac) = Packet::parse(&buf).expect("valid packet");
+        assert_eq!(
+            packet,
+            Packet {
+                device: 42,
+                counter: 1_698_915_036
+            }
+        );
+        assert_eq!(signed, &buf[..SIGNED_LEN]);
+        assert_eq!(mac, &[0xab; MAC_LEN]);
+    }
+
+    #[test]
+    fn test_reject_malformed_packets() {
+        assert!(Packet::parse(&packet(2, 42, 1)).is_none());
+        assert!(Packet::parse(&packet(VERSION, 42, 0)).is_none());
+        assert!(Packet::parse(&packet(VERSION, 42, -1)).is_none());
+        assert!(Packet::parse(&packet(VERSION, 42, 1)[..PACKET_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        let (a, b) = (IpAddr::from([127, 0, 0, 1]), IpAddr::from([127, 0, 0, 2]));
+        assert!(limiter.check(a, now));
+        assert!(limiter.check(a, now));
+        assert!(!limiter.check(a, now));
+        assert!(limiter.check(b, now));
+        assert!(limiter.check(a, now + RATE_LIMIT_WINDOW));
+    }
+}
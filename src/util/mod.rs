@@ -8,7 +8,6 @@ pub mod formats;
 pub mod hf_time;
 #[macro_use]
 mod plural;
-pub mod serde;
 mod snowflake;
 mod token;
 #[cfg(feature = "webhook")]
@@ -37,6 +37,18 @@ impl Snowflake {
         Self(id as _)
     }
 
+    /// Derives the ID of something that happened at `time`, rather than
+    /// generating a new one, so that the same thing always has the same ID.
+    ///
+    /// These use node 0, so they never collide with generated IDs, and `seq`
+    /// tells apart different things that happen at the same time.
+    pub fn at(time: chrono::DateTime<chrono::Utc>, seq: u64) -> Self {
+        let elapsed = u64::try_from(time.timestamp_millis())
+            .unwrap_or_default()
+            .saturating_sub(EPOCH);
+        Self(((elapsed << 22) & bitmask(22)) | seq & max(12))
+    }
+
     pub const fn id(self) -> u64 {
         self.0
     }
@@ -98,4 +110,17 @@ mod tests {
         println!("id: {id}");
         println!("created_at: {}", id.created_at());
     }
+
+    #[test]
+    fn test_at() {
+        let time = chrono::Utc
+            .timestamp_opt(1_698_915_036, 0)
+            .single()
+            .expect("valid timestamp");
+        let id = Snowflake::at(time, 3);
+        assert_eq!(id.created_at(), time);
+        assert_eq!(id.id() & max(12), 3);
+        assert_eq!(Snowflake::at(time, 3).id(), id.id());
+        assert_ne!(Snowflake::at(time, 4).id(), id.id());
+    }
 }
@@ -0,0 +1,232 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Buffers visits and beats in memory so they are written in batches.
+
+use crate::{
+    cluster::{Change, Cluster},
+    config::WriteBuffer,
+    storage::{NewBeat, Storage},
+};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+use tokio::{
+    sync::{oneshot, Notify},
+    time::MissedTickBehavior,
+};
+use tracing::error;
+
+/// The time of the last beat from any device before a beat, or why the beat
+/// couldn't be written.
+type Written = Result<Option<DateTime<Utc>>, Arc<sqlx::Error>>;
+
+/// A beat waiting to be written, and where to send the beat before it.
+#[derive(Debug)]
+struct Pending {
+    beat: NewBeat,
+    done: oneshot::Sender<Written>,
+}
+
+/// Adds visits up into a single update and groups beats into batches, which
+/// are written every [`WriteBuffer::flush_interval`] or as soon as there are
+/// [`WriteBuffer::max_batch_size`] beats.
+#[derive(Debug)]
+pub struct Writer {
+    storage: Arc<dyn Storage>,
+    cluster: Arc<Cluster>,
+    config: WriteBuffer,
+    visits: AtomicI64,
+    beats: Mutex<Vec<Pending>>,
+    full: Notify,
+    /// Held while writing, so batches are written in the order they were
+    /// buffered.
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl Writer {
+    /// Creates a writer for `storage`, and starts writing buffered visits and
+    /// beats in the background unless they are written right away. Visits are
+    /// sent to the rest of the `cluster` once they are written.
+    pub fn start(storage: Arc<dyn Storage>, cluster: Arc<Cluster>, config: WriteBuffer) -> Arc<Self> {
+        let writer = Arc::new(Self {
+            storage,
+            cluster,
+            config,
+            visits: AtomicI64::new(0),
+            beats: Mutex::new(vec![]),
+            full: Notify::new(),
+            flushing: tokio::sync::Mutex::new(()),
+        });
+        if writer.is_buffered() {
+            tokio::spawn(serve_writes(writer.clone()));
+        }
+        writer
+    }
+
+    const fn is_buffered(&self) -> bool {
+        !self.config.flush_interval.is_zero()
+    }
+
+    /// Counts a visit to the site.
+    pub fn visit(&self) {
+        if self.is_buffered() {
+            self.visits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let storage = self.storage.clone();
+            let cluster = self.cluster.clone();
+            tokio::spawn(async move {
+                if storage.add_visits(1).await.is_ok() {
+                    cluster.send(Change::Visits { count: 1 });
+                }
+            });
+        }
+    }
+
+    /// Records a beat once it has been written, returning the time of the
+    /// last beat from any device before it, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, which has already been logged, if the beat couldn't
+    /// be written.
+    pub async fn beat(&self, beat: NewBeat) -> Written {
+        if !self.is_buffered() {
+            return self.storage.insert_live_beats(&[beat]).await.map_err(|e| {
+                error!("Failed to update database on successful beat: {e:?}");
+                Arc::new(e)
+            });
+        }
+        let (done, previous) = oneshot::channel();
+        let buffered = {
+            let mut beats = self.beats.lock();
+            beats.push(Pending { beat, done });
+            beats.len()
+        };
+        if buffered >= self.config.max_batch_size {
+            self.full.notify_one();
+        }
+        previous
+            .await
+            .unwrap_or_else(|_| Err(Arc::new(sqlx::Error::WorkerCrashed)))
+    }
+
+    /// Writes all buffered visits and beats.
+    pub async fn flush(&self) {
+        let _flushing = self.flushing.lock().await;
+        let visits = self.visits.swap(0, Ordering::Relaxed);
+        if visits > 0 {
+            match self.storage.add_visits(visits).await {
+                Ok(()) => self.cluster.send(Change::Visits { count: visits }),
+                Err(e) => {
+                    error!("Failed to add {visits} visits: {e:?}");
+                    self.visits.fetch_add(visits, Ordering::Relaxed);
+                }
+            }
+        }
+        loop {
+            let batch = {
+                let mut beats = self.beats.lock();
+                let len = beats.len().min(self.config.max_batch_size);
+                beats.drain(..len).collect::<Vec<_>>()
+            };
+            if batch.is_empty() {
+                break;
+            }
+            self.write(batch).await;
+        }
+    }
+
+    async fn write(&self, batch: Vec<Pending>) {
+        let beats = batch.iter().map(|pending| pending.beat).collect::<Vec<_>>();
+        match self.storage.insert_live_beats(&beats).await {
+            Ok(mut previous) => {
+                // each beat's previous one may be earlier in the batch
+                for pending in batch {
+                    let _ = pending.done.send(Ok(previous));
+                    previous = previous.max(Some(pending.beat.time));
+                }
+            }
+            Err(e) => {
+                error!("Failed to update database with {} beats: {e:?}", batch.len());
+                let e = Arc::new(e);
+                for pending in batch {
+                    let _ = pending.done.send(Err(e.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Writes buffered visits and beats until the process exits. The server
+/// flushes them one last time once it has shut down.
+async fn serve_writes(writer: Arc<Writer>) {
+    let mut interval = tokio::time::interval(writer.config.flush_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = writer.full.notified() => {}
+        }
+        writer.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices::Credential,
+        storage::{Memory, NewDevice},
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_batches() {
+        let storage = Arc::new(Memory::default());
+        storage
+            .add_device(NewDevice {
+                id: 1,
+                name: "test",
+                token: "token",
+                signing_secret: None,
+                public_key: None,
+                probe: None,
+            })
+            .await
+            .expect("device to be added");
+        let config = WriteBuffer {
+            flush_interval: Duration::from_secs(3600),
+            max_batch_size: 2,
+        };
+        let cluster = Cluster::join(storage.clone()).await;
+        let writer = Writer::start(storage.clone(), cluster, config);
+        let start = Utc::now();
+        let beat = |seconds| NewBeat {
+            device: 1,
+            credential: Credential::Current,
+            time: start + chrono::TimeDelta::seconds(seconds),
+        };
+        // a full batch is written without waiting for the interval
+        let (first, second) = tokio::join!(writer.beat(beat(0)), writer.beat(beat(1)));
+        assert_eq!(
+            (
+                first.expect("first beat to be written"),
+                second.expect("second beat to be written")
+            ),
+            (None, Some(beat(0).time))
+        );
+        writer.visit();
+        writer.visit();
+        assert_eq!(storage.totals().await.expect("totals to be read").num_visits, 0);
+        writer.flush().await;
+        assert_eq!(storage.totals().await.expect("totals to be read").num_visits, 2);
+        assert_eq!(storage.total_beats().await.expect("totals to be read"), 2);
+    }
+}
@@ -138,7 +138,7 @@ function Index(stats) {
 document.addEventListener('DOMContentLoaded', () => {
   const path = window.location.pathname;
   const component = path === '/' ? Index : Stats;
-  const url = new URL('/api/stats/ws', window.location.href);
+  const url = new URL('/api/v1/stats/ws', window.location.href);
   // http -> ws
   // https -> wss
   url.protocol = url.protocol.replace('http', 'ws');
@@ -0,0 +1,183 @@
+// Copyright (c) 2023 Isis <root@5ht2.me>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drives the router end to end against the in-memory storage backend.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::ConnectInfo,
+    http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    middleware, Extension, Router,
+};
+use clap::Parser;
+use heartbeat::{
+    devices::Metadata,
+    handle_errors,
+    routes::router,
+    storage::{Memory, Storage},
+    AppState, Config, WebCli, PROBLEM_CONTENT_TYPE,
+};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, sync::Arc};
+use tower::ServiceExt;
+
+const SECRET_KEY: &str = "sekrit";
+
+fn config() -> &'static Config {
+    config_with(&[])
+}
+
+fn config_with(args: &[&str]) -> &'static Config {
+    let base = ["heartbeat", "--secret-key", SECRET_KEY, "--database-dsn", "memory:"];
+    let cli = WebCli::parse_from(base.iter().chain(args));
+    Box::leak(Box::new(Config::try_new(cli).expect("config to be valid")))
+}
+
+fn app(config: &'static Config, state: AppState) -> Router {
+    router(config)
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state, handle_errors))
+        .layer(Extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 6060)))))
+}
+
+async fn setup() -> (Router, Arc<Memory>) {
+    let storage = Arc::new(Memory::default());
+    let config = config();
+    let state = AppState::with_storage(config, storage.clone()).await;
+    (app(config, state), storage)
+}
+
+async fn request(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    auth: Option<&str>,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let mut builder = Request::builder().method(method).uri(uri);
+    if let Some(auth) = auth {
+        builder = builder.header(AUTHORIZATION, auth);
+    }
+    let request = match body {
+        Some(body) => builder
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => builder.body(Body::empty()),
+    }
+    .expect("request to be valid");
+    let response = app.clone().oneshot(request).await.expect("router to be infallible");
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("body to be readable");
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn test_empty_stats() {
+    let (app, _) = setup().await;
+    let (status, stats) = request(&app, Method::GET, "/api/v1/stats", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["total_beats"], 0);
+    assert_eq!(stats["devices"], json!([]));
+}
+
+#[tokio::test]
+async fn test_device_beats() {
+    let (app, storage) = setup().await;
+    let body = json!({ "name": "test" });
+    let (status, device) = request(&app, Method::POST, "/api/v1/devices", Some(SECRET_KEY), Some(body)).await;
+    assert_eq!(status, StatusCode::OK);
+    let token = device["token"].as_str().expect("token to be a string");
+    for _ in 0..2 {
+        let (status, _) = request(&app, Method::POST, "/api/v1/beat", Some(token), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert_eq!(storage.total_beats().await.ok(), Some(2));
+    let (_, stats) = request(&app, Method::GET, "/api/v1/stats", None, None).await;
+    assert_eq!(stats["devices"][0]["name"], "test");
+    assert!(stats["last_seen"].is_i64());
+}
+
+#[tokio::test]
+async fn test_device_without_beats_after_restart() {
+    let (before, storage) = setup().await;
+    let body = json!({ "name": "test" });
+    let (status, _) = request(&before, Method::POST, "/api/v1/devices", Some(SECRET_KEY), Some(body)).await;
+    assert_eq!(status, StatusCode::OK);
+    let config = config();
+    let restarted = app(config, AppState::with_storage(config, storage).await);
+    let (_, stats) = request(&restarted, Method::GET, "/api/v1/stats", None, None).await;
+    assert_eq!(stats["devices"][0]["name"], "test");
+    assert_eq!(stats["devices"][0]["num_beats"], 0);
+}
+
+#[tokio::test]
+async fn test_uptime_out_of_range() {
+    let config = config_with(&["--public-metadata", "uptime"]);
+    let storage = Arc::new(Memory::default());
+    let before = app(config, AppState::with_storage(config, storage.clone()).await);
+    let body = json!({ "name": "test" });
+    let (_, device) = request(&before, Method::POST, "/api/v1/devices", Some(SECRET_KEY), Some(body)).await;
+    let token = device["token"].as_str().expect("token to be a string");
+    let id = device["id"].as_i64().expect("id to be an integer");
+    let body = json!({ "uptime": u64::MAX });
+    let (status, problem) = request(&before, Method::POST, "/api/v1/beat", Some(token), Some(body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem["code"], "invalid_uptime");
+    // uptimes stored before they were limited still render
+    let metadata = Metadata {
+        uptime: Some(u64::MAX),
+        ..Metadata::default()
+    };
+    storage
+        .store_metadata(id, chrono::Utc::now(), &metadata)
+        .await
+        .expect("metadata to be stored");
+    let restarted = app(config, AppState::with_storage(config, storage).await);
+    let response = restarted
+        .oneshot(
+            Request::get(format!("/devices/{id}"))
+                .body(Body::empty())
+                .expect("request to be valid"),
+        )
+        .await
+        .expect("router to be infallible");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_unauthorized() {
+    let (app, _) = setup().await;
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/api/v1/beat")
+                .header(AUTHORIZATION, "not a token")
+                .body(Body::empty())
+                .expect("request to be valid"),
+        )
+        .await
+        .expect("router to be infallible");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["content-type"], PROBLEM_CONTENT_TYPE);
+    let body = json!({ "name": "test" });
+    let (status, _) = request(&app, Method::POST, "/api/v1/devices", Some("not the key"), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_demo() {
+    let config = config();
+    let state = AppState::demo(config).await.expect("demo data to be stored");
+    let app = app(config, state);
+    let (status, stats) = request(&app, Method::GET, "/api/v1/stats", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["devices"].as_array().map(Vec::len), Some(3));
+    assert!(stats["longest_absence"].as_i64().is_some_and(|absence| absence > 0));
+    let (status, _) = request(&app, Method::GET, "/", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+}